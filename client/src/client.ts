@@ -6,12 +6,27 @@ export class Client {
     private world: World;
     private currentFrame: number;
     private localPlayer: PlayerId;
+    private stopped: boolean;
 
-    public constructor(game: Game, localPlayer: PlayerId, currentFrame: number, worldBuf: Uint8Array) {
+    // If `gameDiff` is given, then `worldBuf` is the keyframe that it has to be
+    // applied to.
+    public constructor(
+        game: Game,
+        localPlayer: PlayerId,
+        currentFrame: number,
+        worldBuf: Uint8Array,
+        gameDiff?: Uint8Array,
+    ) {
         this.game = game;
         this.localPlayer = localPlayer;
         this.currentFrame = currentFrame;
         this.world = this.game.deserializeWorld(worldBuf);
+        if (gameDiff !== undefined) {
+            const keyframe = this.world;
+            this.world = this.game.applyWorldDiff(keyframe, gameDiff);
+            keyframe.free();
+        }
+        this.stopped = false;
     }
 
     public get currentFrameNumber(): number {
@@ -34,12 +49,26 @@ export class Client {
 
     public runGameLoop() {
         const renderLoop = () => {
+            if (this.stopped) {
+                return;
+            }
             window.requestAnimationFrame(renderLoop);
             this.render();
         };
         window.requestAnimationFrame(renderLoop);
     }
 
+    public stop() {
+        this.stopped = true;
+    }
+
+    // Stops the client and frees its world, the client can't be used after
+    // this.
+    public free() {
+        this.stop();
+        this.world.free();
+    }
+
     private addPlayer(player: number) {
         const id = new PlayerId(player);
         const oldWorld = this.world;
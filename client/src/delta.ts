@@ -0,0 +1,59 @@
+// Applies deltas made by the server's `delta::diff`, see `server/src/delta.rs`
+// for the format. Returns null if the delta is invalid or was made from a
+// different base.
+export function applyDelta(base: Uint8Array, delta: Uint8Array): Uint8Array | null {
+    let offset = 0;
+
+    function readVarint(): number | null {
+        let value = 0;
+        let scale = 1;
+        while (offset < delta.length) {
+            const byte = delta[offset++];
+            value += (byte & 0x7f) * scale;
+            if ((byte & 0x80) === 0) {
+                return value;
+            }
+            scale *= 128;
+            // larger numbers can't be represented exactly
+            if (scale > Math.pow(2, 53)) {
+                return null;
+            }
+        }
+        return null;
+    }
+
+    if (readVarint() !== base.length) {
+        return null;
+    }
+    const targetLength = readVarint();
+    if (targetLength === null) {
+        return null;
+    }
+    const target = new Uint8Array(targetLength);
+    let written = 0;
+    while (offset < delta.length) {
+        const operation = readVarint();
+        if (operation === null) {
+            return null;
+        }
+        const length = Math.floor(operation / 2);
+        if (length > targetLength - written) {
+            return null;
+        }
+        if (operation % 2 === 0) {
+            if (offset + length > delta.length) {
+                return null;
+            }
+            target.set(delta.subarray(offset, offset + length), written);
+            offset += length;
+        } else {
+            const from = readVarint();
+            if (from === null || from + length > base.length) {
+                return null;
+            }
+            target.set(base.subarray(from, from + length), written);
+        }
+        written += length;
+    }
+    return written === targetLength ? target : null;
+}
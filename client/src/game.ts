@@ -1,3 +1,4 @@
+import { InputState } from "input";
 import { Handle, InputHandle, LowLevelGame, WorldHandle } from "lowLevelGame";
 
 export class Game {
@@ -45,6 +46,16 @@ export class Game {
         return new World(this.game, worldHandle);
     }
 
+    // Only works if game code exports `apply_world_diff`.
+    public applyWorldDiff(base: World, diff: Uint8Array): World {
+        const buffer = this.game.allocateBuffer(diff.length);
+        const ptr = this.game.bufferPtr(buffer);
+        this.game.writeMemory(ptr, diff);
+        const worldHandle = this.game.applyWorldDiff(base.handle, buffer);
+        this.game.freeHandle(buffer);
+        return new World(this.game, worldHandle);
+    }
+
     public serializeInput(input: Input): Uint8Array {
         const buffer = this.game.serializeInput(input.handle);
         const ptr = this.game.bufferPtr(buffer);
@@ -54,8 +65,13 @@ export class Game {
         return raw;
     }
 
-    public createInput(letters: number, oldLetters: number, other: number, oldOther: number): Input {
-        const handle = this.game.createInput(letters, oldLetters, other, oldOther);
+    public createInput(state: InputState): Input {
+        const raw = state.serialize();
+        const buffer = this.game.allocateBuffer(raw.length);
+        const ptr = this.game.bufferPtr(buffer);
+        this.game.writeMemory(ptr, raw);
+        const handle = this.game.createInput(buffer);
+        this.game.freeHandle(buffer);
         return new Input(this.game, handle);
     }
 
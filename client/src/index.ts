@@ -1,6 +1,7 @@
 import { Client } from "client";
 import { Game, PlayerId } from "game";
-import { NetworkHandler } from "network";
+import { InputState } from "input";
+import { NetworkHandler, TimeSync } from "network";
 
 const canvas = document.getElementById("game-canvas") as HTMLCanvasElement;
 const ctx = canvas.getContext("2d") as CanvasRenderingContext2D;
@@ -14,46 +15,211 @@ function showColor(color: number): string {
     return "#" + showHex(color >> 16) + showHex(color >> 8) + showHex(color);
 }
 
+// Colors that game code draws with are ARGB, see `primitive_game::canvas::Color`.
+function showArgb(color: number): string {
+    const alpha = ((color >>> 24) & 0xFF) / 255;
+    return "rgba(" + ((color >> 16) & 0xFF) + ", " + ((color >> 8) & 0xFF) + ", " + (color & 0xFF) + ", " + alpha + ")";
+}
+
+// Sprites are loaded from assets of the game package. Ids are indexes into
+// this array, and sprites that haven't loaded yet are not drawn.
+const sprites: Array<{ name: string, image: HTMLImageElement, loaded: boolean }> = [];
+
+function loadSprite(name: string): number {
+    for (let id = 0; id < sprites.length; id++) {
+        if (sprites[id].name === name) {
+            return id;
+        }
+    }
+    const sprite = { name, image: new Image(), loaded: false };
+    sprite.image.onload = () => sprite.loaded = true;
+    sprite.image.src = "/game/assets/" + name;
+    sprites.push(sprite);
+    return sprites.length - 1;
+}
+
+// Values that game code reads from the host while simulating a frame.
+const host = {
+    randomSeed: 0,
+    memory: null as WebAssembly.Memory | null,
+};
+
+function readString(ptr: number, len: number): string {
+    if (host.memory === null) {
+        return "";
+    }
+    const bytes = new Uint8Array(host.memory.buffer, ptr, len);
+    return new TextDecoder("utf-8").decode(bytes);
+}
+
+// Levels are numbered the same way as in `primitive_game::log::Level`.
+function logFromGame(level: number, message: string) {
+    if (level <= 1) {
+        console.error(message);
+    } else if (level === 2) {
+        console.warn(message);
+    } else if (level === 3) {
+        console.info(message);
+    } else {
+        console.debug(message);
+    }
+}
+
 const imports = {
     env: {
         draw_rectangle: (x: number, y: number, width: number, height: number, color: number) => {
             ctx.fillStyle = showColor(color);
             ctx.fillRect(x, y, width, height);
         },
-        log_str: (ptr: number, len: number) => {},
+        fill_rect: (x: number, y: number, width: number, height: number, color: number) => {
+            ctx.fillStyle = showArgb(color);
+            ctx.fillRect(x, y, width >>> 0, height >>> 0);
+        },
+        fill_circle: (x: number, y: number, radius: number, color: number) => {
+            ctx.fillStyle = showArgb(color);
+            ctx.beginPath();
+            ctx.arc(x, y, radius >>> 0, 0, 2 * Math.PI);
+            ctx.fill();
+        },
+        draw_line: (x0: number, y0: number, x1: number, y1: number, color: number) => {
+            ctx.strokeStyle = showArgb(color);
+            ctx.lineWidth = 1;
+            ctx.beginPath();
+            ctx.moveTo(x0 + 0.5, y0 + 0.5);
+            ctx.lineTo(x1 + 0.5, y1 + 0.5);
+            ctx.stroke();
+        },
+        fill_polygon: (ptr: number, points: number, color: number) => {
+            if (host.memory === null || points === 0) {
+                return;
+            }
+            const coordinates = new Int32Array(host.memory.buffer, ptr, points * 2);
+            ctx.fillStyle = showArgb(color);
+            ctx.beginPath();
+            ctx.moveTo(coordinates[0], coordinates[1]);
+            for (let i = 1; i < points; i++) {
+                ctx.lineTo(coordinates[2 * i], coordinates[2 * i + 1]);
+            }
+            ctx.closePath();
+            ctx.fill("evenodd");
+        },
+        load_sprite: (ptr: number, len: number) => loadSprite(readString(ptr, len)),
+        draw_sprite: (id: number, x: number, y: number) => {
+            const sprite = sprites[id];
+            if (sprite !== undefined && sprite.loaded) {
+                ctx.drawImage(sprite.image, x, y);
+            }
+        },
+        log_str: (ptr: number, len: number) => logFromGame(3, readString(ptr, len)),
+        log_message: (level: number, ptr: number, len: number) => logFromGame(level, readString(ptr, len)),
+        random_seed: () => host.randomSeed,
     },
 };
 
-const keyState = {
-    letters: 0,
-    other: 0,
-};
+const inputState = new InputState();
 
-// Client will send inputs this many frames ahead.
-// It could also do client side prediction, but that's to be implemented later.
+// Client will send inputs this many frames ahead until latency to the server
+// is measured. It could also do client side prediction, but that's to be
+// implemented later.
 export const clientRushingFrames = 10;
+const minInputLead = 1;
+const maxInputLead = 60;
+
+// Applied updates are acknowledged on frames that are multiples of this, must
+// be the same as `send_queue::ACK_INTERVAL` in the server.
+const ackInterval = 10;
+
+// How many frames ahead of `currentFrame` inputs should be sent so that they
+// reach the server before it simulates their frame, with some margin for
+// jitter.
+function inputLead(sync: TimeSync, currentFrame: number): number {
+    // server has moved on by half of rtt since sending this, and input will
+    // arrive after another half
+    const arrivalFrame = sync.frame + sync.phase + (sync.rtt + 2 * sync.jitter) / sync.frameTime;
+    const lead = Math.ceil(arrivalFrame - currentFrame) + 1;
+    return Math.max(minInputLead, Math.min(maxInputLead, lead));
+}
+
+function loadGame(): Promise<Game> {
+    // Code might change while the server is running, so it must not be cached.
+    return WebAssembly
+        .instantiateStreaming(fetch("/game/code.wasm", { cache: "no-store" }), imports)
+        .then(wasm => {
+            host.memory = wasm.instance.exports.memory as WebAssembly.Memory;
+            return new Game(wasm.instance);
+        });
+}
 
-WebAssembly
-    .instantiateStreaming(fetch("/game/code.wasm"), imports)
-    .then(wasm => {
+loadGame()
+    .then(initialGame => {
         const handler = new NetworkHandler();
-        const game = new Game(wasm.instance);
+        let game = initialGame;
         let client: Client;
         let lastSentInputFrame: number;
+        let lead = clientRushingFrames;
+        // lead is estimated from round trip time until server gives advice
+        // based on when inputs actually arrive
+        let advised = false;
 
         handler.onWorldState = worldState => {
             console.debug("Initial world state:", worldState);
             const playerId = new PlayerId(worldState.localPlayerId);
-            client = new Client(game, playerId, worldState.frame, worldState.world);
-            handler.joinGame(client.currentFrameNumber + clientRushingFrames);
-            sendInput(client.currentFrameNumber + clientRushingFrames + 1);
-            lastSentInputFrame = client.currentFrameNumber + clientRushingFrames + 1;
+            client = new Client(game, playerId, worldState.frame, worldState.world, worldState.gameDiff);
+            handler.joinGame(client.currentFrameNumber + lead);
+            sendInput(client.currentFrameNumber + lead + 1);
+            lastSentInputFrame = client.currentFrameNumber + lead + 1;
+            client.runGameLoop();
+        };
+
+        handler.onReload = worldState => {
+            console.info("Game code changed, reloading");
+            client.stop();
+            loadGame().then(newGame => {
+                game = newGame;
+                const playerId = new PlayerId(worldState.localPlayerId);
+                client = new Client(game, playerId, worldState.frame, worldState.world, worldState.gameDiff);
+                client.runGameLoop();
+                handler.reloadCompleted();
+            });
+        };
+
+        handler.onResync = worldState => {
+            console.warn("Fell behind the server, skipping to frame", worldState.frame);
+            // new client runs on the same game instance, so old world would
+            // leak otherwise
+            client.free();
+            const playerId = new PlayerId(worldState.localPlayerId);
+            client = new Client(game, playerId, worldState.frame, worldState.world, worldState.gameDiff);
+            // server lets inputs skip the frames that were missed
+            lastSentInputFrame = Math.max(lastSentInputFrame, client.currentFrameNumber + lead);
             client.runGameLoop();
         };
 
+        handler.onTimeSync = sync => {
+            if (client !== undefined && !advised) {
+                lead = inputLead(sync, client.currentFrameNumber);
+                console.debug(`Round trip time ${sync.rtt.toFixed(1)} ms, sending inputs ${lead} frames ahead`);
+            }
+        };
+
+        handler.onInputAdvice = advice => {
+            if (advice.lateInputs > 0) {
+                console.warn(`${advice.lateInputs} inputs arrived too late and were dropped`);
+            }
+            advised = true;
+            lead = Math.max(minInputLead, Math.min(maxInputLead, lead + advice.change));
+            console.debug(`Server advised changing input lead by ${advice.change}, now ${lead}`);
+        };
+
         handler.onPlayerInputs = inputs => {
+            host.randomSeed = inputs.seed;
             client.step(inputs);
-            const sendFor = client.currentFrameNumber + clientRushingFrames;
+            if (client.currentFrameNumber % ackInterval === 0) {
+                handler.sendAck(client.currentFrameNumber);
+            }
+            // inputs for skipped frames are sent when lead grows, and none are
+            // sent until the client catches up when it shrinks
+            const sendFor = client.currentFrameNumber + lead;
             while (sendFor > lastSentInputFrame) {
                 lastSentInputFrame += 1;
                 sendInput(lastSentInputFrame);
@@ -62,7 +228,8 @@ WebAssembly
 
         function sendInput(frame: number) {
             console.debug(`Sending input for frame ${frame}`);
-            const input = game.createInput(keyState.letters, 0, keyState.other, 0);
+            const input = game.createInput(inputState);
+            inputState.advance();
             const serialized = game.serializeInput(input);
             input.free();
             handler.sendInput({
@@ -72,72 +239,162 @@ WebAssembly
         }
     });
 
-function keyIndex(key: string): number {
-    if (key === "a") { return 0; }
-    if (key === "b") { return 1; }
-    if (key === "c") { return 2; }
-    if (key === "d") { return 3; }
-    if (key === "e") { return 4; }
-    if (key === "f") { return 5; }
-    if (key === "g") { return 6; }
-    if (key === "h") { return 7; }
-    if (key === "i") { return 8; }
-    if (key === "j") { return 9; }
-    if (key === "k") { return 10; }
-    if (key === "l") { return 11; }
-    if (key === "m") { return 12; }
-    if (key === "n") { return 13; }
-    if (key === "o") { return 14; }
-    if (key === "p") { return 15; }
-    if (key === "q") { return 16; }
-    if (key === "r") { return 17; }
-    if (key === "s") { return 18; }
-    if (key === "t") { return 19; }
-    if (key === "u") { return 20; }
-    if (key === "v") { return 21; }
-    if (key === "w") { return 22; }
-    if (key === "x") { return 23; }
-    if (key === "y") { return 24; }
-    if (key === "z") { return 25; }
-    if (key === "ArrowUp") { return 32; }
-    if (key === "ArrowDown") { return 33; }
-    if (key === "ArrowLeft") { return 34; }
-    if (key === "ArrowRight") { return 35; }
-    if (key === "0") { return 36; }
-    if (key === "1") { return 37; }
-    if (key === "2") { return 38; }
-    if (key === "3") { return 39; }
-    if (key === "4") { return 40; }
-    if (key === "5") { return 41; }
-    if (key === "6") { return 42; }
-    if (key === "7") { return 43; }
-    if (key === "8") { return 44; }
-    if (key === "9") { return 45; }
-    return -1;
+// Numbers of `primitive_game::input::Key`, by `KeyboardEvent.code`, which
+// identifies physical keys regardless of keyboard layout.
+const keyNumbers: { [code: string]: number } = {
+    KeyA: 0,
+    KeyB: 1,
+    KeyC: 2,
+    KeyD: 3,
+    KeyE: 4,
+    KeyF: 5,
+    KeyG: 6,
+    KeyH: 7,
+    KeyI: 8,
+    KeyJ: 9,
+    KeyK: 10,
+    KeyL: 11,
+    KeyM: 12,
+    KeyN: 13,
+    KeyO: 14,
+    KeyP: 15,
+    KeyQ: 16,
+    KeyR: 17,
+    KeyS: 18,
+    KeyT: 19,
+    KeyU: 20,
+    KeyV: 21,
+    KeyW: 22,
+    KeyX: 23,
+    KeyY: 24,
+    KeyZ: 25,
+    ArrowUp: 32,
+    ArrowDown: 33,
+    ArrowLeft: 34,
+    ArrowRight: 35,
+    Digit0: 36,
+    Digit1: 37,
+    Digit2: 38,
+    Digit3: 39,
+    Digit4: 40,
+    Digit5: 41,
+    Digit6: 42,
+    Digit7: 43,
+    Digit8: 44,
+    Digit9: 45,
+    Space: 46,
+    Enter: 47,
+    Escape: 48,
+    Tab: 49,
+    Backspace: 50,
+    Delete: 51,
+    Insert: 52,
+    Home: 53,
+    End: 54,
+    PageUp: 55,
+    PageDown: 56,
+    ShiftLeft: 57,
+    ShiftRight: 58,
+    ControlLeft: 59,
+    ControlRight: 60,
+    AltLeft: 61,
+    AltRight: 62,
+    MetaLeft: 63,
+    MetaRight: 64,
+    CapsLock: 65,
+    F1: 66,
+    F2: 67,
+    F3: 68,
+    F4: 69,
+    F5: 70,
+    F6: 71,
+    F7: 72,
+    F8: 73,
+    F9: 74,
+    F10: 75,
+    F11: 76,
+    F12: 77,
+    Minus: 78,
+    Equal: 79,
+    BracketLeft: 80,
+    BracketRight: 81,
+    Backslash: 82,
+    Semicolon: 83,
+    Quote: 84,
+    Backquote: 85,
+    Comma: 86,
+    Period: 87,
+    Slash: 88,
+    Numpad0: 89,
+    Numpad1: 90,
+    Numpad2: 91,
+    Numpad3: 92,
+    Numpad4: 93,
+    Numpad5: 94,
+    Numpad6: 95,
+    Numpad7: 96,
+    Numpad8: 97,
+    Numpad9: 98,
+    NumpadAdd: 99,
+    NumpadSubtract: 100,
+    NumpadMultiply: 101,
+    NumpadDivide: 102,
+    NumpadDecimal: 103,
+    NumpadEnter: 104,
+    NumLock: 105,
+    ScrollLock: 106,
+    PrintScreen: 107,
+    Pause: 108,
+    ContextMenu: 109,
+};
+
+function keyIndex(code: string): number {
+    const index = keyNumbers[code];
+    return index === undefined ? -1 : index;
 }
 
 document.onkeydown = (ev) => {
-    if ([32, 37, 38, 39, 40].indexOf(ev.keyCode) > -1) {
+    if ([9, 32, 37, 38, 39, 40].indexOf(ev.keyCode) > -1) {
         ev.preventDefault();
     }
-    const index = keyIndex(ev.key);
-    if (index >= 32) {
-        keyState.other |= (1 << (index - 32));
-    } else if (index >= 0) {
-        keyState.other |= (1 << index);
+    inputState.setKey(keyIndex(ev.code), true);
+    if (ev.key.length === 1 && !ev.ctrlKey && !ev.metaKey) {
+        inputState.text += ev.key;
     }
 };
 
 document.onkeyup = (ev) => {
-    const index = keyIndex(ev.key);
-    if (index >= 32) {
-        keyState.other &= ~(1 << (index - 32));
-    } else if (index >= 0) {
-        keyState.other &= ~(1 << index);
-    }
+    inputState.setKey(keyIndex(ev.code), false);
 };
 
 document.onblur = (ev) => {
-    keyState.letters = 0;
-    keyState.other = 0;
+    inputState.releaseAll();
+};
+
+// Pointer position is in canvas pixels, even when the canvas is scaled.
+canvas.onmousemove = (ev) => {
+    const rect = canvas.getBoundingClientRect();
+    inputState.mouseX = Math.floor((ev.clientX - rect.left) * canvas.width / rect.width);
+    inputState.mouseY = Math.floor((ev.clientY - rect.top) * canvas.height / rect.height);
+};
+
+canvas.onmousedown = (ev) => {
+    inputState.setButton(ev.button, true);
+};
+
+// Buttons can be released outside of the canvas.
+document.onmouseup = (ev) => {
+    inputState.setButton(ev.button, false);
+};
+
+canvas.oncontextmenu = (ev) => {
+    ev.preventDefault();
+};
+
+canvas.onwheel = (ev) => {
+    ev.preventDefault();
+    // scrolling by lines or pages is converted to pixels approximately
+    const scale = ev.deltaMode === 0 ? 1 : ev.deltaMode === 1 ? 16 : canvas.height;
+    inputState.wheelX += Math.round(ev.deltaX * scale);
+    inputState.wheelY += Math.round(ev.deltaY * scale);
 };
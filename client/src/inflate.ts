@@ -0,0 +1,17 @@
+// Browsers can inflate raw deflate themselves, typings of this TypeScript
+// version just don't know about it yet.
+declare class DecompressionStream {
+    public readonly readable: ReadableStream;
+    public readonly writable: WritableStream;
+    constructor(format: "deflate-raw");
+}
+
+// Inflates a message that server compressed with raw deflate and decodes it
+// as UTF-8. Fails if the data is not valid deflate.
+export function inflateText(data: ArrayBuffer): Promise<string> {
+    const compressed = new Response(data).body as ReadableStream;
+    const inflated = compressed.pipeThrough(new DecompressionStream("deflate-raw"));
+    return new Response(inflated)
+        .arrayBuffer()
+        .then(bytes => new TextDecoder("utf-8").decode(bytes));
+}
@@ -0,0 +1,77 @@
+// State of input devices in the format that `create_input` export of game
+// modules reads, see `primitive_game::input` for the description.
+const inputStateVersion = 1;
+const keyBytes = 16;
+
+export class InputState {
+    public keys = new Uint8Array(keyBytes);
+    public oldKeys = new Uint8Array(keyBytes);
+    public mouseX = 0;
+    public mouseY = 0;
+    public buttons = 0;
+    public oldButtons = 0;
+    public wheelX = 0;
+    public wheelY = 0;
+    public text = "";
+
+    public setKey(key: number, pressed: boolean) {
+        if (key < 0 || key >= keyBytes * 8) {
+            return;
+        }
+        if (pressed) {
+            this.keys[key >> 3] |= 1 << (key & 7);
+        } else {
+            this.keys[key >> 3] &= ~(1 << (key & 7));
+        }
+    }
+
+    public setButton(button: number, pressed: boolean) {
+        if (button < 0 || button >= 8) {
+            return;
+        }
+        if (pressed) {
+            this.buttons |= 1 << button;
+        } else {
+            this.buttons &= ~(1 << button);
+        }
+    }
+
+    // Called after the state was sent, so that the next input knows what was
+    // held before and only gets scrolling and text that happen after it.
+    public advance() {
+        this.oldKeys.set(this.keys);
+        this.oldButtons = this.buttons;
+        this.wheelX = 0;
+        this.wheelY = 0;
+        this.text = "";
+    }
+
+    public releaseAll() {
+        this.keys.fill(0);
+        this.buttons = 0;
+    }
+
+    public serialize(): Uint8Array {
+        const text = new TextEncoder().encode(this.text);
+        const buffer = new Uint8Array(2 + 2 * keyBytes + 8 + 2 + 8 + 4 + text.length);
+        const view = new DataView(buffer.buffer);
+        let offset = 0;
+        view.setUint8(offset++, inputStateVersion);
+        view.setUint8(offset++, keyBytes);
+        buffer.set(this.keys, offset);
+        offset += keyBytes;
+        buffer.set(this.oldKeys, offset);
+        offset += keyBytes;
+        view.setInt32(offset, this.mouseX, true);
+        view.setInt32(offset + 4, this.mouseY, true);
+        offset += 8;
+        view.setUint8(offset++, this.buttons);
+        view.setUint8(offset++, this.oldButtons);
+        view.setInt32(offset, this.wheelX, true);
+        view.setInt32(offset + 4, this.wheelY, true);
+        offset += 8;
+        view.setUint32(offset, text.length, true);
+        buffer.set(text, offset + 4);
+        return buffer;
+    }
+}
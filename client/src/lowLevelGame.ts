@@ -67,6 +67,11 @@ export class LowLevelGame {
         return new Handle(value, "world");
     }
 
+    public applyWorldDiff(base: WorldHandle, diff: BufferHandle): WorldHandle {
+        const value = this.instance.exports.apply_world_diff(base.value, diff.value);
+        return new Handle(value, "world");
+    }
+
     public deserializeInput(buffer: BufferHandle): InputHandle {
         const value = this.instance.exports.deserialize_input(buffer.value);
         return new Handle(value, "input");
@@ -85,8 +90,8 @@ export class LowLevelGame {
         this.memory().set(data, ptr);
     }
 
-    public createInput(letters: number, oldLetters: number, other: number, oldOther: number): InputHandle {
-        const value = this.instance.exports.create_input(letters, oldLetters, other, oldOther);
+    public createInput(inputState: BufferHandle): InputHandle {
+        const value = this.instance.exports.create_input(inputState.value);
         return new Handle(value, "input");
     }
 
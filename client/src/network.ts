@@ -1,11 +1,48 @@
+import { applyDelta } from "delta";
+import { inflateText } from "inflate";
 import { w3cwebsocket as WebSocketClient } from "websocket";
 
-type ServerMessage = WorldStateMessage | PlayerInputMessage;
+type ServerMessage =
+    SentWorldState
+    | PlayerInputMessage
+    | ReloadMessage
+    | ResyncMessage
+    | KeyframeMessage
+    | PingMessage
+    | TimeSyncMessage
+    | InputAdviceMessage;
 
 export interface WorldStateMessage {
     localPlayerId: number;
     frame: number;
     world: Uint8Array;
+    // Set when the world was sent as a difference in game's own format, then
+    // `world` is the keyframe that game code has to apply it to.
+    gameDiff?: Uint8Array;
+}
+
+// World state as server sends it, either the whole world or a difference
+// from the last keyframe.
+interface SentWorldState {
+    localPlayerId: number;
+    frame: number;
+    world?: Uint8Array;
+    delta?: WorldDelta;
+}
+
+interface WorldDelta {
+    keyframe: number;
+    fromGame: boolean;
+    delta: Uint8Array;
+}
+
+interface Keyframe {
+    frame: number;
+    world: Uint8Array;
+}
+
+interface KeyframeMessage {
+    keyframe: Keyframe;
 }
 
 export interface PlayerInputs {
@@ -13,11 +50,54 @@ export interface PlayerInputs {
 }
 
 export interface PlayerInputMessage {
+    seed: number;
     newPlayers: number[];
     removedPlayers: number[];
     inputs: PlayerInputs;
 }
 
+export interface ReloadMessage {
+    reload: SentWorldState;
+}
+
+// Client fell too far behind, so server skipped some updates and sent the
+// current world instead.
+interface ResyncMessage {
+    resync: SentWorldState;
+}
+
+interface PingMessage {
+    ping: { id: number };
+}
+
+// Where the server was in time when it received the answer to its ping.
+export interface TimeSync {
+    // next frame that the server will simulate
+    frame: number;
+    // fraction of frame time that has passed since the previous frame
+    phase: number;
+    // all times are in milliseconds
+    frameTime: number;
+    rtt: number;
+    jitter: number;
+}
+
+interface TimeSyncMessage {
+    timeSync: TimeSync;
+}
+
+// Server's advice to send inputs `change` frames earlier, or later if it is
+// negative.
+export interface InputAdvice {
+    change: number;
+    // inputs that arrived too late and were dropped since the last advice
+    lateInputs: number;
+}
+
+interface InputAdviceMessage {
+    inputAdvice: InputAdvice;
+}
+
 export interface LocalPlayerInput {
     frame: number;
     input: Uint8Array;
@@ -26,19 +106,36 @@ export interface LocalPlayerInput {
 export class NetworkHandler {
     public onWorldState: (world: WorldStateMessage) => void;
     public onPlayerInputs: (inputs: PlayerInputMessage) => void;
+    public onReload: (world: WorldStateMessage) => void;
+    public onResync: (world: WorldStateMessage) => void;
+    public onTimeSync: (sync: TimeSync) => void;
+    public onInputAdvice: (advice: InputAdvice) => void;
     private client: WebSocketClient;
     private pendingInputs: PlayerInputMessage[];
     private receivedWorldState: boolean;
+    private keyframe: Keyframe | null;
+    // Messages after a compressed one wait for it to be inflated, so that
+    // they are handled in the order they were received.
+    private inflating: Promise<void> | null;
 
     constructor() {
-        this.client = new WebSocketClient("ws://" + location.host + "/ws");
+        // server may send large messages as binary frames with deflated JSON
+        this.client = new WebSocketClient("ws://" + location.host + "/ws?compression=deflate");
+        // browsers give binary frames as blobs by default
+        this.client.binaryType = "arraybuffer";
         this.pendingInputs = [];
         this.receivedWorldState = false;
+        this.keyframe = null;
+        this.inflating = null;
         this.onWorldState = _ => {};
         this.onPlayerInputs = _ => {};
+        this.onReload = _ => {};
+        this.onResync = _ => {};
+        this.onTimeSync = _ => {};
+        this.onInputAdvice = _ => {};
         this.client.onopen = () => this.onOpen();
         this.client.onerror = err => this.error(err);
-        this.client.onclose = () => this.onClose();
+        this.client.onclose = (event?: { reason?: string }) => this.onClose(event);
         this.client.onmessage = msg => this.onMessage(msg);
     }
 
@@ -51,11 +148,47 @@ export class NetworkHandler {
         }));
     }
 
+    // Tells the server that updates were applied up to `frame`, so that it
+    // knows how far behind the client is.
+    public sendAck(frame: number) {
+        this.client.send(JSON.stringify({ ack: { frame } }));
+    }
+
     public joinGame(frame: number) {
         console.info("Joining on frame:", frame);
         this.client.send(JSON.stringify({ join: { frame } }));
     }
 
+    // Should be called when game code is reloaded after `onReload`. Player
+    // inputs received while reloading are delivered only after this.
+    public reloadCompleted() {
+        this.receivedWorldState = true;
+        this.pendingInputs.forEach(this.onPlayerInputs);
+        this.pendingInputs = [];
+    }
+
+    // Applies the delta if the world was sent as one. Returns null if it can't
+    // be applied, then the world is lost and there is nothing to run.
+    private resolveWorldState(state: SentWorldState): WorldStateMessage | null {
+        const { frame, localPlayerId, delta } = state;
+        if (delta === undefined) {
+            return { frame, localPlayerId, world: state.world as Uint8Array };
+        }
+        if (this.keyframe === null || this.keyframe.frame !== delta.keyframe) {
+            console.error("Received a delta from unknown keyframe:", delta.keyframe);
+            return null;
+        }
+        if (delta.fromGame) {
+            return { frame, localPlayerId, world: this.keyframe.world, gameDiff: delta.delta };
+        }
+        const world = applyDelta(this.keyframe.world, delta.delta);
+        if (world === null) {
+            console.error("Received an invalid delta from keyframe:", delta.keyframe);
+            return null;
+        }
+        return { frame, localPlayerId, world };
+    }
+
     private error(err: Error) {
         console.error(`Connection error: ${err}`);
     }
@@ -64,16 +197,76 @@ export class NetworkHandler {
         console.info("Connected");
     }
 
-    private onClose() {
-        console.info("Disconnected");
+    private onClose(event?: { reason?: string }) {
+        // server says why it disconnected the client in the close frame
+        if (event !== undefined && event.reason) {
+            console.warn(`Disconnected: ${event.reason}`);
+        } else {
+            console.info("Disconnected");
+        }
     }
 
     private onMessage(message: any) {
         console.debug("Received message:", message);
-        const payload = parseMessagePayload(message.data);
-        if (isWorldState(payload)) {
+        const previous = this.inflating;
+        if (typeof message.data === "string") {
+            const text: string = message.data;
+            if (previous === null) {
+                this.handleMessage(text);
+            } else {
+                this.inflating = previous.then(() => this.handleMessage(text));
+                this.clearInflatingWhenDone();
+            }
+            return;
+        }
+        const inflated = inflateText(message.data as ArrayBuffer);
+        const ready = previous === null ? inflated : previous.then(() => inflated);
+        this.inflating = ready.then(
+            text => this.handleMessage(text),
+            err => console.error("Received invalid compressed message:", err),
+        );
+        this.clearInflatingWhenDone();
+    }
+
+    private clearInflatingWhenDone() {
+        const inflating = this.inflating as Promise<void>;
+        inflating.then(() => {
+            if (this.inflating === inflating) {
+                this.inflating = null;
+            }
+        });
+    }
+
+    private handleMessage(text: string) {
+        const payload = parseMessagePayload(text);
+        if (isPing(payload)) {
+            this.client.send(JSON.stringify({ pong: { id: payload.ping.id } }));
+        } else if (isTimeSync(payload)) {
+            this.onTimeSync(payload.timeSync);
+        } else if (isInputAdvice(payload)) {
+            this.onInputAdvice(payload.inputAdvice);
+        } else if (isKeyframe(payload)) {
+            this.keyframe = payload.keyframe;
+        } else if (isReload(payload)) {
+            this.receivedWorldState = false;
+            // keyframes are for the old game code
+            this.keyframe = null;
+            const world = this.resolveWorldState(payload.reload);
+            if (world !== null) {
+                this.onReload(world);
+            }
+        } else if (isResync(payload)) {
+            const world = this.resolveWorldState(payload.resync);
+            if (world !== null) {
+                this.onResync(world);
+            }
+        } else if (isWorldState(payload)) {
+            const world = this.resolveWorldState(payload);
+            if (world === null) {
+                return;
+            }
             this.receivedWorldState = true;
-            this.onWorldState(payload);
+            this.onWorldState(world);
             this.pendingInputs.forEach(this.onPlayerInputs);
             this.pendingInputs = [];
         } else {
@@ -86,14 +279,44 @@ export class NetworkHandler {
     }
 }
 
+function parseWorldState(msg: any): SentWorldState {
+    const state: SentWorldState = {
+        frame: msg.frame,
+        localPlayerId: msg.localPlayerId,
+    };
+    if (msg.delta !== undefined) {
+        state.delta = {
+            keyframe: msg.delta.keyframe,
+            fromGame: msg.delta.fromGame,
+            delta: new Uint8Array(msg.delta.delta),
+        };
+    } else {
+        state.world = new Uint8Array(msg.world);
+    }
+    return state;
+}
+
 function parseMessagePayload(message: any): ServerMessage {
     const msg = JSON.parse(message);
-    if (msg.world !== undefined) {
+    if (msg.ping !== undefined || msg.timeSync !== undefined || msg.inputAdvice !== undefined) {
+        return msg;
+    } else if (msg.keyframe !== undefined) {
         return {
-            frame: msg.frame,
-            localPlayerId: msg.localPlayerId,
-            world: new Uint8Array(msg.world),
+            keyframe: {
+                frame: msg.keyframe.frame,
+                world: new Uint8Array(msg.keyframe.world),
+            },
         };
+    } else if (msg.reload !== undefined) {
+        return {
+            reload: parseWorldState(msg.reload),
+        };
+    } else if (msg.resync !== undefined) {
+        return {
+            resync: parseWorldState(msg.resync),
+        };
+    } else if (msg.world !== undefined || msg.delta !== undefined) {
+        return parseWorldState(msg);
     } else {
         const inputs: PlayerInputs = {};
         for (const key of Object.keys(msg.inputs)) {
@@ -102,13 +325,38 @@ function parseMessagePayload(message: any): ServerMessage {
         return {
             frame: msg.frame,
             inputs,
+            seed: msg.seed,
             newPlayers: msg.newPlayers,
             removedPlayers: msg.removedPlayers,
         };
     }
 }
 
-function isWorldState(message: ServerMessage): message is WorldStateMessage {
-    const m = message as WorldStateMessage;
-    return m.frame !== undefined && m.world !== undefined && m.localPlayerId !== undefined;
+function isReload(message: ServerMessage): message is ReloadMessage {
+    return (message as ReloadMessage).reload !== undefined;
+}
+
+function isResync(message: ServerMessage): message is ResyncMessage {
+    return (message as ResyncMessage).resync !== undefined;
+}
+
+function isPing(message: ServerMessage): message is PingMessage {
+    return (message as PingMessage).ping !== undefined;
+}
+
+function isTimeSync(message: ServerMessage): message is TimeSyncMessage {
+    return (message as TimeSyncMessage).timeSync !== undefined;
+}
+
+function isInputAdvice(message: ServerMessage): message is InputAdviceMessage {
+    return (message as InputAdviceMessage).inputAdvice !== undefined;
+}
+
+function isKeyframe(message: ServerMessage): message is KeyframeMessage {
+    return (message as KeyframeMessage).keyframe !== undefined;
+}
+
+function isWorldState(message: ServerMessage): message is SentWorldState {
+    const m = message as SentWorldState;
+    return m.frame !== undefined && (m.world !== undefined || m.delta !== undefined) && m.localPlayerId !== undefined;
 }
@@ -2,8 +2,12 @@
 
 use std::fs;
 use std::io;
+use std::path::Path;
 use std::process::Command;
 
+/// Files from this directory end up in `assets/` directory of the package.
+const ASSETS_PATH: &str = "./primitive-game/assets";
+
 fn main() {
     assert!(Command::new("cargo")
         .arg("build")
@@ -31,7 +35,27 @@ fn main() {
 fn write_zip(output_file: fs::File, mut wasm: &[u8]) -> zip::result::ZipResult<()> {
     let mut zip = zip::ZipWriter::new(output_file);
     zip.add_directory("assets/", zip::write::FileOptions::default())?;
+    if Path::new(ASSETS_PATH).is_dir() {
+        add_assets(&mut zip, Path::new(ASSETS_PATH), "assets/")?;
+    }
     zip.start_file("code.wasm", zip::write::FileOptions::default())?;
     io::copy(&mut wasm, &mut zip)?;
     Ok(())
 }
+
+fn add_assets(zip: &mut zip::ZipWriter<fs::File>, directory: &Path, prefix: &str) -> zip::result::ZipResult<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            let name = format!("{}/", name);
+            zip.add_directory(name.as_str(), zip::write::FileOptions::default())?;
+            add_assets(zip, &entry.path(), &name)?;
+        } else {
+            zip.start_file(name.as_str(), zip::write::FileOptions::default())?;
+            io::copy(&mut fs::File::open(entry.path())?, zip)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! `#[derive(Serialize)]` for `primitive_game::game::Serialize`.
+//!
+//! Fields are written in declaration order. Enums start with a one byte tag,
+//! which every variant must set explicitly, either as a discriminant
+//! (`Variant = 1`) or with `#[serialize(tag = 1)]`, so that reordering
+//! variants doesn't change the format of existing worlds.
+
+#![warn(rust_2018_idioms)]
+
+use std::collections::BTreeMap;
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DataEnum, DeriveInput, Error, Expr, Fields, Lit, LitInt, Variant};
+
+#[proc_macro_derive(Serialize, attributes(serialize))]
+pub fn derive_serialize(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream, Error> {
+    let krate = quote!(::primitive_game::game);
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::primitive_game::game::Serialize));
+    }
+    let (read, write) = match &input.data {
+        Data::Struct(data) => {
+            let (construct, bindings, write) = fields(&data.fields, quote!(Self));
+            (quote!(Ok(#construct)), quote!(let #bindings = self; #write))
+        }
+        Data::Enum(data) => expand_enum(data)?,
+        Data::Union(_) => return Err(Error::new_spanned(&input.ident, "unions can't be serialized")),
+    };
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::Serialize for #name #type_generics #where_clause {
+            fn read<__R: #krate::Reader>(reader: &mut __R) -> ::core::result::Result<Self, #krate::ReadError> {
+                #read
+            }
+
+            #[allow(unused_variables)]
+            fn write<__W: #krate::Writer>(&self, writer: &mut __W) {
+                #write
+            }
+        }
+    })
+}
+
+/// Expression that reads the fields into `path`, pattern that binds them,
+/// and statements that write the bound fields.
+fn fields(fields: &Fields, path: TokenStream) -> (TokenStream, TokenStream, TokenStream) {
+    let krate = quote!(::primitive_game::game);
+    let read = quote!(#krate::Serialize::read(reader)?);
+    match fields {
+        Fields::Named(named) => {
+            let names: Vec<_> = named.named.iter().map(|field| field.ident.as_ref().unwrap()).collect();
+            // fields are bound to generated names, so that they can't shadow
+            // `writer`
+            let bindings: Vec<_> = names.iter().map(|name| format_ident!("__field_{}", name)).collect();
+            (
+                quote!(#path { #(#names: #read),* }),
+                quote!(#path { #(#names: #bindings),* }),
+                quote!(#(#krate::Serialize::write(#bindings, writer);)*),
+            )
+        }
+        Fields::Unnamed(unnamed) => {
+            let names: Vec<_> = (0..unnamed.unnamed.len()).map(|index| format_ident!("field{}", index)).collect();
+            let reads = names.iter().map(|_| &read);
+            (
+                quote!(#path(#(#reads),*)),
+                quote!(#path(#(#names),*)),
+                quote!(#(#krate::Serialize::write(#names, writer);)*),
+            )
+        }
+        Fields::Unit => (path.clone(), path, quote!()),
+    }
+}
+
+fn expand_enum(data: &DataEnum) -> Result<(TokenStream, TokenStream), Error> {
+    let krate = quote!(::primitive_game::game);
+    let mut tags = BTreeMap::new();
+    let mut read_arms = Vec::new();
+    let mut write_arms = Vec::new();
+    for variant in &data.variants {
+        let tag = variant_tag(variant)?;
+        if let Some(other) = tags.insert(tag, &variant.ident) {
+            let message = format!("variant `{}` has the same tag as `{}`", variant.ident, other);
+            return Err(Error::new_spanned(&variant.ident, message));
+        }
+        let name = &variant.ident;
+        let (construct, bindings, write) = fields(&variant.fields, quote!(Self::#name));
+        read_arms.push(quote!(#tag => Ok(#construct),));
+        write_arms.push(quote! {
+            #bindings => {
+                #krate::Serialize::write(&#tag, writer);
+                #write
+            }
+        });
+    }
+    let read = quote! {
+        let __offset = #krate::Reader::position(reader);
+        match <u8 as #krate::Serialize>::read(reader)? {
+            #(#read_arms)*
+            _ => Err(#krate::ReadError::new(#krate::ReadErrorKind::InvalidTag, __offset)),
+        }
+    };
+    let write = quote! {
+        match self {
+            #(#write_arms)*
+        }
+    };
+    Ok((read, write))
+}
+
+fn variant_tag(variant: &Variant) -> Result<u8, Error> {
+    let mut tag = None;
+    if let Some((_, discriminant)) = &variant.discriminant {
+        match discriminant {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Int(int) => tag = Some(parse_tag(int)?),
+                _ => return Err(Error::new_spanned(discriminant, "tag must be an integer")),
+            },
+            _ => return Err(Error::new_spanned(discriminant, "tag must be an integer literal")),
+        }
+    }
+    for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("serialize")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("tag") {
+                return Err(meta.error("unknown serialize attribute"));
+            }
+            if tag.is_some() {
+                return Err(meta.error("tag is set twice"));
+            }
+            tag = Some(parse_tag(&meta.value()?.parse()?)?);
+            Ok(())
+        })?;
+    }
+    tag.ok_or_else(|| {
+        let message = format!(
+            "variant `{}` needs an explicit tag, like `{} = 1` or `#[serialize(tag = 1)]`",
+            variant.ident, variant.ident,
+        );
+        Error::new_spanned(&variant.ident, message)
+    })
+}
+
+fn parse_tag(int: &LitInt) -> Result<u8, Error> {
+    int.base10_parse::<u8>()
+        .map_err(|_| Error::new_spanned(int, "tag must be in 0..=255"))
+}
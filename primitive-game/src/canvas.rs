@@ -0,0 +1,325 @@
+//! Drawing API that games use in `Game::render`. Everything is drawn by the
+//! host, except text, which is drawn from a built-in bitmap font so that it
+//! looks the same on every host.
+
+/// Color with alpha, stored as `0xAARRGGBB`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Color(pub u32);
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+    pub const TRANSPARENT: Color = Color::rgba(0, 0, 0, 0);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color::rgba(r, g, b, 255)
+    }
+
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color((a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32)
+    }
+
+    /// Opaque color from `0xRRGGBB`.
+    pub const fn from_hex(hex: u32) -> Color {
+        Color(0xff00_0000 | (hex & 0x00ff_ffff))
+    }
+
+    pub fn with_alpha(self, a: u8) -> Color {
+        Color((self.0 & 0x00ff_ffff) | (a as u32) << 24)
+    }
+
+    pub fn alpha(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+}
+
+/// Image from `assets/` in the game package.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sprite {
+    id: u32,
+}
+
+/// Width of a character in font pixels, including spacing.
+const GLYPH_ADVANCE: u32 = 6;
+/// Height of a line of text in font pixels, including spacing.
+const LINE_HEIGHT: u32 = 9;
+
+pub struct Canvas {
+    width: u32,
+    height: u32,
+}
+
+impl Canvas {
+    pub(crate) fn new(width: u32, height: u32) -> Canvas {
+        Canvas { width, height }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: Color) {
+        host::fill_rect(x, y, width, height, color.0);
+    }
+
+    pub fn fill_circle(&mut self, x: i32, y: i32, radius: u32, color: Color) {
+        host::fill_circle(x, y, radius, color.0);
+    }
+
+    /// One pixel wide line, including both ends.
+    pub fn draw_line(&mut self, from: (i32, i32), to: (i32, i32), color: Color) {
+        host::draw_line(from.0, from.1, to.0, to.1, color.0);
+    }
+
+    /// Fills the inside of a polygon, using even-odd rule for polygons that
+    /// intersect themselves.
+    pub fn fill_polygon(&mut self, points: &[(i32, i32)], color: Color) {
+        let coordinates = points.iter().flat_map(|&(x, y)| [x, y]).collect::<Vec<i32>>();
+        host::fill_polygon(&coordinates, color.0);
+    }
+
+    /// Draws text with its top left corner at `x`, `y`. Each pixel of the
+    /// font is `scale` pixels big. Characters that the font doesn't have
+    /// are drawn as `?`.
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, scale: u32, color: Color) {
+        let (mut left, mut top) = (x, y);
+        for ch in text.chars() {
+            if ch == '\n' {
+                left = x;
+                top = top.saturating_add(scaled(LINE_HEIGHT, scale));
+                continue;
+            }
+            for (column, bits) in glyph(ch).iter().enumerate() {
+                let column_x = left.saturating_add(scaled(column as u32, scale));
+                // one rectangle for each vertical run of pixels
+                let mut row: u32 = 0;
+                while row < 8 {
+                    if bits & (1 << row) == 0 {
+                        row += 1;
+                        continue;
+                    }
+                    let start = row;
+                    while row < 8 && bits & (1 << row) != 0 {
+                        row += 1;
+                    }
+                    let run_y = top.saturating_add(scaled(start, scale));
+                    let height = (row - start).saturating_mul(scale);
+                    self.fill_rect(column_x, run_y, scale, height, color);
+                }
+            }
+            left = left.saturating_add(scaled(GLYPH_ADVANCE, scale));
+        }
+    }
+
+    /// Size of text drawn with `draw_text`.
+    pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+        let lines = text.split('\n');
+        let (count, longest) = lines.fold((0u32, 0), |(count, longest), line| {
+            (count + 1, longest.max(line.chars().count() as u32))
+        });
+        let width = longest.saturating_mul(GLYPH_ADVANCE).saturating_mul(scale);
+        (width, count.saturating_mul(LINE_HEIGHT).saturating_mul(scale))
+    }
+
+    /// Sprite from `assets/<name>` in the game package. Sprites might still
+    /// be loading when they are drawn, and then nothing is drawn.
+    pub fn sprite(&mut self, name: &str) -> Sprite {
+        Sprite { id: host::load_sprite(name) }
+    }
+
+    /// Draws sprite with its top left corner at `x`, `y`.
+    pub fn draw_sprite(&mut self, sprite: Sprite, x: i32, y: i32) {
+        host::draw_sprite(sprite.id, x, y);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod host {
+    use crate::externals;
+
+    pub fn fill_rect(x: i32, y: i32, width: u32, height: u32, color: u32) {
+        unsafe { externals::fill_rect(x, y, width, height, color) }
+    }
+
+    pub fn fill_circle(x: i32, y: i32, radius: u32, color: u32) {
+        unsafe { externals::fill_circle(x, y, radius, color) }
+    }
+
+    pub fn draw_line(x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+        unsafe { externals::draw_line(x0, y0, x1, y1, color) }
+    }
+
+    pub fn fill_polygon(coordinates: &[i32], color: u32) {
+        let points = (coordinates.len() / 2) as u32;
+        unsafe { externals::fill_polygon(coordinates.as_ptr() as usize as u32, points, color) }
+    }
+
+    pub fn load_sprite(name: &str) -> u32 {
+        unsafe { externals::load_sprite(name.as_ptr() as usize as u32, name.len() as u32) }
+    }
+
+    pub fn draw_sprite(sprite: u32, x: i32, y: i32) {
+        unsafe { externals::draw_sprite(sprite, x, y) }
+    }
+}
+
+// Native builds are only used for simulating the game on the server, which
+// renders snapshots with the wasm module instead.
+#[cfg(not(target_arch = "wasm32"))]
+mod host {
+    pub fn fill_rect(_x: i32, _y: i32, _width: u32, _height: u32, _color: u32) {}
+    pub fn fill_circle(_x: i32, _y: i32, _radius: u32, _color: u32) {}
+    pub fn draw_line(_x0: i32, _y0: i32, _x1: i32, _y1: i32, _color: u32) {}
+    pub fn fill_polygon(_coordinates: &[i32], _color: u32) {}
+    pub fn load_sprite(_name: &str) -> u32 { 0 }
+    pub fn draw_sprite(_sprite: u32, _x: i32, _y: i32) {}
+}
+
+/// Offset of `value` font pixels, saturating instead of overflowing for huge
+/// scales.
+fn scaled(value: u32, scale: u32) -> i32 {
+    value.saturating_mul(scale).min(i32::MAX as u32) as i32
+}
+
+/// Columns of a 5x7 glyph, lowest bit is the top row.
+fn glyph(ch: char) -> &'static [u8; 5] {
+    let index = match ch {
+        ' '..='~' => ch as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT[index]
+}
+
+/// Printable ASCII characters, starting with space.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '''
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // '*'
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // 'F'
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // 'G'
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // 'M'
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\'
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x08, 0x04, 0x08, 0x10, 0x08], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors() {
+        assert_eq!(Color::rgb(1, 2, 3), Color(0xff01_0203));
+        assert_eq!(Color::from_hex(0x123456), Color::rgba(0x12, 0x34, 0x56, 0xff));
+        assert_eq!(Color::WHITE.with_alpha(128).alpha(), 128);
+    }
+
+    #[test]
+    fn font_covers_printable_ascii() {
+        assert_eq!(glyph('A'), &[0x7e, 0x11, 0x11, 0x11, 0x7e]);
+        assert_eq!(glyph('~'), &FONT[94]);
+        assert_eq!(glyph('é'), glyph('?'));
+    }
+
+    #[test]
+    fn text_size() {
+        assert_eq!(Canvas::text_size("", 1), (0, 9));
+        assert_eq!(Canvas::text_size("abc\nde", 2), (36, 36));
+        assert_eq!(Canvas::text_size("a", u32::MAX), (u32::MAX, u32::MAX));
+        assert_eq!(scaled(LINE_HEIGHT, u32::MAX), i32::MAX);
+    }
+}
@@ -0,0 +1,219 @@
+//! Compact encodings for `Serialize`. Readers and writers have an
+//! `Encoding`: with `Encoding::Compact`, multi-byte integers and lengths are
+//! written as LEB128 varints, and signed integers are zigzag-encoded first,
+//! so small numbers take a single byte. Games choose the encoding of their
+//! world and input with `Game::WORLD_ENCODING` and `Game::INPUT_ENCODING`.
+
+use crate::game::{self, Reader, ReadError, ReadErrorKind, Serialize, Writer};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum Encoding {
+    /// Integers are little-endian and take their full size.
+    #[default]
+    Fixed,
+    /// Integers and lengths are varints.
+    Compact,
+}
+
+/// Reader or writer with a different encoding than the one it wraps.
+pub struct WithEncoding<T> {
+    inner: T,
+    encoding: Encoding,
+}
+
+impl<T> WithEncoding<T> {
+    pub fn new(inner: T, encoding: Encoding) -> Self {
+        WithEncoding { inner, encoding }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Reader> Reader for WithEncoding<R> {
+    fn read_byte(&mut self) -> Result<u8, ReadError> {
+        self.inner.read_byte()
+    }
+
+    fn position(&self) -> usize {
+        self.inner.position()
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+impl<W: Writer> Writer for WithEncoding<W> {
+    fn write_byte(&mut self, byte: u8) {
+        self.inner.write_byte(byte)
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+pub fn write_varint<W: Writer>(writer: &mut W, mut value: u64) {
+    while value >= 0x80 {
+        writer.write_byte(value as u8 | 0x80);
+        value >>= 7;
+    }
+    writer.write_byte(value as u8);
+}
+
+/// Fails if the number doesn't fit in `bits` bits, or if it has redundant
+/// trailing zero bytes, so that every number has exactly one encoding.
+pub fn read_varint<R: Reader>(reader: &mut R, bits: u32) -> Result<u64, ReadError> {
+    let offset = reader.position();
+    let invalid = || ReadError::new(ReadErrorKind::InvalidValue, offset);
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_byte()?;
+        let payload = u64::from(byte & 0x7f);
+        if shift >= bits || (shift > 0 && byte == 0) || (payload << shift) >> shift != payload {
+            return Err(invalid());
+        }
+        value |= payload << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    if bits < 64 && value >> bits != 0 {
+        return Err(invalid());
+    }
+    Ok(value)
+}
+
+/// Maps signed numbers to unsigned so that numbers close to zero are small:
+/// 0, -1, 1, -2, 2... become 0, 1, 2, 3, 4...
+pub fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Booleans stored as bits, eight in a byte, after their count.
+#[derive(PartialEq, Eq, Debug, Default, Clone)]
+pub struct PackedBools(pub Vec<bool>);
+
+impl Serialize for PackedBools {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        let count = game::read_length(reader)?;
+        let mut bools = Vec::with_capacity(count.min(game::MAX_PREALLOCATED));
+        let mut byte = 0;
+        for index in 0..count {
+            if index % 8 == 0 {
+                byte = reader.read_byte()?;
+            }
+            bools.push(byte & (1 << (index % 8)) != 0);
+        }
+        // unused bits must be zero, so that equal values are always written the same way
+        if !count.is_multiple_of(8) && byte >> (count % 8) != 0 {
+            return Err(ReadError::new(ReadErrorKind::InvalidValue, reader.position() - 1));
+        }
+        Ok(PackedBools(bools))
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        (self.0.len() as u32).write(writer);
+        for chunk in self.0.chunks(8) {
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (index, &bit)| byte | ((bit as u8) << index));
+            writer.write_byte(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::SliceReader;
+
+    fn varint_bytes(value: u64) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        write_varint(&mut buffer, value);
+        buffer
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(varint_bytes(0), [0]);
+        assert_eq!(varint_bytes(127), [0x7f]);
+        assert_eq!(varint_bytes(128), [0x80, 0x01]);
+        assert_eq!(varint_bytes(300), [0xac, 0x02]);
+        assert_eq!(varint_bytes(u64::MAX).len(), 10);
+        for &value in &[0, 1, 127, 128, 300, 1 << 35, u64::from(u32::MAX), u64::MAX] {
+            let bytes = varint_bytes(value);
+            assert_eq!(read_varint(&mut SliceReader::new(&bytes), 64).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn invalid_varints() {
+        // too large for u32
+        assert!(read_varint(&mut SliceReader::new(&varint_bytes(1 << 32)), 32).is_err());
+        assert!(read_varint(&mut SliceReader::new(&varint_bytes(u64::from(u32::MAX))), 32).is_ok());
+        // 11 bytes, or 10 bytes with more than 64 bits
+        assert!(read_varint(&mut SliceReader::new(&[0xff; 10]), 64).is_err());
+        assert!(read_varint(&mut SliceReader::new(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]), 64).is_err());
+        // redundant zero byte
+        assert!(read_varint(&mut SliceReader::new(&[0x80, 0x00]), 64).is_err());
+        // unterminated
+        assert!(read_varint(&mut SliceReader::new(&[0x80]), 64).is_err());
+    }
+
+    #[test]
+    fn zigzag() {
+        let pairs = [(0, 0), (-1, 1), (1, 2), (-2, 3), (i64::MAX, u64::MAX - 1), (i64::MIN, u64::MAX)];
+        for &(signed, unsigned) in &pairs {
+            assert_eq!(zigzag_encode(signed), unsigned);
+            assert_eq!(zigzag_decode(unsigned), signed);
+        }
+    }
+
+    fn compact_bytes<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut writer = WithEncoding::new(Vec::new(), Encoding::Compact);
+        value.write(&mut writer);
+        writer.into_inner()
+    }
+
+    fn read_compact<T: Serialize>(bytes: &[u8]) -> Result<T, ReadError> {
+        T::read(&mut SliceReader::with_encoding(bytes, Encoding::Compact))
+    }
+
+    #[test]
+    fn compact_integers() {
+        assert_eq!(compact_bytes(&5u32), [5]);
+        assert_eq!(compact_bytes(&-3i32), [5]);
+        assert_eq!(compact_bytes(&300u16), [0xac, 0x02]);
+        assert_eq!(compact_bytes(&200u8), [200]);
+        assert_eq!(compact_bytes(&vec![1i64, -1]), [2, 2, 1]);
+        assert_eq!(read_compact::<Vec<i64>>(&[2, 2, 1]).unwrap(), [1, -1]);
+        assert_eq!(read_compact::<i16>(&compact_bytes(&i16::MIN)).unwrap(), i16::MIN);
+        assert_eq!(read_compact::<u64>(&compact_bytes(&u64::MAX)).unwrap(), u64::MAX);
+        assert!(read_compact::<u16>(&compact_bytes(&70_000u32)).is_err());
+        assert!(read_compact::<i16>(&compact_bytes(&70_000i32)).is_err());
+        assert_eq!(read_compact::<String>(&compact_bytes(&"hi".to_string())).unwrap(), "hi");
+    }
+
+    #[test]
+    fn packed_bools() {
+        let bools = PackedBools(vec![true, false, false, true, false, false, false, false, true, true]);
+        let mut buffer = Vec::new();
+        bools.write(&mut buffer);
+        assert_eq!(buffer, [10, 0, 0, 0, 0b0000_1001, 0b0000_0011]);
+        assert_eq!(PackedBools::read(&mut SliceReader::new(&buffer)).unwrap(), bools);
+        assert_eq!(compact_bytes(&bools), [10, 0b0000_1001, 0b0000_0011]);
+        assert!(PackedBools::read(&mut SliceReader::new(&[2, 0, 0, 0, 0b100])).is_err(), "unused bits were accepted");
+        assert!(PackedBools::read(&mut SliceReader::new(&[9, 0, 0, 0, 0])).is_err());
+        assert_eq!(PackedBools::read(&mut SliceReader::new(&[0, 0, 0, 0])).unwrap(), PackedBools(vec![]));
+    }
+}
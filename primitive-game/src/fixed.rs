@@ -0,0 +1,184 @@
+//! Fixed-point numbers for game logic. Floating point results can differ
+//! between the server and browsers, while fixed-point arithmetic is integer
+//! math that gives the same results everywhere.
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use crate::game::{Reader, ReadError, Serialize, Writer};
+
+/// Number with 16 integer and 16 fractional bits. Arithmetic wraps around on
+/// overflow, the same way in debug and release builds.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    pub const FRACTION_BITS: u32 = 16;
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << Fixed::FRACTION_BITS);
+    pub const MIN: Fixed = Fixed(i32::MIN);
+    pub const MAX: Fixed = Fixed(i32::MAX);
+
+    /// Number whose representation is `bits`, that is `bits / 2^16`.
+    pub const fn from_bits(bits: i32) -> Fixed {
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    pub fn from_int(value: i32) -> Fixed {
+        Fixed(value.wrapping_shl(Fixed::FRACTION_BITS))
+    }
+
+    /// `numerator / denominator`, rounded towards zero. Panics if the
+    /// denominator is zero.
+    pub fn from_ratio(numerator: i32, denominator: i32) -> Fixed {
+        Fixed((i64::from(numerator) << Fixed::FRACTION_BITS).wrapping_div(i64::from(denominator)) as i32)
+    }
+
+    /// Largest integer that is not greater than the number.
+    pub fn floor(self) -> i32 {
+        self.0 >> Fixed::FRACTION_BITS
+    }
+
+    /// Nearest integer, halves are rounded up.
+    pub fn round(self) -> i32 {
+        (self.0.wrapping_add(1 << (Fixed::FRACTION_BITS - 1))) >> Fixed::FRACTION_BITS
+    }
+
+    pub fn abs(self) -> Fixed {
+        Fixed(self.0.wrapping_abs())
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_add(other.0))
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_sub(other.0))
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(self.0.wrapping_neg())
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    /// Rounds towards negative infinity.
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) * i64::from(other.0)) >> Fixed::FRACTION_BITS) as i32)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    /// Rounds towards zero, panics on division by zero.
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) << Fixed::FRACTION_BITS) / i64::from(other.0)) as i32)
+    }
+}
+
+impl fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Writes the exact decimal value. Formatting goes through integers, because
+/// game modules with floating point instructions are rejected by the server.
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits = i64::from(self.0);
+        let magnitude = bits.abs();
+        let sign = if bits < 0 { "-" } else { "" };
+        let integer = magnitude >> Fixed::FRACTION_BITS;
+        let fraction = magnitude & ((1 << Fixed::FRACTION_BITS) - 1);
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, integer);
+        }
+        // fraction / 2^16 = fraction * 5^16 / 10^16
+        let digits = format!("{:016}", fraction * 5i64.pow(Fixed::FRACTION_BITS));
+        write!(f, "{}{}.{}", sign, integer, digits.trim_end_matches('0'))
+    }
+}
+
+impl Serialize for Fixed {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        i32::read(reader).map(Fixed)
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        self.0.write(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::SliceReader;
+
+    #[test]
+    fn arithmetic() {
+        let half = Fixed::from_ratio(1, 2);
+        assert_eq!(half.to_bits(), 0x8000);
+        assert_eq!(Fixed::from_int(3) + half, Fixed::from_ratio(7, 2));
+        assert_eq!(Fixed::from_int(3) * half, Fixed::from_ratio(3, 2));
+        assert_eq!(Fixed::ONE / Fixed::from_int(4), Fixed::from_ratio(1, 4));
+        assert_eq!(-Fixed::from_int(2) - half, Fixed::from_ratio(-5, 2));
+        assert_eq!(Fixed::from_ratio(-5, 2).floor(), -3);
+        assert_eq!(Fixed::from_ratio(-5, 2).round(), -2);
+        assert_eq!(Fixed::from_ratio(5, 2).round(), 3);
+        assert_eq!(Fixed::from_ratio(-5, 2).abs(), Fixed::from_ratio(5, 2));
+        assert_eq!(Fixed::from_ratio(1, 3).to_bits(), 21845);
+        assert_eq!(Fixed::MAX + Fixed::from_bits(1), Fixed::MIN);
+        assert_eq!(Fixed::from_ratio(3, 4).to_string(), "0.75");
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(Fixed::ZERO.to_string(), "0");
+        assert_eq!(Fixed::from_int(-3).to_string(), "-3");
+        assert_eq!(Fixed::from_ratio(-5, 2).to_string(), "-2.5");
+        assert_eq!(format!("{:?}", Fixed::from_bits(-1)), "-0.0000152587890625");
+        assert_eq!(Fixed::from_ratio(1, 3).to_string(), "0.3333282470703125");
+        assert_eq!(Fixed::MIN.to_string(), "-32768");
+        assert_eq!(Fixed::MAX.to_string(), "32767.9999847412109375");
+    }
+
+    #[test]
+    fn serialize() {
+        let mut buffer = Vec::new();
+        Fixed::from_ratio(-1, 2).write(&mut buffer);
+        assert_eq!(buffer, [0x00, 0x80, 0xff, 0xff]);
+        assert_eq!(Fixed::read(&mut SliceReader::new(&buffer)).unwrap(), Fixed::from_ratio(-1, 2));
+        assert!(Fixed::read(&mut SliceReader::new(&buffer[..3])).is_err());
+    }
+}
@@ -1,3 +1,13 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use crate::canvas::Canvas;
+use crate::encoding::{self, Encoding, WithEncoding};
+pub use crate::input::{InputState, Key, KeyboardState, MouseButton, MouseState};
+/// Derives `Serialize` for structs and enums, see `primitive_game_derive`.
+pub use primitive_game_derive::Serialize;
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub struct PlayerId {
     id: u32,
@@ -15,22 +25,63 @@ impl PlayerId {
 
 pub trait Reader {
     fn read_byte(&mut self) -> Result<u8, ReadError>;
+
+    /// Number of bytes read so far, errors say where they happened.
+    fn position(&self) -> usize;
+
+    fn encoding(&self) -> Encoding {
+        Encoding::Fixed
+    }
+}
+
+/// Reader of a byte slice that keeps track of its position.
+pub struct SliceReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    encoding: Encoding,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        SliceReader::with_encoding(bytes, Encoding::Fixed)
+    }
+
+    pub fn with_encoding(bytes: &'a [u8], encoding: Encoding) -> Self {
+        SliceReader { bytes, position: 0, encoding }
+    }
+
+    /// Bytes that weren't read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.position..]
+    }
 }
 
-impl<'a> Reader for &'a [u8] {
+impl<'a> Reader for SliceReader<'a> {
     fn read_byte(&mut self) -> Result<u8, ReadError> {
-        if self.is_empty() {
-            Err(ReadError)
-        } else {
-            let byte = self[0];
-            *self = &self[1..];
-            Ok(byte)
+        match self.bytes.get(self.position) {
+            Some(&byte) => {
+                self.position += 1;
+                Ok(byte)
+            }
+            None => Err(ReadError::new(ReadErrorKind::UnexpectedEof, self.position)),
         }
     }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
 }
 
 pub trait Writer {
     fn write_byte(&mut self, byte: u8);
+
+    fn encoding(&self) -> Encoding {
+        Encoding::Fixed
+    }
 }
 
 impl Writer for Vec<u8> {
@@ -39,102 +90,101 @@ impl Writer for Vec<u8> {
     }
 }
 
-#[derive(Debug)]
-pub struct ReadError;
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ReadErrorKind {
+    /// Data ended in the middle of a value.
+    UnexpectedEof,
+    /// Enum variant, `Option`, `bool` or format version that doesn't exist.
+    InvalidTag,
+    /// Collection is longer than `MAX_LENGTH`.
+    LengthTooLarge,
+    /// Data continues after the value.
+    TrailingBytes,
+    /// Anything else that a value can't be, like a number out of range,
+    /// invalid UTF-8 or unordered keys.
+    InvalidValue,
+}
+
+impl fmt::Display for ReadErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ReadErrorKind::UnexpectedEof => "unexpected end of data",
+            ReadErrorKind::InvalidTag => "invalid tag",
+            ReadErrorKind::LengthTooLarge => "length too large",
+            ReadErrorKind::TrailingBytes => "trailing bytes",
+            ReadErrorKind::InvalidValue => "invalid value",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Why reading failed, and the offset of the byte where it did.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct ReadError {
+    kind: ReadErrorKind,
+    offset: usize,
+}
+
+impl ReadError {
+    pub fn new(kind: ReadErrorKind, offset: usize) -> Self {
+        ReadError { kind, offset }
+    }
+
+    pub fn kind(&self) -> ReadErrorKind {
+        self.kind
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)
+    }
+}
+
+impl Error for ReadError {}
 
 pub trait Serialize: Sized {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError>;
     fn write<W: Writer>(&self, writer: &mut W);
 }
 
-#[repr(u8)]
-#[allow(dead_code)]
-#[derive(PartialEq, Eq, Debug, Copy, Clone)]
-pub enum Key {
-    A = 0,
-    B = 1,
-    C = 2,
-    D = 3,
-    E = 4,
-    F = 5,
-    G = 6,
-    H = 7,
-    I = 8,
-    J = 9,
-    K = 10,
-    L = 11,
-    M = 12,
-    N = 13,
-    O = 14,
-    P = 15,
-    Q = 16,
-    R = 17,
-    S = 18,
-    T = 19,
-    U = 20,
-    V = 21,
-    W = 22,
-    X = 23,
-    Y = 24,
-    Z = 25,
-    Up = 32,
-    Down = 33,
-    Left = 34,
-    Right = 35,
-    Num0 = 36,
-    Num1 = 37,
-    Num2 = 38,
-    Num3 = 39,
-    Num4 = 40,
-    Num5 = 41,
-    Num6 = 42,
-    Num7 = 43,
-    Num8 = 44,
-    Num9 = 45,
-}
-
-#[derive(Debug, Copy, Clone)]
-pub struct KeyboardState {
-    old_keys: u64,
-    keys: u64,
-}
-
-impl KeyboardState {
-    pub fn new(letters: u32, old_letters: u32, other: u32, old_other: u32) -> Self {
-        let keys = (u64::from(other) << 32) + u64::from(letters);
-        let old_keys = (u64::from(old_other) << 32) + u64::from(old_letters);
-        KeyboardState { keys, old_keys }
-    }
-
-    #[allow(dead_code)]
-    pub fn is_pressed(&self, key: Key) -> bool {
-        let key = key as u8;
-        (self.keys >> key) & 1 != 0
-    }
-
-    #[allow(dead_code)]
-    pub fn was_pressed(&self, key: Key) -> bool {
-        let key = key as u8;
-        (self.old_keys >> key) & 1 != 0
-    }
-
-    #[allow(dead_code)]
-    pub fn is_just_pressed(&self, key: Key) -> bool {
-        self.is_pressed(key) && !self.was_pressed(key)
+/// Value written with given encoding, the way host sees worlds and inputs.
+pub fn to_bytes<T: Serialize>(value: &T, encoding: Encoding) -> Vec<u8> {
+    let mut writer = WithEncoding::new(Vec::new(), encoding);
+    value.write(&mut writer);
+    writer.into_inner()
+}
+
+/// Fails if `bytes` has anything after the value.
+pub fn from_bytes<T: Serialize>(bytes: &[u8], encoding: Encoding) -> Result<T, ReadError> {
+    let mut reader = SliceReader::with_encoding(bytes, encoding);
+    let value = T::read(&mut reader)?;
+    if !reader.remaining().is_empty() {
+        return Err(ReadError::new(ReadErrorKind::TrailingBytes, reader.position()));
     }
+    Ok(value)
 }
 
 pub trait Game {
     type World: Serialize;
     type Input: Serialize;
 
+    /// Encoding of worlds that are sent to clients.
+    const WORLD_ENCODING: Encoding = Encoding::Fixed;
+    /// Encoding of inputs that clients send.
+    const INPUT_ENCODING: Encoding = Encoding::Fixed;
+
     fn initial_world() -> Self::World;
     fn update_world(world: &Self::World) -> Self::World;
     fn update_player(world: &Self::World, player: PlayerId, input: &Self::Input) -> Self::World;
     fn add_player(world: &Self::World, player: PlayerId) -> Self::World;
     fn remove_player(world: &Self::World, player: PlayerId) -> Self::World;
-    fn create_input(keys: KeyboardState) -> Self::Input;
-    fn render(world: &Self::World, local_player: PlayerId, width: u32, height: u32);
+    fn create_input(input: &InputState) -> Self::Input;
+    fn render(world: &Self::World, local_player: PlayerId, canvas: &mut Canvas);
 }
 
 impl Serialize for u8 {
@@ -149,12 +199,18 @@ impl Serialize for u8 {
 
 impl Serialize for u16 {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        if reader.encoding() == Encoding::Compact {
+            return encoding::read_varint(reader, 16).map(|x| x as u16);
+        }
         let low = u16::from(reader.read_byte()?);
         let high = u16::from(reader.read_byte()?);
         Ok(low + high * 256)
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
+        if writer.encoding() == Encoding::Compact {
+            return encoding::write_varint(writer, u64::from(*self));
+        }
         writer.write_byte(*self as u8);
         writer.write_byte((*self / 256) as u8);
     }
@@ -162,12 +218,18 @@ impl Serialize for u16 {
 
 impl Serialize for u32 {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        if reader.encoding() == Encoding::Compact {
+            return encoding::read_varint(reader, 32).map(|x| x as u32);
+        }
         let low = u32::from(u16::read(reader)?);
         let high = u32::from(u16::read(reader)?);
         Ok(low + (high << 16))
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
+        if writer.encoding() == Encoding::Compact {
+            return encoding::write_varint(writer, u64::from(*self));
+        }
         (*self as u16).write(writer);
         ((*self >> 16) as u16).write(writer);
     }
@@ -175,12 +237,18 @@ impl Serialize for u32 {
 
 impl Serialize for u64 {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        if reader.encoding() == Encoding::Compact {
+            return encoding::read_varint(reader, 64);
+        }
         let low = u64::from(u32::read(reader)?);
         let high = u64::from(u32::read(reader)?);
         Ok(low + (high << 32))
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
+        if writer.encoding() == Encoding::Compact {
+            return encoding::write_varint(writer, *self);
+        }
         (*self as u32).write(writer);
         ((*self >> 32) as u32).write(writer);
     }
@@ -196,40 +264,96 @@ impl Serialize for i8 {
     }
 }
 
+/// Compact signed integers are zigzag-encoded before they are written as
+/// varints, so that small negative numbers are small too.
+fn read_zigzag<R: Reader>(reader: &mut R, bits: u32) -> Result<i64, ReadError> {
+    encoding::read_varint(reader, bits).map(encoding::zigzag_decode)
+}
+
 impl Serialize for i16 {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        if reader.encoding() == Encoding::Compact {
+            return read_zigzag(reader, 16).map(|x| x as i16);
+        }
         u16::read(reader).map(|x| x as i16)
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
+        if writer.encoding() == Encoding::Compact {
+            return encoding::write_varint(writer, encoding::zigzag_encode(i64::from(*self)));
+        }
         (*self as u16).write(writer)
     }
 }
 
 impl Serialize for i32 {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        if reader.encoding() == Encoding::Compact {
+            return read_zigzag(reader, 32).map(|x| x as i32);
+        }
         u32::read(reader).map(|x| x as i32)
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
+        if writer.encoding() == Encoding::Compact {
+            return encoding::write_varint(writer, encoding::zigzag_encode(i64::from(*self)));
+        }
         (*self as u32).write(writer)
     }
 }
 
 impl Serialize for i64 {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        if reader.encoding() == Encoding::Compact {
+            return read_zigzag(reader, 64);
+        }
         u64::read(reader).map(|x| x as i64)
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
+        if writer.encoding() == Encoding::Compact {
+            return encoding::write_varint(writer, encoding::zigzag_encode(*self));
+        }
         (*self as u64).write(writer)
     }
 }
 
+/// Collections don't reserve more than this many elements up front, because
+/// lengths come from the data and can be wrong.
+pub(crate) const MAX_PREALLOCATED: usize = 1024;
+
+/// Longest collection that can be read. Longer lengths come from corrupt or
+/// hostile data, and reading them would take too long even if it fails.
+pub const MAX_LENGTH: usize = 1 << 24;
+
+pub(crate) fn read_length<R: Reader>(reader: &mut R) -> Result<usize, ReadError> {
+    let offset = reader.position();
+    let length = u32::read(reader)? as usize;
+    if length > MAX_LENGTH {
+        return Err(ReadError::new(ReadErrorKind::LengthTooLarge, offset));
+    }
+    Ok(length)
+}
+
+impl Serialize for bool {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        let offset = reader.position();
+        match reader.read_byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ReadError::new(ReadErrorKind::InvalidTag, offset)),
+        }
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        writer.write_byte(*self as u8);
+    }
+}
+
 impl<T: Serialize> Serialize for Vec<T> {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        let size = u32::read(reader)? as usize;
-        let mut v = Vec::with_capacity(size);
+        let size = read_length(reader)?;
+        let mut v = Vec::with_capacity(size.min(MAX_PREALLOCATED));
         for _ in 0..size {
             v.push(T::read(reader)?);
         }
@@ -244,52 +368,173 @@ impl<T: Serialize> Serialize for Vec<T> {
     }
 }
 
-impl Serialize for PlayerId {
+impl Serialize for String {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        Ok(PlayerId { id: u32::read(reader)? })
+        let length = read_length(reader)?;
+        let start = reader.position();
+        let mut bytes = Vec::with_capacity(length.min(MAX_PREALLOCATED));
+        for _ in 0..length {
+            bytes.push(reader.read_byte()?);
+        }
+        String::from_utf8(bytes).map_err(|error| {
+            ReadError::new(ReadErrorKind::InvalidValue, start + error.utf8_error().valid_up_to())
+        })
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
-        self.id.write(writer);
+        (self.len() as u32).write(writer);
+        for &byte in self.as_bytes() {
+            writer.write_byte(byte);
+        }
     }
 }
 
-impl Serialize for () {
-    fn read<R: Reader>(_reader: &mut R) -> Result<Self, ReadError> {
-        Ok(())
+impl<T: Serialize> Serialize for Option<T> {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        let offset = reader.position();
+        match reader.read_byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::read(reader)?)),
+            _ => Err(ReadError::new(ReadErrorKind::InvalidTag, offset)),
+        }
     }
 
-    fn write<W: Writer>(&self, _writer: &mut W) {}
+    fn write<W: Writer>(&self, writer: &mut W) {
+        match self {
+            None => writer.write_byte(0),
+            Some(value) => {
+                writer.write_byte(1);
+                value.write(writer);
+            }
+        }
+    }
 }
 
-impl<A: Serialize, B: Serialize> Serialize for (A, B) {
+impl<T: Serialize> Serialize for Box<T> {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        let a = A::read(reader)?;
-        let b = B::read(reader)?;
-        Ok((a, b))
+        T::read(reader).map(Box::new)
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
-        self.0.write(writer);
-        self.1.write(writer);
+        (**self).write(writer)
     }
 }
 
-impl<A: Serialize, B: Serialize, C: Serialize> Serialize for (A, B, C) {
+/// Arrays have a fixed length, so it is not written.
+impl<T: Serialize, const N: usize> Serialize for [T; N] {
     fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        let a = A::read(reader)?;
-        let b = B::read(reader)?;
-        let c = C::read(reader)?;
-        Ok((a, b, c))
+        let mut items = Vec::with_capacity(N);
+        for _ in 0..N {
+            items.push(T::read(reader)?);
+        }
+        Ok(items.try_into().unwrap_or_else(|_| unreachable!()))
     }
 
     fn write<W: Writer>(&self, writer: &mut W) {
-        self.0.write(writer);
-        self.1.write(writer);
-        self.2.write(writer);
+        for item in self {
+            item.write(writer);
+        }
     }
 }
 
+/// Entries are written in order of keys, so equal maps are always written
+/// the same way. Reading fails if keys are not strictly increasing.
+impl<K: Serialize + Ord, V: Serialize> Serialize for BTreeMap<K, V> {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        let size = read_length(reader)?;
+        let mut map = BTreeMap::new();
+        for _ in 0..size {
+            let offset = reader.position();
+            let key = K::read(reader)?;
+            if map.last_key_value().is_some_and(|(last, _)| *last >= key) {
+                return Err(ReadError::new(ReadErrorKind::InvalidValue, offset));
+            }
+            let value = V::read(reader)?;
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        (self.len() as u32).write(writer);
+        for (key, value) in self {
+            key.write(writer);
+            value.write(writer);
+        }
+    }
+}
+
+/// Written like `BTreeMap`, in order and without duplicates.
+impl<T: Serialize + Ord> Serialize for BTreeSet<T> {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        let size = read_length(reader)?;
+        let mut set = BTreeSet::new();
+        for _ in 0..size {
+            let offset = reader.position();
+            let item = T::read(reader)?;
+            if set.last().is_some_and(|last| *last >= item) {
+                return Err(ReadError::new(ReadErrorKind::InvalidValue, offset));
+            }
+            set.insert(item);
+        }
+        Ok(set)
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        (self.len() as u32).write(writer);
+        for item in self {
+            item.write(writer);
+        }
+    }
+}
+
+impl Serialize for PlayerId {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        Ok(PlayerId { id: u32::read(reader)? })
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        self.id.write(writer);
+    }
+}
+
+impl Serialize for () {
+    fn read<R: Reader>(_reader: &mut R) -> Result<Self, ReadError> {
+        Ok(())
+    }
+
+    fn write<W: Writer>(&self, _writer: &mut W) {}
+}
+
+macro_rules! tuple_impls {
+    ($($name:ident)+) => {
+        impl<$($name: Serialize),+> Serialize for ($($name,)+) {
+            fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+                Ok(($($name::read(reader)?,)+))
+            }
+
+            #[allow(non_snake_case)]
+            fn write<W: Writer>(&self, writer: &mut W) {
+                let ($($name,)+) = self;
+                $($name.write(writer);)+
+            }
+        }
+    };
+}
+
+tuple_impls! { A }
+tuple_impls! { A B }
+tuple_impls! { A B C }
+tuple_impls! { A B C D }
+tuple_impls! { A B C D E }
+tuple_impls! { A B C D E F }
+tuple_impls! { A B C D E F G }
+tuple_impls! { A B C D E F G H }
+tuple_impls! { A B C D E F G H I }
+tuple_impls! { A B C D E F G H I J }
+tuple_impls! { A B C D E F G H I J K }
+tuple_impls! { A B C D E F G H I J K L }
+
 #[allow(dead_code)]
 pub struct Empty;
 
@@ -297,11 +542,172 @@ impl Game for Empty {
     type World = ();
     type Input = ();
 
-    fn initial_world() -> Self::World { () }
-    fn update_world(_: &Self::World) -> Self::World { () }
-    fn update_player(_: &Self::World, _: PlayerId, _: &Self::Input) -> Self::World { () }
-    fn add_player(_: &Self::World, _: PlayerId) -> Self::World { () }
-    fn remove_player(_: &Self::World, _: PlayerId) -> Self::World { () }
-    fn create_input(_: KeyboardState) -> Self::Input { () }
-    fn render(_: &Self::World, _: PlayerId, _: u32, _: u32) {}
-}
\ No newline at end of file
+    fn initial_world() -> Self::World {}
+    fn update_world(_: &Self::World) -> Self::World {}
+    fn update_player(_: &Self::World, _: PlayerId, _: &Self::Input) -> Self::World {}
+    fn add_player(_: &Self::World, _: PlayerId) -> Self::World {}
+    fn remove_player(_: &Self::World, _: PlayerId) -> Self::World {}
+    fn create_input(_: &InputState) -> Self::Input {}
+    fn render(_: &Self::World, _: PlayerId, _: &mut Canvas) {}
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Serialize>(value: &T) -> (T, Vec<u8>) {
+        let mut buffer = Vec::new();
+        value.write(&mut buffer);
+        let read = from_bytes(&buffer, Encoding::Fixed).expect("failed to read written value");
+        (read, buffer)
+    }
+
+    #[derive(Serialize, PartialEq, Debug)]
+    struct Named<T> {
+        id: PlayerId,
+        items: Vec<T>,
+        unit: Unit,
+    }
+
+    #[derive(Serialize, PartialEq, Debug)]
+    struct Tuple(u8, i16);
+
+    #[derive(Serialize, PartialEq, Debug)]
+    struct Unit;
+
+    // field names are the same as parameters of `Serialize` methods
+    #[derive(Serialize, PartialEq, Debug)]
+    struct Parameters {
+        reader: u8,
+        writer: u16,
+    }
+
+    #[derive(Serialize, PartialEq, Debug, Copy, Clone)]
+    enum Direction {
+        Up = 1,
+        Down = 2,
+        Left = 10,
+    }
+
+    #[derive(Serialize, PartialEq, Debug)]
+    enum Shape {
+        #[serialize(tag = 7)]
+        Circle { radius: u32 },
+        #[serialize(tag = 0)]
+        Line(Tuple, Direction),
+        #[serialize(tag = 255)]
+        Empty,
+    }
+
+    #[test]
+    fn derived_structs() {
+        let value = Named { id: PlayerId::new(3), items: vec![Tuple(1, -1), Tuple(2, 256)], unit: Unit };
+        let (read, bytes) = round_trip(&value);
+        assert_eq!(read, value);
+        assert_eq!(bytes, [3, 0, 0, 0, 2, 0, 0, 0, 1, 0xff, 0xff, 2, 0, 1]);
+        assert_eq!(round_trip(&Unit).1, []);
+        let value = Parameters { reader: 1, writer: 2 };
+        assert_eq!(round_trip(&value), (value, vec![1, 2, 0]));
+    }
+
+    #[test]
+    fn derived_enums() {
+        assert_eq!(round_trip(&Direction::Left), (Direction::Left, vec![10]));
+        let shapes = vec![
+            Shape::Circle { radius: 5 },
+            Shape::Line(Tuple(4, 5), Direction::Down),
+            Shape::Empty,
+        ];
+        let (read, bytes) = round_trip(&shapes);
+        assert_eq!(read, shapes);
+        assert_eq!(bytes, [3, 0, 0, 0, 7, 5, 0, 0, 0, 0, 4, 5, 0, 2, 255]);
+    }
+
+    #[test]
+    fn bool_and_option() {
+        assert_eq!(round_trip(&true), (true, vec![1]));
+        assert_eq!(round_trip(&false), (false, vec![0]));
+        assert!(bool::read(&mut SliceReader::new(&[2])).is_err());
+        assert_eq!(round_trip(&Some(5u8)), (Some(5), vec![1, 5]));
+        assert_eq!(round_trip(&None::<u8>), (None, vec![0]));
+        assert!(Option::<u8>::read(&mut SliceReader::new(&[2, 5])).is_err());
+        assert!(Option::<u8>::read(&mut SliceReader::new(&[1])).is_err());
+    }
+
+    #[test]
+    fn strings() {
+        let (read, bytes) = round_trip(&"héllo".to_string());
+        assert_eq!(read, "héllo");
+        assert_eq!(bytes, [6, 0, 0, 0, b'h', 0xc3, 0xa9, b'l', b'l', b'o']);
+        assert!(String::read(&mut SliceReader::new(&[1, 0, 0, 0, 0xff])).is_err(), "invalid UTF-8 was accepted");
+        assert!(String::read(&mut SliceReader::new(&[2, 0, 0, 0, b'h'])).is_err());
+    }
+
+    #[test]
+    fn lengths_are_not_trusted() {
+        // reading must fail when data ends, without reserving 4 billion elements first
+        assert!(Vec::<u64>::read(&mut SliceReader::new(&[0xff, 0xff, 0xff, 0xff, 1])).is_err());
+        assert!(String::read(&mut SliceReader::new(&[0xff, 0xff, 0xff, 0xff])).is_err());
+        assert!(BTreeMap::<u8, u8>::read(&mut SliceReader::new(&[0xff, 0xff, 0xff, 0xff])).is_err());
+    }
+
+    fn read_error<T: Serialize>(bytes: &[u8]) -> (ReadErrorKind, usize) {
+        let error = from_bytes::<T>(bytes, Encoding::Fixed).err().expect("invalid data was read");
+        (error.kind(), error.offset())
+    }
+
+    #[test]
+    fn read_errors() {
+        assert_eq!(read_error::<(u8, u32)>(&[1, 2, 0]), (ReadErrorKind::UnexpectedEof, 3));
+        assert_eq!(read_error::<(u8, bool)>(&[1, 2]), (ReadErrorKind::InvalidTag, 1));
+        assert_eq!(read_error::<u8>(&[1, 2]), (ReadErrorKind::TrailingBytes, 1));
+        let too_long = (MAX_LENGTH as u32 + 1).to_le_bytes();
+        assert_eq!(read_error::<Vec<u8>>(&too_long), (ReadErrorKind::LengthTooLarge, 0));
+        assert_eq!(read_error::<String>(&[2, 0, 0, 0, b'h', 0xff]), (ReadErrorKind::InvalidValue, 5));
+        assert_eq!(read_error::<(u8, Shape)>(&[0, 9]), (ReadErrorKind::InvalidTag, 1));
+        assert_eq!(ReadError::new(ReadErrorKind::InvalidTag, 7).to_string(), "invalid tag at byte 7");
+    }
+
+    #[test]
+    fn arrays_and_boxes() {
+        let (read, bytes) = round_trip(&[1u16, 2, 3]);
+        assert_eq!(read, [1, 2, 3]);
+        assert_eq!(bytes, [1, 0, 2, 0, 3, 0]);
+        assert_eq!(round_trip(&[0u8; 0]).1, []);
+        assert!(<[u8; 3]>::read(&mut SliceReader::new(&[1, 2])).is_err());
+        assert_eq!(*round_trip(&Box::new(-1i8)).0, -1);
+    }
+
+    #[test]
+    fn ordered_collections() {
+        let map: BTreeMap<u8, String> = vec![(3, "c".to_string()), (1, "a".to_string())].into_iter().collect();
+        let (read, bytes) = round_trip(&map);
+        assert_eq!(read, map);
+        assert_eq!(bytes, [2, 0, 0, 0, 1, 1, 0, 0, 0, b'a', 3, 1, 0, 0, 0, b'c']);
+        let set: BTreeSet<i16> = vec![5, -1, 2].into_iter().collect();
+        let (read, bytes) = round_trip(&set);
+        assert_eq!(read, set);
+        assert_eq!(bytes, [3, 0, 0, 0, 0xff, 0xff, 2, 0, 5, 0]);
+        // keys out of order and duplicates would make equal values serialize differently
+        assert!(BTreeMap::<u8, u8>::read(&mut SliceReader::new(&[2, 0, 0, 0, 3, 0, 1, 0])).is_err());
+        assert!(BTreeMap::<u8, u8>::read(&mut SliceReader::new(&[2, 0, 0, 0, 1, 0, 1, 0])).is_err());
+        assert!(BTreeSet::<u8>::read(&mut SliceReader::new(&[2, 0, 0, 0, 4, 4])).is_err());
+    }
+
+    #[test]
+    fn tuples() {
+        let value = (1u8, 2u16, 3u32, 4u64, -5i8, -6i16, -7i32, -8i64, true, Some(10u8), (), "12".to_string());
+        let (read, bytes) = round_trip(&value);
+        assert_eq!(read, value);
+        assert_eq!(bytes.len(), 1 + 2 + 4 + 8 + 1 + 2 + 4 + 8 + 1 + 2 + 6);
+        assert_eq!(round_trip(&(7u8,)), ((7,), vec![7]));
+        assert!(<(u8, u8)>::read(&mut SliceReader::new(&[1])).is_err());
+    }
+
+    #[test]
+    fn unknown_tags() {
+        assert!(Direction::read(&mut SliceReader::new(&[0])).is_err());
+        assert!(Direction::read(&mut SliceReader::new(&[3])).is_err());
+        assert!(Shape::read(&mut SliceReader::new(&[1])).is_err());
+        assert!(Shape::read(&mut SliceReader::new(&[7, 0])).is_err(), "read truncated variant");
+    }
+}
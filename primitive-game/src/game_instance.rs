@@ -1,4 +1,6 @@
-use crate::game::{Game, PlayerId, Serialize, KeyboardState};
+use crate::canvas::Canvas;
+use crate::encoding::Encoding;
+use crate::game::{self, Game, InputState, PlayerId};
 use crate::Handle;
 
 enum Object<G: Game> {
@@ -60,7 +62,6 @@ impl<G: Game> GameInstance<G> {
     }
 
     fn create_object(&mut self, object: Object<G>) -> Handle {
-        let object = Object::from(object);
         for (index, slot) in self.objects.iter_mut().enumerate() {
             if slot.is_none() {
                 *slot = Some(object);
@@ -131,47 +132,36 @@ impl<G: Game> GameInstance<G> {
     }
 
     pub fn deserialize_world(&mut self, buffer: Handle) -> Handle {
-        let world = {
-            let mut buffer = &self.buffer_mut(buffer)[..];
-            G::World::read(&mut buffer).expect("failed to deserialize world")
-        };
+        let world = game::from_bytes(self.buffer_mut(buffer), G::WORLD_ENCODING).unwrap_or_else(|error| panic!("failed to deserialize world: {}", error));
         self.create_world(world)
     }
 
     pub fn deserialize_input(&mut self, buffer: Handle) -> Handle {
-        let input = {
-            let mut buffer = &self.buffer_mut(buffer)[..];
-            G::Input::read(&mut buffer).expect("failed to deserialize input")
-        };
+        let input = game::from_bytes(self.buffer_mut(buffer), G::INPUT_ENCODING).unwrap_or_else(|error| panic!("failed to deserialize input: {}", error));
         self.create_object(Object::Input(input))
     }
 
     pub fn serialize_world(&mut self, world: Handle) -> Handle {
-        let mut buf = Vec::new();
-        {
-            let world = self.object(world).as_world();
-            world.write(&mut buf);
-        }
+        let buf = game::to_bytes(self.object(world).as_world(), G::WORLD_ENCODING);
         self.create_object(Object::Buffer(buf))
     }
 
     pub fn serialize_input(&mut self, input: Handle) -> Handle {
-        let mut buf = Vec::new();
-        {
-            let input = self.object(input).as_input();
-            input.write(&mut buf);
-        }
+        let buf = game::to_bytes(self.object(input).as_input(), G::INPUT_ENCODING);
         self.create_object(Object::Buffer(buf))
     }
 
-    pub fn create_input(&mut self, letters: u32, old_letters: u32, other: u32, old_other: u32) -> Handle {
-        let keyboard_state = KeyboardState::new(letters, old_letters, other, old_other);
-        let input = G::create_input(keyboard_state);
+    pub fn create_input(&mut self, input_state: Handle) -> Handle {
+        let input = {
+            let input_state: InputState = game::from_bytes(self.buffer_mut(input_state), Encoding::Fixed)
+                .unwrap_or_else(|error| panic!("failed to read input state: {}", error));
+            G::create_input(&input_state)
+        };
         self.create_object(Object::Input(input))
     }
 
-    pub fn render(&mut self, world: Handle, local_player: u32, width: u32, height: u32) {
+    pub fn render(&mut self, world: Handle, local_player: u32, canvas: &mut Canvas) {
         let world = self.object(world).as_world();
-        G::render(world, PlayerId::new(local_player), width, height);
+        G::render(world, PlayerId::new(local_player), canvas);
     }
 }
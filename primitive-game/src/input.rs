@@ -0,0 +1,437 @@
+//! State of player's input devices that `Game::create_input` turns into game
+//! input. Host passes it to the `create_input` export as a buffer:
+//!
+//! ```text
+//! version       u8       INPUT_STATE_VERSION
+//! key_bytes     u8       length of both key sets
+//! keys          [u8]     bit `n % 8` of byte `n / 8` is set if key `n` is held
+//! old_keys      [u8]     the same for the previous input
+//! mouse_x       i32      pointer position in canvas pixels
+//! mouse_y       i32
+//! buttons       u8       bit `n` is set if `MouseButton` `n` is held
+//! old_buttons   u8       the same for the previous input
+//! wheel_x       i32      scrolled distance since the previous input, in pixels
+//! wheel_y       i32
+//! text          u32, [u8]  UTF-8 text typed since the previous input
+//! ```
+//!
+//! Numbers are little-endian, like everything that `Serialize` writes. Hosts
+//! may send more or fewer key bytes than the game knows about, missing keys
+//! are not held.
+
+use crate::game::{Reader, ReadError, ReadErrorKind, Serialize, Writer};
+
+pub const INPUT_STATE_VERSION: u8 = 1;
+
+/// Key numbers are below this.
+pub const KEY_COUNT: u32 = 128;
+
+/// Number of key bytes that this version of the crate writes.
+const KEY_BYTES: u8 = (KEY_COUNT / 8) as u8;
+
+/// Keys of a standard keyboard, identified by their position rather than by
+/// the character they type. Numbers are part of the input ABI: they never
+/// change, and new keys get new numbers below `KEY_COUNT`. Numbers 26-31 are
+/// unused, because letters and arrows used to be separate bit masks.
+#[repr(u8)]
+#[allow(dead_code)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Key {
+    // Letters.
+    A = 0,
+    B = 1,
+    C = 2,
+    D = 3,
+    E = 4,
+    F = 5,
+    G = 6,
+    H = 7,
+    I = 8,
+    J = 9,
+    K = 10,
+    L = 11,
+    M = 12,
+    N = 13,
+    O = 14,
+    P = 15,
+    Q = 16,
+    R = 17,
+    S = 18,
+    T = 19,
+    U = 20,
+    V = 21,
+    W = 22,
+    X = 23,
+    Y = 24,
+    Z = 25,
+
+    // Arrows.
+    Up = 32,
+    Down = 33,
+    Left = 34,
+    Right = 35,
+
+    // Digits of the main keyboard.
+    Num0 = 36,
+    Num1 = 37,
+    Num2 = 38,
+    Num3 = 39,
+    Num4 = 40,
+    Num5 = 41,
+    Num6 = 42,
+    Num7 = 43,
+    Num8 = 44,
+    Num9 = 45,
+
+    // Editing and navigation.
+    Space = 46,
+    Enter = 47,
+    Escape = 48,
+    Tab = 49,
+    Backspace = 50,
+    Delete = 51,
+    Insert = 52,
+    Home = 53,
+    End = 54,
+    PageUp = 55,
+    PageDown = 56,
+
+    // Modifiers.
+    LeftShift = 57,
+    RightShift = 58,
+    LeftCtrl = 59,
+    RightCtrl = 60,
+    LeftAlt = 61,
+    RightAlt = 62,
+    LeftMeta = 63,
+    RightMeta = 64,
+    CapsLock = 65,
+
+    // Function keys.
+    F1 = 66,
+    F2 = 67,
+    F3 = 68,
+    F4 = 69,
+    F5 = 70,
+    F6 = 71,
+    F7 = 72,
+    F8 = 73,
+    F9 = 74,
+    F10 = 75,
+    F11 = 76,
+    F12 = 77,
+
+    // Punctuation of a US layout, named by the unshifted character.
+    Minus = 78,
+    Equal = 79,
+    LeftBracket = 80,
+    RightBracket = 81,
+    Backslash = 82,
+    Semicolon = 83,
+    Quote = 84,
+    Backquote = 85,
+    Comma = 86,
+    Period = 87,
+    Slash = 88,
+
+    // Numeric keypad.
+    Numpad0 = 89,
+    Numpad1 = 90,
+    Numpad2 = 91,
+    Numpad3 = 92,
+    Numpad4 = 93,
+    Numpad5 = 94,
+    Numpad6 = 95,
+    Numpad7 = 96,
+    Numpad8 = 97,
+    Numpad9 = 98,
+    NumpadAdd = 99,
+    NumpadSubtract = 100,
+    NumpadMultiply = 101,
+    NumpadDivide = 102,
+    NumpadDecimal = 103,
+    NumpadEnter = 104,
+
+    // Rest of a standard keyboard.
+    NumLock = 105,
+    ScrollLock = 106,
+    PrintScreen = 107,
+    Pause = 108,
+    ContextMenu = 109,
+}
+
+impl Key {
+    /// Every key, ordered by number.
+    pub const ALL: [Key; 104] = [
+        Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K,
+        Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V,
+        Key::W, Key::X, Key::Y, Key::Z, Key::Up, Key::Down, Key::Left, Key::Right, Key::Num0,
+        Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7, Key::Num8,
+        Key::Num9, Key::Space, Key::Enter, Key::Escape, Key::Tab, Key::Backspace, Key::Delete,
+        Key::Insert, Key::Home, Key::End, Key::PageUp, Key::PageDown, Key::LeftShift,
+        Key::RightShift, Key::LeftCtrl, Key::RightCtrl, Key::LeftAlt, Key::RightAlt,
+        Key::LeftMeta, Key::RightMeta, Key::CapsLock, Key::F1, Key::F2, Key::F3, Key::F4,
+        Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12, Key::Minus,
+        Key::Equal, Key::LeftBracket, Key::RightBracket, Key::Backslash, Key::Semicolon,
+        Key::Quote, Key::Backquote, Key::Comma, Key::Period, Key::Slash, Key::Numpad0,
+        Key::Numpad1, Key::Numpad2, Key::Numpad3, Key::Numpad4, Key::Numpad5, Key::Numpad6,
+        Key::Numpad7, Key::Numpad8, Key::Numpad9, Key::NumpadAdd, Key::NumpadSubtract,
+        Key::NumpadMultiply, Key::NumpadDivide, Key::NumpadDecimal, Key::NumpadEnter,
+        Key::NumLock, Key::ScrollLock, Key::PrintScreen, Key::Pause, Key::ContextMenu,
+    ];
+}
+
+/// Bit `n` of each set is key number `n`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KeyboardState {
+    old_keys: u128,
+    keys: u128,
+}
+
+impl KeyboardState {
+    #[allow(dead_code)]
+    pub fn is_pressed(&self, key: Key) -> bool {
+        (self.keys >> key as u8) & 1 != 0
+    }
+
+    #[allow(dead_code)]
+    pub fn was_pressed(&self, key: Key) -> bool {
+        (self.old_keys >> key as u8) & 1 != 0
+    }
+
+    #[allow(dead_code)]
+    pub fn is_just_pressed(&self, key: Key) -> bool {
+        self.is_pressed(key) && !self.was_pressed(key)
+    }
+}
+
+/// Numbering matches `MouseEvent.button` in browsers.
+#[repr(u8)]
+#[allow(dead_code)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum MouseButton {
+    Left = 0,
+    Middle = 1,
+    Right = 2,
+    Back = 3,
+    Forward = 4,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MouseState {
+    x: i32,
+    y: i32,
+    buttons: u8,
+    old_buttons: u8,
+    wheel_x: i32,
+    wheel_y: i32,
+}
+
+impl MouseState {
+    /// Last known position of the pointer, in canvas pixels. It can be
+    /// outside of the canvas.
+    pub fn position(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    pub fn is_pressed(&self, button: MouseButton) -> bool {
+        (self.buttons >> button as u8) & 1 != 0
+    }
+
+    pub fn was_pressed(&self, button: MouseButton) -> bool {
+        (self.old_buttons >> button as u8) & 1 != 0
+    }
+
+    pub fn is_just_pressed(&self, button: MouseButton) -> bool {
+        self.is_pressed(button) && !self.was_pressed(button)
+    }
+
+    pub fn is_just_released(&self, button: MouseButton) -> bool {
+        !self.is_pressed(button) && self.was_pressed(button)
+    }
+
+    /// Horizontal and vertical distance scrolled since the previous input.
+    pub fn wheel(&self) -> (i32, i32) {
+        (self.wheel_x, self.wheel_y)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InputState {
+    keyboard: KeyboardState,
+    mouse: MouseState,
+    text: String,
+}
+
+impl InputState {
+    pub fn keyboard(&self) -> &KeyboardState {
+        &self.keyboard
+    }
+
+    pub fn mouse(&self) -> &MouseState {
+        &self.mouse
+    }
+
+    /// Text typed since the previous input, for text fields and chat.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+fn read_keys<R: Reader>(reader: &mut R, bytes: u8) -> Result<u128, ReadError> {
+    let mut keys = 0;
+    for index in 0..bytes {
+        let byte = u128::from(reader.read_byte()?);
+        if index < KEY_BYTES {
+            keys |= byte << (8 * index);
+        }
+    }
+    Ok(keys)
+}
+
+fn write_keys<W: Writer>(writer: &mut W, keys: u128) {
+    (keys as u64).write(writer);
+    ((keys >> 64) as u64).write(writer);
+}
+
+impl Serialize for InputState {
+    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
+        let offset = reader.position();
+        if reader.read_byte()? != INPUT_STATE_VERSION {
+            return Err(ReadError::new(ReadErrorKind::InvalidTag, offset));
+        }
+        let key_bytes = reader.read_byte()?;
+        let keyboard = KeyboardState {
+            keys: read_keys(reader, key_bytes)?,
+            old_keys: read_keys(reader, key_bytes)?,
+        };
+        let (x, y) = <(i32, i32)>::read(reader)?;
+        let (buttons, old_buttons) = <(u8, u8)>::read(reader)?;
+        let (wheel_x, wheel_y) = <(i32, i32)>::read(reader)?;
+        let mouse = MouseState { x, y, buttons, old_buttons, wheel_x, wheel_y };
+        let text = String::read(reader)?;
+        Ok(InputState { keyboard, mouse, text })
+    }
+
+    fn write<W: Writer>(&self, writer: &mut W) {
+        INPUT_STATE_VERSION.write(writer);
+        KEY_BYTES.write(writer);
+        write_keys(writer, self.keyboard.keys);
+        write_keys(writer, self.keyboard.old_keys);
+        (self.mouse.x, self.mouse.y).write(writer);
+        (self.mouse.buttons, self.mouse.old_buttons).write(writer);
+        (self.mouse.wheel_x, self.mouse.wheel_y).write(writer);
+        self.text.write(writer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::SliceReader;
+
+    #[test]
+    fn read_input_state() {
+        let mut buffer = vec![INPUT_STATE_VERSION, 2, 0b0000_0001, 0b0000_0010, 0, 0b0000_0010];
+        buffer.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff, 7, 0, 0, 0]);
+        buffer.extend_from_slice(&[0b101, 0b001, 0, 0, 0, 0, 0xf6, 0xff, 0xff, 0xff]);
+        buffer.extend_from_slice(&[2, 0, 0, 0, b'h', b'i']);
+        let state = InputState::read(&mut SliceReader::new(&buffer)).unwrap();
+        let keys = state.keyboard();
+        assert!(keys.is_just_pressed(Key::A));
+        assert!(keys.is_pressed(Key::J) && !keys.is_just_pressed(Key::J));
+        assert!(!keys.is_pressed(Key::B));
+        let mouse = state.mouse();
+        assert_eq!(mouse.position(), (-2, 7));
+        assert!(mouse.is_pressed(MouseButton::Left) && !mouse.is_just_pressed(MouseButton::Left));
+        assert!(mouse.is_just_pressed(MouseButton::Right));
+        assert!(!mouse.is_pressed(MouseButton::Middle));
+        assert_eq!(mouse.wheel(), (0, -10));
+        assert_eq!(state.text(), "hi");
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut state = InputState::default();
+        state.keyboard.keys = 1 << Key::Num9 as u8 | 1 << Key::ContextMenu as u8;
+        state.mouse.buttons = 1 << MouseButton::Forward as u8;
+        state.text = "ü".to_string();
+        let mut buffer = Vec::new();
+        state.write(&mut buffer);
+        let read = InputState::read(&mut SliceReader::new(&buffer)).unwrap();
+        assert!(read.keyboard().is_just_pressed(Key::Num9));
+        assert!(read.keyboard().is_just_pressed(Key::ContextMenu));
+        assert!(read.mouse().is_just_pressed(MouseButton::Forward));
+        assert_eq!(read.text(), "ü");
+    }
+
+    #[test]
+    fn key_numbers_are_stable() {
+        assert_eq!(Key::A as u8, 0);
+        assert_eq!(Key::Up as u8, 32);
+        assert_eq!(Key::Num9 as u8, 45);
+        assert_eq!(Key::Space as u8, 46);
+        assert_eq!(Key::LeftShift as u8, 57);
+        assert_eq!(Key::F1 as u8, 66);
+        assert_eq!(Key::Numpad0 as u8, 89);
+        assert_eq!(Key::ContextMenu as u8, 109);
+        for pair in Key::ALL.windows(2) {
+            assert!((pair[0] as u8) < (pair[1] as u8), "{:?} and {:?} are out of order", pair[0], pair[1]);
+        }
+        assert!(u32::from(*Key::ALL.last().unwrap() as u8) < KEY_COUNT);
+    }
+
+    /// Every key is read from its own bit of the buffer, including keys in
+    /// the last bytes.
+    #[test]
+    fn key_bits() {
+        for &key in Key::ALL.iter() {
+            let number = key as usize;
+            let mut keys = [0u8; KEY_BYTES as usize];
+            keys[number / 8] = 1 << (number % 8);
+            let mut buffer = vec![INPUT_STATE_VERSION, KEY_BYTES];
+            buffer.extend_from_slice(&keys);
+            buffer.extend_from_slice(&keys);
+            buffer.extend_from_slice(&[0; 22]);
+            let state = InputState::read(&mut SliceReader::new(&buffer)).unwrap();
+            for &other in Key::ALL.iter() {
+                assert_eq!(state.keyboard().is_pressed(other), other == key, "{:?} and {:?}", key, other);
+                assert_eq!(state.keyboard().was_pressed(other), other == key, "{:?} and {:?}", key, other);
+            }
+            assert!(!state.keyboard().is_just_pressed(key));
+            let mut written = Vec::new();
+            state.write(&mut written);
+            assert_eq!(written, buffer, "{:?}", key);
+        }
+    }
+
+    /// Hosts built for fewer keys send fewer bytes, and keys that the game
+    /// doesn't know about are ignored.
+    #[test]
+    fn key_byte_count_can_differ() {
+        let mut buffer = vec![INPUT_STATE_VERSION, 1, 0b10, 0];
+        buffer.extend_from_slice(&[0; 22]);
+        let state = InputState::read(&mut SliceReader::new(&buffer)).unwrap();
+        assert!(state.keyboard().is_just_pressed(Key::B));
+        assert!(!state.keyboard().is_pressed(Key::ContextMenu));
+        let mut buffer = vec![INPUT_STATE_VERSION, KEY_BYTES + 2];
+        buffer.extend_from_slice(&[0xff; 2 * (KEY_BYTES as usize + 2)]);
+        buffer.extend_from_slice(&[0; 22]);
+        let state = InputState::read(&mut SliceReader::new(&buffer)).unwrap();
+        assert!(Key::ALL.iter().all(|&key| state.keyboard().is_pressed(key)));
+        assert_eq!(state.mouse().position(), (0, 0));
+    }
+
+    #[test]
+    fn invalid_input_state() {
+        assert!(InputState::read(&mut SliceReader::new(&[])).is_err());
+        assert!(InputState::read(&mut SliceReader::new(&[INPUT_STATE_VERSION + 1])).is_err());
+        let mut buffer = Vec::new();
+        InputState::default().write(&mut buffer);
+        assert!(InputState::read(&mut SliceReader::new(&buffer[..buffer.len() - 1])).is_err());
+        let len = buffer.len();
+        buffer[len - 4] = 1;
+        buffer.push(0xff);
+        assert!(InputState::read(&mut SliceReader::new(&buffer)).is_err(), "invalid UTF-8 was accepted");
+    }
+}
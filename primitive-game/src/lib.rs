@@ -1,10 +1,21 @@
 #![warn(rust_2018_idioms)]
 
-mod game;
+// Lets `#[derive(Serialize)]` refer to `::primitive_game` inside this crate too.
+extern crate self as primitive_game;
+
+pub mod canvas;
+pub mod encoding;
+pub mod fixed;
+pub mod game;
 mod game_instance;
-mod squares;
+pub mod input;
+#[macro_use]
+pub mod log;
+pub mod random;
+pub mod squares;
 
 use std::sync::{Mutex, MutexGuard};
+use crate::canvas::{Canvas, Color};
 use crate::game_instance::GameInstance;
 
 type GameImpl = crate::squares::Squares;
@@ -12,114 +23,133 @@ static mut GAME: Option<Mutex<GameInstance<GameImpl>>> = None;
 
 fn get_game() -> MutexGuard<'static, GameInstance<GameImpl>> {
     unsafe {
-        GAME.as_ref().expect("game not initialized").lock().unwrap()
+        (*std::ptr::addr_of!(GAME)).as_ref().expect("game not initialized").lock().unwrap()
     }
 }
 
 #[repr(transparent)]
 pub struct Handle(pub u32);
 
-#[no_mangle]
-pub extern fn initialize() {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn initialize() {
     unsafe {
         GAME = Some(Mutex::new(GameInstance::new()));
     }
     std::panic::set_hook(Box::new(|info| {
-        log(&info.to_string());
+        error!("{}", info);
     }));
 }
 
-#[no_mangle]
-pub extern fn initial_world() -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn initial_world() -> Handle {
     get_game().initial_world()
 }
 
-#[no_mangle]
-pub extern fn update_world(world: Handle) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn update_world(world: Handle) -> Handle {
     get_game().update_world(world)
 }
 
-#[no_mangle]
-pub extern fn update_player(world: Handle, player_id: u32, input: Handle) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn update_player(world: Handle, player_id: u32, input: Handle) -> Handle {
     get_game().update_player(world, player_id, input)
 }
 
-#[no_mangle]
-pub extern fn add_player(world: Handle, player_id: u32) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn add_player(world: Handle, player_id: u32) -> Handle {
     get_game().add_player(world, player_id)
 }
 
-#[no_mangle]
-pub extern fn remove_player(world: Handle, player_id: u32) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn remove_player(world: Handle, player_id: u32) -> Handle {
     get_game().remove_player(world, player_id)
 }
 
-#[no_mangle]
-pub extern fn allocate_buffer(size: u32) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn allocate_buffer(size: u32) -> Handle {
     get_game().allocate_buffer(size)
 }
 
-#[no_mangle]
-pub extern fn free_handle(handle: Handle) {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn free_handle(handle: Handle) {
     get_game().free_handle(handle)
 }
 
-#[no_mangle]
-pub extern fn buffer_ptr(buffer: Handle) -> u32 {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn buffer_ptr(buffer: Handle) -> u32 {
     get_game().buffer_ptr(buffer)
 }
 
-#[no_mangle]
-pub extern fn buffer_size(buffer: Handle) -> u32 {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn buffer_size(buffer: Handle) -> u32 {
     get_game().buffer_size(buffer)
 }
 
-#[no_mangle]
-pub extern fn deserialize_world(buffer: Handle) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn deserialize_world(buffer: Handle) -> Handle {
     get_game().deserialize_world(buffer)
 }
 
-#[no_mangle]
-pub extern fn serialize_world(world: Handle) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn serialize_world(world: Handle) -> Handle {
     get_game().serialize_world(world)
 }
 
-#[no_mangle]
-pub extern fn deserialize_input(buffer: Handle) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn deserialize_input(buffer: Handle) -> Handle {
     get_game().deserialize_input(buffer)
 }
 
-#[no_mangle]
-pub extern fn serialize_input(input: Handle) -> Handle {
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn serialize_input(input: Handle) -> Handle {
     get_game().serialize_input(input)
 }
 
-#[no_mangle]
-pub extern fn create_input(letters: u32, old_letters: u32, other: u32, old_other: u32) -> Handle {
-    get_game().create_input(letters, old_letters, other, old_other)
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn create_input(input_state: Handle) -> Handle {
+    get_game().create_input(input_state)
 }
 
-#[no_mangle]
-pub extern fn render(world: Handle, local_player: u32, width: u32, height: u32) {
-    draw_rectangle(0, 0, width, height, 0xFFFFFF);
-    get_game().render(world, local_player, width, height);
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
+pub extern "C" fn render(world: Handle, local_player: u32, width: u32, height: u32) {
+    let mut canvas = Canvas::new(width, height);
+    canvas.clear(Color::WHITE);
+    get_game().render(world, local_player, &mut canvas);
 }
 
-fn draw_rectangle(x: i32, y: i32, width: u32, height: u32, color: u32) {
+#[cfg(target_arch = "wasm32")]
+fn random_seed() -> u32 {
     unsafe {
-        externals::draw_rectangle(x, y, width, height, color);
+        externals::random_seed()
     }
 }
 
-fn log(message: &str) {
-    unsafe {
-        externals::log_str(message.as_ptr() as usize as u32, message.len() as u32);
-    }
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    static RANDOM_SEED: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn random_seed() -> u32 {
+    RANDOM_SEED.with(|seed| seed.get())
+}
+
+/// Seed that `random::frame_seed` returns when the game is run natively.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_random_seed(seed: u32) {
+    RANDOM_SEED.with(|cell| cell.set(seed));
 }
 
+#[cfg(target_arch = "wasm32")]
 mod externals {
-    extern {
-        pub fn draw_rectangle(x: i32, y: i32, width: u32, height: u32, color: u32);
-        pub fn log_str(ptr: u32, size: u32);
+    extern "C" {
+        pub fn fill_rect(x: i32, y: i32, width: u32, height: u32, color: u32);
+        pub fn fill_circle(x: i32, y: i32, radius: u32, color: u32);
+        pub fn draw_line(x0: i32, y0: i32, x1: i32, y1: i32, color: u32);
+        pub fn fill_polygon(points_ptr: u32, points: u32, color: u32);
+        pub fn load_sprite(name_ptr: u32, name_len: u32) -> u32;
+        pub fn draw_sprite(sprite: u32, x: i32, y: i32);
+        pub fn log_message(level: u32, ptr: u32, size: u32);
+        pub fn random_seed() -> u32;
     }
 }
@@ -0,0 +1,48 @@
+//! Deterministic random numbers. Host provides a seed for every frame, which
+//! is the same on the server and on every client, so games that take their
+//! randomness from here stay in sync.
+
+/// Seed of the frame that is currently being simulated.
+pub fn frame_seed() -> u32 {
+    crate::random_seed()
+}
+
+/// splitmix64 generator.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// Generator seeded from the current frame. All generators created on
+    /// the same frame with the same `stream` return the same numbers, so
+    /// independent uses should pass different streams (for example, id of
+    /// the player that the numbers are for).
+    pub fn for_frame(stream: u32) -> Rng {
+        Rng::new((u64::from(frame_seed()) << 32) | u64::from(stream))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Random number in `low..high`. Panics if the range is empty.
+    pub fn range(&mut self, low: i32, high: i32) -> i32 {
+        assert!(low < high, "empty range {}..{}", low, high);
+        let span = (i64::from(high) - i64::from(low)) as u64;
+        let offset = (u64::from(self.next_u32()) * span) >> 32;
+        (i64::from(low) + offset as i64) as i32
+    }
+}
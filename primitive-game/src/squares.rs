@@ -1,5 +1,7 @@
-use crate::draw_rectangle;
-use crate::game::{Game, Key, KeyboardState, PlayerId, Reader, ReadError, Serialize, Writer};
+use crate::canvas::{Canvas, Color};
+use crate::encoding::Encoding;
+use crate::game::{Game, InputState, Key, PlayerId, Serialize};
+use crate::random::Rng;
 
 static COLORS: [u32; 6] = [
     0x0000FF,
@@ -10,7 +12,7 @@ static COLORS: [u32; 6] = [
     0xFFFF00,
 ];
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize)]
 struct Player {
     id: PlayerId,
     x: i32,
@@ -18,16 +20,18 @@ struct Player {
 }
 
 impl Player {
+    /// New player at a random spot in the top left corner of the screen.
     fn new(id: PlayerId) -> Player {
+        let mut rng = Rng::for_frame(id.id());
         Player {
             id,
-            x: 10,
-            y: 10,
+            x: rng.range(10, 200),
+            y: rng.range(10, 200),
         }
     }
 
-    fn color(&self) -> u32 {
-        COLORS[self.id.id() as usize % COLORS.len()]
+    fn color(&self) -> Color {
+        Color::from_hex(COLORS[self.id.id() as usize % COLORS.len()])
     }
 
     fn update(&self, input: Input) -> Player {
@@ -39,50 +43,17 @@ impl Player {
     }
 }
 
-impl Serialize for Player {
-    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        let (id, x, y) = <(PlayerId, i32, i32)>::read(reader)?;
-        Ok(Player { id, x, y })
-    }
-
-    fn write<W: Writer>(&self, writer: &mut W) {
-        (self.id, self.x, self.y).write(writer)
-    }
-}
-
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize)]
 pub struct Input {
     dx: i8,
     dy: i8,
 }
 
-impl Serialize for Input {
-    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        let (dx, dy) = <(i8, i8)>::read(reader)?;
-        Ok(Input { dx, dy })
-    }
-
-    fn write<W: Writer>(&self, writer: &mut W) {
-        (self.dx, self.dy).write(writer)
-    }
-}
-
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize)]
 pub struct World {
     players: Vec<Player>,
 }
 
-impl Serialize for World {
-    fn read<R: Reader>(reader: &mut R) -> Result<Self, ReadError> {
-        let players = Vec::<Player>::read(reader)?;
-        Ok(World { players })
-    }
-
-    fn write<W: Writer>(&self, writer: &mut W) {
-        self.players.write(writer)
-    }
-}
-
 impl World {
     fn from_players(players: impl Iterator<Item = Player>) -> World {
         World {
@@ -120,6 +91,9 @@ impl Game for Squares {
     type World = World;
     type Input = Input;
 
+    const WORLD_ENCODING: Encoding = Encoding::Compact;
+    const INPUT_ENCODING: Encoding = Encoding::Compact;
+
     fn initial_world() -> Self::World {
         World::default()
     }
@@ -139,14 +113,17 @@ impl Game for Squares {
     }
 
     fn add_player(world: &Self::World, player: PlayerId) -> Self::World {
-        world.add_player(Player::new(player))
+        let player = Player::new(player);
+        debug!("player {} joined at ({}, {})", player.id.id(), player.x, player.y);
+        world.add_player(player)
     }
 
     fn remove_player(world: &Self::World, player: PlayerId) -> Self::World {
         world.remove_player(player)
     }
 
-    fn create_input(keys: KeyboardState) -> Self::Input {
+    fn create_input(input: &InputState) -> Self::Input {
+        let keys = input.keyboard();
         let (mut dx, mut dy) = (0, 0);
         if keys.is_pressed(Key::Right) { dx += 1; }
         if keys.is_pressed(Key::Left) { dx -= 1; }
@@ -155,9 +132,15 @@ impl Game for Squares {
         Input { dx, dy }
     }
 
-    fn render(world: &Self::World, _local_player: PlayerId, _width: u32, _height: u32) {
+    fn render(world: &Self::World, local_player: PlayerId, canvas: &mut Canvas) {
         for player in world.players() {
-            draw_rectangle(player.x, player.y, 20, 20, player.color());
+            if player.id == local_player {
+                canvas.fill_circle(player.x + 10, player.y + 10, 18, player.color().with_alpha(64));
+            }
+            canvas.fill_rect(player.x, player.y, 20, 20, player.color());
+            canvas.draw_text(player.x + 4, player.y + 6, &player.id.id().to_string(), 1, Color::BLACK);
         }
+        let count = format!("players: {}", world.players.len());
+        canvas.draw_text(4, 4, &count, 2, Color::BLACK);
     }
 }
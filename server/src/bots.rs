@@ -0,0 +1,168 @@
+//! Synthetic players that drive a game without real clients. Used for
+//! validating and benchmarking game packages.
+
+use std::fmt;
+use std::rc::Rc;
+use crate::game::wasmi::{Input, WasmiGame};
+use crate::input::{InputState, KeySet};
+use crate::server::ClientId;
+
+/// Keys that random bots press: letters, arrows and digits.
+const RANDOM_KEY_MASK: u128 = ((1 << 26) - 1) | (((1 << 14) - 1) << 32);
+
+#[derive(Debug)]
+pub struct ScriptError {
+    line: usize,
+    key: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: unknown key `{}`", self.line, self.key)
+    }
+}
+
+/// Keyboard states for consecutive frames, replayed in a loop. Each line of
+/// the script lists keys that are held in that frame, separated by
+/// whitespace: letters `a`-`z`, digits `0`-`9` and named keys like `up`,
+/// `space` or `left_shift` (see `KeySet::key_number`). Empty line means that
+/// no keys are held, lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct Script {
+    frames: Vec<KeySet>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, ScriptError> {
+        let mut frames = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let mut keys = KeySet::default();
+            for name in line.split_whitespace() {
+                let key = KeySet::key_number(name).ok_or_else(|| ScriptError {
+                    line: index + 1,
+                    key: name.to_string(),
+                })?;
+                keys = keys.with(key);
+            }
+            frames.push(keys);
+        }
+        Ok(Script { frames })
+    }
+}
+
+/// Xorshift generator for keyboard states.
+pub struct RandomKeys(u64);
+
+impl RandomKeys {
+    /// Generator for bot number `index` in a run with given seed. Bots of the
+    /// same run press unrelated keys even though their indexes are similar.
+    pub fn new(seed: u64, index: u64) -> RandomKeys {
+        // splitmix64
+        let mut z = seed.wrapping_add(index).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // xorshift gets stuck on zero
+        RandomKeys(if z == 0 { 0x9e37_79b9_7f4a_7c15 } else { z })
+    }
+
+    fn generate(&mut self) -> KeySet {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        KeySet::from_bits(u128::from(self.0) & RANDOM_KEY_MASK)
+    }
+}
+
+enum KeySource {
+    Random(RandomKeys),
+    Script {
+        script: Rc<Script>,
+        position: usize,
+    },
+}
+
+pub struct Bot {
+    client: ClientId,
+    source: KeySource,
+    previous: KeySet,
+}
+
+impl Bot {
+    pub fn random(client: ClientId, keys: RandomKeys) -> Bot {
+        Bot {
+            client,
+            source: KeySource::Random(keys),
+            previous: KeySet::default(),
+        }
+    }
+
+    /// Bot that replays the script starting from given frame of it.
+    pub fn scripted(client: ClientId, script: Rc<Script>, start: usize) -> Bot {
+        Bot {
+            client,
+            source: KeySource::Script { script, position: start },
+            previous: KeySet::default(),
+        }
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// Input for the next frame, created by the game from bot's keyboard.
+    pub fn next_input(&mut self, game: &mut WasmiGame) -> Input {
+        let keys = match &mut self.source {
+            KeySource::Random(random) => random.generate(),
+            KeySource::Script { script, position } => {
+                let keys = if script.frames.is_empty() {
+                    KeySet::default()
+                } else {
+                    script.frames[*position % script.frames.len()]
+                };
+                *position += 1;
+                keys
+            }
+        };
+        let old_keys = std::mem::replace(&mut self.previous, keys);
+        game.create_input(&InputState { keys, old_keys, ..InputState::default() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script() {
+        let script = Script::parse("a up\n\n# comment\n9 right z f12\n").expect("failed to parse");
+        assert_eq!(
+            script.frames,
+            vec![
+                KeySet::from_bits(1 | (1 << 32)),
+                KeySet::default(),
+                KeySet::from_bits((1 << 25) | (1 << 35) | (1 << 45) | (1 << 77)),
+            ],
+        );
+    }
+
+    #[test]
+    fn bots_press_different_keys() {
+        let keys = |seed, index| {
+            let mut random = RandomKeys::new(seed, index);
+            (0..10).map(|_| random.generate()).collect::<Vec<_>>()
+        };
+        assert_ne!(keys(0, 0), keys(0, 1));
+        assert_ne!(keys(2, 0), keys(2, 1));
+        assert_eq!(keys(5, 3), keys(5, 3));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let err = Script::parse("a\nb spacebar").expect_err("parsed invalid script");
+        assert_eq!(err.to_string(), "line 2: unknown key `spacebar`");
+    }
+}
@@ -0,0 +1,216 @@
+//! Byte-level difference between two world blobs, so that a client that
+//! already has one of them can be sent the other in a few bytes. The client
+//! side is in `client/src/delta.ts`, and the two must agree on the format:
+//!
+//! ```text
+//! delta     = varint(base length) varint(target length) operation*
+//! operation = varint(length * 2) byte*length         (insert bytes)
+//!           | varint(length * 2 + 1) varint(offset)  (copy from base)
+//! ```
+//!
+//! Varints are LEB128, like in `primitive_game::encoding`.
+
+use std::collections::HashMap;
+
+/// Base is indexed in blocks of this many bytes. Matches shorter than that
+/// might not be found, but they would barely be shorter than the bytes.
+const BLOCK: usize = 8;
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_insert(out: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        write_varint(out, bytes.len() * 2);
+        out.extend_from_slice(bytes);
+    }
+}
+
+fn write_copy(out: &mut Vec<u8>, offset: usize, length: usize) {
+    write_varint(out, length * 2 + 1);
+    write_varint(out, offset);
+}
+
+/// Delta that turns `base` into `target`.
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index = HashMap::new();
+    for start in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        index.entry(&base[start..start + BLOCK]).or_insert(start);
+    }
+    let mut out = Vec::new();
+    write_varint(&mut out, base.len());
+    write_varint(&mut out, target.len());
+    let mut inserted_from = 0;
+    let mut position = 0;
+    // distance from target to base in the last match, most worlds change in
+    // place so the next match is likely to be at the same distance
+    let mut shift = 0isize;
+    while position + BLOCK <= target.len() {
+        let block = &target[position..position + BLOCK];
+        let same_shift = position as isize + shift;
+        let found = if same_shift >= 0 && base.get(same_shift as usize..same_shift as usize + BLOCK) == Some(block) {
+            Some(same_shift as usize)
+        } else {
+            index.get(block).copied()
+        };
+        let base_start = match found {
+            Some(base_start) => base_start,
+            None => {
+                position += 1;
+                continue;
+            }
+        };
+        let (mut start, mut base_start) = (position, base_start);
+        while start > inserted_from && base_start > 0 && target[start - 1] == base[base_start - 1] {
+            start -= 1;
+            base_start -= 1;
+        }
+        let length = target[start..]
+            .iter()
+            .zip(&base[base_start..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        write_insert(&mut out, &target[inserted_from..start]);
+        write_copy(&mut out, base_start, length);
+        position = start + length;
+        inserted_from = position;
+        shift = base_start as isize - start as isize;
+    }
+    write_insert(&mut out, &target[inserted_from..]);
+    out
+}
+
+// Clients apply deltas, server only needs this to test `diff`
+#[cfg(test)]
+struct DeltaReader<'a> {
+    delta: &'a [u8],
+}
+
+#[cfg(test)]
+impl<'a> DeltaReader<'a> {
+    fn varint(&mut self) -> Option<usize> {
+        let mut value = 0usize;
+        let mut shift = 0u32;
+        loop {
+            let (&byte, rest) = self.delta.split_first()?;
+            self.delta = rest;
+            let payload = usize::from(byte & 0x7f);
+            if shift >= usize::BITS || (payload << shift) >> shift != payload {
+                return None;
+            }
+            value |= payload << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        if length > self.delta.len() {
+            return None;
+        }
+        let (bytes, rest) = self.delta.split_at(length);
+        self.delta = rest;
+        Some(bytes)
+    }
+}
+
+/// Target that `delta` was made for, or `None` if the delta is invalid or
+/// was made from a different base.
+#[cfg(test)]
+pub fn apply(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = DeltaReader { delta };
+    if reader.varint()? != base.len() {
+        return None;
+    }
+    let target_length = reader.varint()?;
+    let mut target = Vec::with_capacity(target_length.min(base.len() + delta.len()));
+    while !reader.delta.is_empty() {
+        let operation = reader.varint()?;
+        let length = operation / 2;
+        if length > target_length - target.len() {
+            return None;
+        }
+        if operation % 2 == 0 {
+            target.extend_from_slice(reader.bytes(length)?);
+        } else {
+            let offset = reader.varint()?;
+            target.extend_from_slice(base.get(offset..offset.checked_add(length)?)?);
+        }
+    }
+    if target.len() != target_length {
+        return None;
+    }
+    Some(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = diff(base, target);
+        assert_eq!(apply(base, &delta).as_deref(), Some(target));
+        delta
+    }
+
+    fn pseudo_random_bytes(count: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_changes_give_small_deltas() {
+        let base = pseudo_random_bytes(10_000, 1);
+        assert!(round_trip(&base, &base).len() < 10);
+        let mut changed = base.clone();
+        changed[5000] ^= 0xff;
+        changed[7000] ^= 0xff;
+        assert!(round_trip(&base, &changed).len() < 30);
+        let mut inserted = base.clone();
+        inserted.splice(100..100, pseudo_random_bytes(50, 2));
+        inserted.drain(8000..8100);
+        assert!(round_trip(&base, &inserted).len() < 80);
+        let mut appended = base.clone();
+        appended.extend_from_slice(&[1, 2, 3]);
+        assert!(round_trip(&base, &appended).len() < 15);
+    }
+
+    #[test]
+    fn unrelated_and_empty_blobs() {
+        let base = pseudo_random_bytes(1000, 3);
+        let other = pseudo_random_bytes(1000, 4);
+        assert!(round_trip(&base, &other).len() < 1010);
+        assert_eq!(round_trip(&[], &[]), [0, 0]);
+        round_trip(&[], &base);
+        round_trip(&base, &[]);
+        round_trip(&[1, 2, 3], &[3, 2, 1]);
+        round_trip(&[7; 100], &[7; 333]);
+    }
+
+    #[test]
+    fn invalid_deltas() {
+        let base = pseudo_random_bytes(100, 5);
+        let delta = diff(&base, &base);
+        assert!(apply(&base[..99], &delta).is_none(), "applied to a different base");
+        assert!(apply(&base, &delta[..delta.len() - 1]).is_none());
+        // copy past the end of base
+        assert!(apply(&[1, 2], &[2, 2, 5, 1]).is_none());
+        // more bytes than the target length
+        assert!(apply(&[], &[0, 1, 4, 1, 2]).is_none());
+        // fewer bytes than the target length
+        assert!(apply(&[], &[0, 3, 2, 1]).is_none());
+        assert!(apply(&[], &[0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).is_none());
+    }
+}
@@ -0,0 +1,501 @@
+//! Checks that game code computes identical worlds on every peer, by
+//! replaying a recorded game on several independent game instances and
+//! comparing their worlds after every frame.
+
+use std::fmt;
+use crate::game::{DeserializeError, FrameUpdate, Game, ToBlob};
+use crate::game::wasmi::{self, WasmiGame};
+use crate::protocol::Update;
+use crate::recording::Recording;
+use crate::validate::catch_panic;
+
+/// Game that can continue a recorded game.
+pub trait Replay: Game {
+    fn deserialize_world(&mut self, from: &[u8]) -> Result<Self::World, DeserializeError>;
+    /// Player with the id that was broadcast to clients.
+    fn player_id(&self, id: u64) -> Option<Self::PlayerId>;
+}
+
+impl Replay for WasmiGame {
+    fn deserialize_world(&mut self, from: &[u8]) -> Result<wasmi::World, DeserializeError> {
+        Ok(WasmiGame::deserialize_world(self, from))
+    }
+
+    fn player_id(&self, id: u64) -> Option<wasmi::PlayerId> {
+        if id > u64::from(u32::MAX) {
+            None
+        } else {
+            Some(wasmi::PlayerId::new(id as u32))
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl<G: primitive_game::game::Game> Replay for crate::game::native::NativeGame<G> {
+    fn deserialize_world(&mut self, from: &[u8]) -> Result<Self::World, DeserializeError> {
+        crate::game::native::NativeGame::deserialize_world(self, from)
+    }
+
+    fn player_id(&self, id: u64) -> Option<Self::PlayerId> {
+        if id > u64::from(u32::MAX) {
+            None
+        } else {
+            Some(crate::game::native::PlayerId::new(id as u32))
+        }
+    }
+}
+
+/// Replays a recording on a single game instance.
+trait Replayer {
+    fn start(&mut self, world: &[u8]) -> Result<(), String>;
+    fn apply(&mut self, update: &Update) -> Result<(), String>;
+    fn world(&self) -> Vec<u8>;
+}
+
+struct GameReplayer<G: Replay> {
+    game: G,
+    world: Option<G::World>,
+}
+
+impl<G: Replay> GameReplayer<G> {
+    fn player(&self, id: u64) -> Result<G::PlayerId, String> {
+        self.game.player_id(id).ok_or_else(|| format!("invalid player id {}", id))
+    }
+}
+
+impl<G: Replay> Replayer for GameReplayer<G> {
+    fn start(&mut self, world: &[u8]) -> Result<(), String> {
+        let world = self.game
+            .deserialize_world(world)
+            .map_err(|_| "failed to deserialize starting world".to_string())?;
+        self.world = Some(world);
+        Ok(())
+    }
+
+    fn apply(&mut self, update: &Update) -> Result<(), String> {
+        let mut frame_update = FrameUpdate::<G> {
+            seed: update.seed,
+            ..FrameUpdate::default()
+        };
+        for &player in &update.new_players {
+            frame_update.new_players.insert(self.player(player)?);
+        }
+        for &player in &update.removed_players {
+            frame_update.removed_players.insert(self.player(player)?);
+        }
+        for (&player, input) in &update.inputs {
+            let player = self.player(player)?;
+            let input = self.game
+                .deserialize_input(input)
+                .map_err(|_| format!("failed to deserialize input of player {}", Into::<u64>::into(player)))?;
+            frame_update.player_inputs.insert(player, input);
+        }
+        let world = self.world.as_ref().expect("replay not started");
+        self.world = Some(self.game.apply_update(world, &frame_update));
+        Ok(())
+    }
+
+    fn world(&self) -> Vec<u8> {
+        self.world.as_ref().expect("replay not started").to_blob()
+    }
+}
+
+pub struct Backend {
+    name: String,
+    replayer: Box<dyn Replayer>,
+}
+
+impl Backend {
+    pub fn new<G: Replay + 'static>(name: impl Into<String>, game: G) -> Backend {
+        Backend {
+            name: name.into(),
+            replayer: Box::new(GameReplayer { game, world: None }),
+        }
+    }
+}
+
+/// Maximum number of differing bytes listed in a report.
+const MAX_LISTED_DIFFERENCES: usize = 32;
+
+pub enum Outcome {
+    /// All backends computed the same worlds for every frame.
+    Deterministic,
+    /// Backends computed different worlds on this frame.
+    Diverged {
+        frame: u64,
+        worlds: Vec<(String, Vec<u8>)>,
+    },
+    /// A backend failed to replay the frame.
+    Failed {
+        frame: u64,
+        backend: String,
+        error: String,
+    },
+}
+
+pub struct Report {
+    pub frames: u64,
+    pub outcome: Outcome,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.outcome, Outcome::Deterministic)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Deterministic => {
+                write!(f, "all backends computed identical worlds for {} frames", self.frames)
+            }
+            Outcome::Failed { frame, backend, error } => {
+                write!(f, "backend `{}` failed on frame {}: {}", backend, frame, error)
+            }
+            Outcome::Diverged { frame, worlds } => {
+                write!(f, "worlds diverged on frame {}", frame)?;
+                let (expected_name, expected) = &worlds[0];
+                for (name, world) in &worlds[1..] {
+                    if world != expected {
+                        write!(f, "\n`{}` vs `{}`:\n", expected_name, name)?;
+                        write_diff(f, expected, world)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_diff(f: &mut fmt::Formatter<'_>, left: &[u8], right: &[u8]) -> fmt::Result {
+    if left.len() != right.len() {
+        writeln!(f, "  lengths differ: {} != {} bytes", left.len(), right.len())?;
+    }
+    let len = left.len().max(right.len());
+    let differences = (0..len)
+        .filter(|&i| left.get(i) != right.get(i))
+        .collect::<Vec<_>>();
+    let show = |byte: Option<&u8>| byte.map_or("--".to_string(), |b| format!("{:02x}", b));
+    for &offset in differences.iter().take(MAX_LISTED_DIFFERENCES) {
+        writeln!(f, "  offset {:#06x}: {} != {}", offset, show(left.get(offset)), show(right.get(offset)))?;
+    }
+    if differences.len() > MAX_LISTED_DIFFERENCES {
+        writeln!(f, "  ... and {} more differing bytes", differences.len() - MAX_LISTED_DIFFERENCES)?;
+    }
+    Ok(())
+}
+
+/// Replays the recording on every backend, stopping at the first frame
+/// where their worlds differ.
+pub fn check(recording: &Recording, mut backends: Vec<Backend>) -> Report {
+    assert!(backends.len() >= 2, "need at least two backends to compare");
+    let mut frame = recording.start.frame;
+    let failed = |frame, backend: &Backend, error| Report {
+        frames: frame - recording.start.frame,
+        outcome: Outcome::Failed { frame, backend: backend.name.clone(), error },
+    };
+    for backend in &mut backends {
+        let replayer = &mut backend.replayer;
+        if let Err(e) = catch_panic(|| replayer.start(&recording.start.world)) {
+            return failed(frame, backend, e);
+        }
+    }
+    let mut updates = recording.updates.iter();
+    loop {
+        let mut worlds = Vec::new();
+        for backend in &backends {
+            let replayer = &backend.replayer;
+            match catch_panic(|| Ok(replayer.world())) {
+                Ok(world) => worlds.push((backend.name.clone(), world)),
+                Err(e) => return failed(frame, backend, e),
+            }
+        }
+        if worlds.iter().any(|(_, world)| *world != worlds[0].1) {
+            return Report {
+                frames: frame - recording.start.frame,
+                outcome: Outcome::Diverged { frame, worlds },
+            };
+        }
+        let update = match updates.next() {
+            Some(update) => update,
+            None => break,
+        };
+        for backend in &mut backends {
+            let replayer = &mut backend.replayer;
+            if let Err(e) = catch_panic(|| replayer.apply(update)) {
+                return failed(frame, backend, e);
+            }
+        }
+        frame += 1;
+    }
+    Report {
+        frames: frame - recording.start.frame,
+        outcome: Outcome::Deterministic,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "native")]
+    fn native_recording(frames: u64) -> Recording {
+        use primitive_game::squares::Squares;
+        use crate::game::native::NativeGame;
+        use crate::input::{InputState, KeySet};
+        use crate::recording::Start;
+        use crate::server::Server;
+
+        let mut game = NativeGame::<Squares>::new();
+        let keys = KeySet::default().with(KeySet::key_number("right").unwrap());
+        let input = game.create_input(&InputState { keys, ..InputState::default() }).to_blob();
+        let mut server = Server::new(game);
+        let start = Start { frame: server.frame(), world: server.world().to_blob() };
+        let (client, _) = server.client_connected();
+        assert!(server.client_joined(client, 0).is_ok());
+        let mut updates = Vec::new();
+        for frame in 1..=frames {
+            assert!(server.client_input(client, frame, &input).is_ok());
+            updates.push(Update::from(server.game_tick()));
+        }
+        Recording { start, updates }
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn identical_games_are_deterministic() {
+        use primitive_game::squares::Squares;
+        use crate::game::native::NativeGame;
+
+        let recording = native_recording(10);
+        let backends = vec![
+            Backend::new("a", NativeGame::<Squares>::new()),
+            Backend::new("b", NativeGame::<Squares>::new()),
+        ];
+        let report = check(&recording, backends);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.frames, 10);
+    }
+
+    /// Replays correctly until `after` updates were applied, and then reports
+    /// a slightly different world.
+    #[cfg(feature = "native")]
+    struct Drifting {
+        inner: Box<dyn Replayer>,
+        applied: u64,
+        after: u64,
+    }
+
+    #[cfg(feature = "native")]
+    impl Replayer for Drifting {
+        fn start(&mut self, world: &[u8]) -> Result<(), String> {
+            self.inner.start(world)
+        }
+
+        fn apply(&mut self, update: &Update) -> Result<(), String> {
+            self.applied += 1;
+            self.inner.apply(update)
+        }
+
+        fn world(&self) -> Vec<u8> {
+            let mut world = self.inner.world();
+            if self.applied >= self.after {
+                world[0] ^= 1;
+            }
+            world
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn drifting_backend_diverges() {
+        use primitive_game::squares::Squares;
+        use crate::game::native::NativeGame;
+
+        let recording = native_recording(10);
+        let drifting = Drifting {
+            inner: Backend::new("", NativeGame::<Squares>::new()).replayer,
+            applied: 0,
+            after: 4,
+        };
+        let backends = vec![
+            Backend::new("a", NativeGame::<Squares>::new()),
+            Backend { name: "b".to_string(), replayer: Box::new(drifting) },
+        ];
+        let report = check(&recording, backends);
+        let start = recording.start.frame;
+        match &report.outcome {
+            Outcome::Diverged { frame, worlds } => {
+                assert_eq!(*frame, start + 4);
+                assert_eq!(worlds.len(), 2);
+                assert_ne!(worlds[0].1, worlds[1].1);
+            }
+            _ => panic!("unexpected report: {}", report),
+        }
+        assert_eq!(report.frames, 4);
+        assert!(report.to_string().starts_with(&format!("worlds diverged on frame {}", start + 4)));
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn incompatible_world_fails_to_start() {
+        use primitive_game::game::Empty;
+        use primitive_game::squares::Squares;
+        use crate::game::native::NativeGame;
+
+        let recording = native_recording(10);
+        let backends = vec![
+            Backend::new("squares", NativeGame::<Squares>::new()),
+            Backend::new("empty", NativeGame::<Empty>::new()),
+        ];
+        let report = check(&recording, backends);
+        match &report.outcome {
+            // empty world is empty, so squares world has trailing bytes for it
+            Outcome::Failed { frame: 0, backend, .. } if backend == "empty" => {}
+            _ => panic!("unexpected report: {}", report),
+        }
+    }
+
+    /// Game whose world is a single number. Handles point to a length
+    /// followed by data, and are never freed.
+    const COUNTER_GAME: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            ;; wasmi allocates memory as it is written to, and the host can
+            ;; only write to what is allocated
+            (data (i32.const 65535) "\00")
+            (global $next (mut i32) (i32.const 1024))
+            (global $updates (mut i32) (i32.const 0))
+            (func $alloc (param $size i32) (result i32)
+                (local $handle i32)
+                (local.set $handle (global.get $next))
+                (i32.store (local.get $handle) (local.get $size))
+                (global.set $next (i32.add
+                    (local.get $handle)
+                    (i32.and (i32.add (local.get $size) (i32.const 11)) (i32.const -4))))
+                (local.get $handle))
+            (func $value (param $value i32) (result i32)
+                (local $handle i32)
+                (local.set $handle (call $alloc (i32.const 4)))
+                (i32.store offset=4 (local.get $handle) (local.get $value))
+                (local.get $handle))
+            (func $get (param $handle i32) (result i32)
+                (i32.load offset=4 (local.get $handle)))
+            (func (export "initialize"))
+            (func (export "initial_world") (result i32)
+                (call $value (i32.const 0)))
+            (func (export "update_world") (param i32) (result i32)
+                (global.set $updates (i32.add (global.get $updates) (i32.const 1)))
+                (call $value (i32.add
+                    (i32.add (i32.mul (call $get (local.get 0)) (i32.const 31)) (i32.const 1))
+                    DRIFT)))
+            (func (export "update_player") (param i32 i32 i32) (result i32)
+                (call $value (i32.add
+                    (call $get (local.get 0))
+                    (i32.mul (local.get 1) (call $get (local.get 2))))))
+            (func (export "add_player") (param i32 i32) (result i32)
+                (call $value (i32.add (call $get (local.get 0)) (i32.mul (local.get 1) (i32.const 1000)))))
+            (func (export "remove_player") (param i32 i32) (result i32)
+                (call $value (i32.sub (call $get (local.get 0)) (local.get 1))))
+            (func (export "allocate_buffer") (param i32) (result i32)
+                (call $alloc (local.get 0)))
+            (func (export "free_handle") (param i32))
+            (func (export "buffer_ptr") (param i32) (result i32)
+                (i32.add (local.get 0) (i32.const 4)))
+            (func (export "buffer_size") (param i32) (result i32)
+                (i32.load (local.get 0)))
+            (func (export "deserialize_world") (param i32) (result i32)
+                (call $value (call $get (local.get 0))))
+            (func (export "serialize_world") (param i32) (result i32)
+                (call $value (call $get (local.get 0))))
+            (func (export "deserialize_input") (param i32) (result i32)
+                (call $value (call $get (local.get 0))))
+            (func (export "serialize_input") (param i32) (result i32)
+                (call $value (call $get (local.get 0))))
+            (func (export "create_input") (param i32) (result i32)
+                (call $value (i32.const 1)))
+            (func (export "render") (param i32 i32 i32 i32)))
+    "#;
+
+    /// Counter game on every runtime. The second interpreter adds `drift` to
+    /// the world on every update after the third one.
+    fn counter_backends(drift: i32) -> Vec<Backend> {
+        use crate::game::wasmi::runtime::Runtime;
+        use crate::game::wasmi::sys::Module;
+
+        let drift = format!("(i32.mul (i32.const {}) (i32.gt_u (global.get $updates) (i32.const 3)))", drift);
+        let honest = wat::parse_str(COUNTER_GAME.replace("DRIFT", "(i32.const 0)")).unwrap();
+        let drifting = wat::parse_str(COUNTER_GAME.replace("DRIFT", &drift)).unwrap();
+        let game = |code: &[u8], runtime| WasmiGame::new(Module::from_buffer(code, runtime).unwrap());
+        let mut backends = vec![
+            Backend::new("interpreter", game(&honest, Runtime::Interpreter)),
+            Backend::new("second interpreter", game(&drifting, Runtime::Interpreter)),
+        ];
+        if cfg!(feature = "jit") {
+            backends.push(Backend::new("jit", game(&honest, Runtime::Jit)));
+        }
+        backends
+    }
+
+    fn counter_recording(frames: u64) -> Recording {
+        use std::collections::HashMap;
+        use crate::recording::Start;
+
+        let mut updates = vec![Update {
+            seed: 0,
+            new_players: vec![2],
+            removed_players: Vec::new(),
+            inputs: HashMap::new(),
+        }];
+        for frame in 1..frames {
+            let mut inputs = HashMap::new();
+            inputs.insert(2, vec![frame as u8, 0, 0, 0]);
+            updates.push(Update { seed: 0, new_players: Vec::new(), removed_players: Vec::new(), inputs });
+        }
+        Recording { start: Start { frame: 5, world: vec![0; 4] }, updates }
+    }
+
+    #[test]
+    fn wasm_runtimes_agree() {
+        let report = check(&counter_recording(10), counter_backends(0));
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.frames, 10);
+    }
+
+    #[test]
+    fn wasm_runtimes_diverge() {
+        let report = check(&counter_recording(10), counter_backends(1));
+        match &report.outcome {
+            Outcome::Diverged { frame: 9, worlds } => {
+                assert_eq!(worlds[0].0, "interpreter");
+                assert_ne!(worlds[0].1, worlds[1].1);
+                assert!(worlds[2..].iter().all(|(_, world)| *world == worlds[0].1));
+            }
+            _ => panic!("unexpected report: {}", report),
+        }
+        assert_eq!(report.frames, 4);
+    }
+
+    #[test]
+    fn diff_lists_differing_bytes() {
+        let report = Report {
+            frames: 3,
+            outcome: Outcome::Diverged {
+                frame: 3,
+                worlds: vec![
+                    ("a".to_string(), vec![1, 2, 3, 4]),
+                    ("b".to_string(), vec![1, 5, 3]),
+                ],
+            },
+        };
+        assert_eq!(
+            report.to_string(),
+            "worlds diverged on frame 3\n\
+             `a` vs `b`:\n  \
+             lengths differ: 4 != 3 bytes\n  \
+             offset 0x0001: 02 != 05\n  \
+             offset 0x0003: 04 != --\n",
+        );
+    }
+}
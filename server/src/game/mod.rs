@@ -1,8 +1,12 @@
+#[cfg(feature = "native")]
+pub mod native;
 pub mod wasmi;
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Debug};
 use std::hash::Hash;
+use crate::image::Image;
+use crate::package::Package;
 
 #[derive(Debug)]
 pub struct DeserializeError;
@@ -24,7 +28,23 @@ pub trait Game {
     fn deserialize_input(&mut self, from: &[u8]) -> Result<Self::Input, DeserializeError>;
     fn generate_player_id(&mut self) -> Self::PlayerId;
 
+    /// Seed that game code gets from the host until the next call. Games
+    /// that don't use host randomness can ignore it.
+    fn set_random_seed(&mut self, _seed: u32) {}
+
+    /// Room and frame that following calls are made for. Only used to tag
+    /// messages that game code logs.
+    fn set_log_frame(&mut self, _room_id: u64, _frame: u64) {}
+
+    /// Difference from `base` to `world` in game's own format, for clients
+    /// that already have `base`. Returns `None` if game code doesn't have
+    /// such a format, then a generic byte-level diff is used instead.
+    fn diff_world(&self, _base: &Self::World, _world: &Self::World) -> Option<Vec<u8>> {
+        None
+    }
+
     fn apply_update(&mut self, world: &Self::World, update: &FrameUpdate<Self>) -> Self::World {
+        self.set_random_seed(update.seed);
         // FIXME: gross
         let mut removed = update.removed_players.iter();
         let mut world = if let Some(&player) = removed.next() {
@@ -46,7 +66,32 @@ pub trait Game {
     }
 }
 
+/// Game whose code can be replaced while it is running.
+pub trait Reload: Game + Sized {
+    type Error: fmt::Display;
+
+    /// Load game code from the package. New game must not generate player
+    /// ids that were already generated by `self`. World is carried over if
+    /// new code can read it, otherwise `Reloaded::world` is `None` and the game
+    /// has to be restarted.
+    fn reload(&self, package: &Package, world: &Self::World) -> Result<Reloaded<Self>, Self::Error>;
+}
+
+/// Game that can draw its world on the server.
+pub trait Render: Game {
+    /// Draws the world like a spectator, who doesn't control any player,
+    /// sees it.
+    fn render_to_image(&self, world: &Self::World, width: u32, height: u32) -> Result<Image, String>;
+}
+
+pub struct Reloaded<G: Game> {
+    pub game: G,
+    pub world: Option<G::World>,
+}
+
 pub struct FrameUpdate<G: Game + ?Sized> {
+    /// Seed for random numbers that game code uses on this frame.
+    pub seed: u32,
     pub new_players: BTreeSet<G::PlayerId>,
     pub removed_players: BTreeSet<G::PlayerId>,
     pub player_inputs: BTreeMap<G::PlayerId, G::Input>,
@@ -71,6 +116,7 @@ impl<G: Game + ?Sized> FrameUpdate<G> {
 impl<G: Game + ?Sized> Default for FrameUpdate<G> {
     fn default() -> Self {
         FrameUpdate {
+            seed: 0,
             new_players: Default::default(),
             removed_players: Default::default(),
             player_inputs: Default::default(),
@@ -85,8 +131,8 @@ where
     G::Input: Eq,
 {
     fn eq(&self, rhs: &Self) -> bool {
-        let l = (&self.new_players, &self.removed_players, &self.player_inputs);
-        let r = (&rhs.new_players, &rhs.removed_players, &rhs.player_inputs);
+        let l = (self.seed, &self.new_players, &self.removed_players, &self.player_inputs);
+        let r = (rhs.seed, &rhs.new_players, &rhs.removed_players, &rhs.player_inputs);
         l == r
     }
 }
@@ -106,6 +152,7 @@ where
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("FrameUpdate")
+            .field("seed", &self.seed)
             .field("new_players", &self.new_players)
             .field("removed_players", &self.removed_players)
             .field("player_inputs", &self.player_inputs)
@@ -0,0 +1,206 @@
+//! Game that runs game logic natively, without compiling it to wasm. Useful
+//! for unit tests and profiling of game crates, and for checking that wasm
+//! bindings don't change game results.
+
+use std::marker::PhantomData;
+use log::warn;
+use primitive_game::game as guest;
+use super::{DeserializeError, Game, ToBlob};
+#[cfg(test)]
+use crate::input::InputState;
+#[cfg(test)]
+use primitive_game::encoding::Encoding;
+
+pub struct World<G: guest::Game> {
+    world: G::World,
+}
+
+impl<G: guest::Game> ToBlob for World<G> {
+    fn to_blob(&self) -> Vec<u8> {
+        guest::to_bytes(&self.world, G::WORLD_ENCODING)
+    }
+}
+
+pub struct Input<G: guest::Game> {
+    input: G::Input,
+}
+
+impl<G: guest::Game> ToBlob for Input<G> {
+    fn to_blob(&self) -> Vec<u8> {
+        guest::to_bytes(&self.input, G::INPUT_ENCODING)
+    }
+}
+
+#[derive(PartialEq, Eq, Ord, PartialOrd, Debug, Hash, Copy, Clone)]
+pub struct PlayerId {
+    id: u32,
+}
+
+impl PlayerId {
+    pub fn new(id: u32) -> PlayerId {
+        PlayerId { id }
+    }
+
+    fn to_guest(self) -> guest::PlayerId {
+        guest::PlayerId::new(self.id)
+    }
+}
+
+impl From<PlayerId> for u64 {
+    fn from(player: PlayerId) -> u64 {
+        u64::from(player.id)
+    }
+}
+
+pub struct NativeGame<G: guest::Game> {
+    next_player_id: u32,
+    game: PhantomData<G>,
+}
+
+impl<G: guest::Game> NativeGame<G> {
+    pub fn new() -> Self {
+        NativeGame {
+            next_player_id: 0,
+            game: PhantomData,
+        }
+    }
+
+    /// Create input from state of input devices, the same way that the
+    /// client does.
+    // This is only used in tests, so cfg(test) effectively silences dead code warning
+    #[cfg(test)]
+    pub fn create_input(&mut self, state: &InputState) -> Input<G> {
+        let state = guest::from_bytes(&state.to_bytes(), Encoding::Fixed).expect("invalid input state");
+        Input { input: G::create_input(&state) }
+    }
+
+    pub fn deserialize_world(&mut self, from: &[u8]) -> Result<World<G>, DeserializeError> {
+        let world = guest::from_bytes(from, G::WORLD_ENCODING).map_err(|error| {
+            warn!("failed to deserialize world: {}", error);
+            DeserializeError
+        })?;
+        Ok(World { world })
+    }
+}
+
+impl<G: guest::Game> Default for NativeGame<G> {
+    fn default() -> Self {
+        NativeGame::new()
+    }
+}
+
+impl<G: guest::Game> Game for NativeGame<G> {
+    type World = World<G>;
+    type PlayerId = PlayerId;
+    type Input = Input<G>;
+
+    fn initial_world(&mut self) -> World<G> {
+        World { world: G::initial_world() }
+    }
+
+    fn update_world(&mut self, world: &World<G>) -> World<G> {
+        World { world: G::update_world(&world.world) }
+    }
+
+    fn update_player(&mut self, world: &World<G>, player: PlayerId, input: &Input<G>) -> World<G> {
+        World { world: G::update_player(&world.world, player.to_guest(), &input.input) }
+    }
+
+    fn add_player(&mut self, world: &World<G>, player: PlayerId) -> World<G> {
+        World { world: G::add_player(&world.world, player.to_guest()) }
+    }
+
+    fn remove_player(&mut self, world: &World<G>, player: PlayerId) -> World<G> {
+        World { world: G::remove_player(&world.world, player.to_guest()) }
+    }
+
+    fn deserialize_input(&mut self, from: &[u8]) -> Result<Input<G>, DeserializeError> {
+        let input = guest::from_bytes(from, G::INPUT_ENCODING).map_err(|error| {
+            warn!("failed to deserialize input: {}", error);
+            DeserializeError
+        })?;
+        Ok(Input { input })
+    }
+
+    fn generate_player_id(&mut self) -> PlayerId {
+        let id = PlayerId { id: self.next_player_id };
+        self.next_player_id += 1;
+        id
+    }
+
+    fn set_random_seed(&mut self, seed: u32) {
+        primitive_game::set_random_seed(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use primitive_game::squares::Squares;
+    use crate::bots::{Bot, RandomKeys};
+    use crate::input::KeySet;
+    use crate::game::wasmi::WasmiGame;
+    use crate::game::wasmi::runtime::Runtime;
+    use crate::game::wasmi::sys::Module;
+    use crate::package;
+    use crate::server::Server;
+
+    const RIGHT: u8 = 35;
+
+    #[test]
+    fn server_runs_native_game() {
+        let mut game = NativeGame::<Squares>::new();
+        let keys = KeySet::default().with(RIGHT);
+        let input = game.create_input(&InputState { keys, ..InputState::default() }).to_blob();
+        let mut server = Server::new(game);
+        let (client, _) = server.client_connected();
+        assert!(server.client_joined(client, 0).is_ok());
+        server.game_tick();
+        let start = server.world().to_blob();
+        assert!(server.client_input(client, 1, &input).is_ok());
+        server.game_tick();
+        let moved = server.world().to_blob();
+        let mut game = NativeGame::<Squares>::new();
+        let mut expected = game.deserialize_world(&start).unwrap();
+        expected = game.update_world(&expected);
+        let input = game.deserialize_input(&input).unwrap();
+        expected = game.update_player(&expected, PlayerId { id: 0 }, &input);
+        assert_eq!(moved, expected.to_blob());
+        assert_ne!(moved, start);
+    }
+
+    /// Needs a package built from `primitive-game`, run with
+    /// `GAME_PACKAGE=path/to/game.zip cargo test --features native -- --ignored`.
+    #[test]
+    #[ignore]
+    fn native_game_matches_wasm() {
+        let path = std::env::var("GAME_PACKAGE").expect("GAME_PACKAGE is not set");
+        let package = package::load_from_file(&path).expect("failed to load package");
+        let module = Module::from_buffer(&package.wasm_module, Runtime::Interpreter)
+            .expect("failed to load module");
+        let module = Rc::new(module);
+        let mut client_game = WasmiGame::with_shared_module(module.clone());
+        let mut wasm = Server::new(WasmiGame::with_shared_module(module));
+        let mut native = Server::new(NativeGame::<Squares>::new());
+        let mut bots = Vec::new();
+        for index in 0..4 {
+            let (client, _) = wasm.client_connected();
+            let (native_client, _) = native.client_connected();
+            assert_eq!(client, native_client);
+            assert!(wasm.client_joined(client, 0).is_ok());
+            assert!(native.client_joined(client, 0).is_ok());
+            bots.push(Bot::random(client, RandomKeys::new(0, index)));
+        }
+        for frame in 1..=500 {
+            for bot in &mut bots {
+                let input = bot.next_input(&mut client_game).to_blob();
+                assert!(wasm.client_input(bot.client(), frame, &input).is_ok());
+                assert!(native.client_input(bot.client(), frame, &input).is_ok());
+            }
+            wasm.game_tick();
+            native.game_tick();
+            assert_eq!(wasm.world().to_blob(), native.world().to_blob(), "frame {}", frame);
+        }
+    }
+}
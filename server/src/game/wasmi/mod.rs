@@ -1,9 +1,17 @@
+pub mod runtime;
 pub mod sys;
 
+use std::cell::RefCell;
+use std::mem;
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
-use self::sys::{Handle, Module};
-use super::{DeserializeError, Game, ToBlob};
+use log::warn;
+use crate::image::{self, Image, Sprites};
+use crate::input::InputState;
+use crate::package::Package;
+use self::sys::{Handle, Module, ModuleError};
+use super::{DeserializeError, Game, Reload, Reloaded, Render, ToBlob};
 
 struct AutoHandle {
     raw: Option<Handle>,
@@ -24,19 +32,24 @@ impl Deref for AutoHandle {
     }
 }
 
+/// Contents of the buffer, which is freed.
+fn take_buffer(module: &Module, buffer: Handle) -> Vec<u8> {
+    let ptr = module.buffer_ptr(&buffer);
+    let size = module.buffer_size(&buffer);
+    let mut blob = Vec::new();
+    module.read_memory(ptr, size, &mut blob);
+    module.free_handle(buffer);
+    blob
+}
+
 pub struct World {
     handle: AutoHandle,
 }
 
 impl ToBlob for World {
     fn to_blob(&self) -> Vec<u8> {
-        let buffer_handle = self.handle.module.serialize_world(&self.handle);
-        let ptr = self.handle.module.buffer_ptr(&buffer_handle);
-        let size = self.handle.module.buffer_size(&buffer_handle);
-        let mut blob = Vec::new();
-        self.handle.module.read_memory(ptr, size, &mut blob);
-        self.handle.module.free_handle(buffer_handle);
-        blob
+        let module = &self.handle.module;
+        take_buffer(module, module.serialize_world(&self.handle))
     }
 }
 
@@ -46,13 +59,8 @@ pub struct Input {
 
 impl ToBlob for Input {
     fn to_blob(&self) -> Vec<u8> {
-        let buffer_handle = self.handle.module.serialize_input(&self.handle);
-        let ptr = self.handle.module.buffer_ptr(&buffer_handle);
-        let size = self.handle.module.buffer_size(&buffer_handle);
-        let mut blob = Vec::new();
-        self.handle.module.read_memory(ptr, size, &mut blob);
-        self.handle.module.free_handle(buffer_handle);
-        blob
+        let module = &self.handle.module;
+        take_buffer(module, module.serialize_input(&self.handle))
     }
 }
 
@@ -62,28 +70,88 @@ pub struct PlayerId {
 }
 
 impl PlayerId {
+    pub fn new(id: u32) -> PlayerId {
+        PlayerId { id }
+    }
+
     pub fn to_u32(self) -> u32 {
         self.id
     }
 }
 
-impl Into<u64> for PlayerId {
-    fn into(self) -> u64 {
-        u64::from(self.to_u32())
+impl From<PlayerId> for u64 {
+    fn from(player: PlayerId) -> u64 {
+        u64::from(player.to_u32())
     }
 }
 
+/// Player id that no player has, so that worlds are rendered like a
+/// spectator sees them.
+const SPECTATOR: u32 = u32::MAX;
+
 pub struct WasmiGame {
     next_player_id: u32,
     module: Rc<Module>,
+    /// Separate instance for rendering, created when it's first needed.
+    renderer: RefCell<Option<Rc<Module>>>,
+    sprites: Rc<Sprites>,
 }
 
 impl WasmiGame {
     pub fn new(module: sys::Module) -> Self {
+        Self::with_shared_module(Rc::new(module))
+    }
+
+    /// Create a game that shares module instance with other games. Handles
+    /// created by any of them are valid for all of them.
+    pub fn with_shared_module(module: Rc<Module>) -> Self {
         Self {
             next_player_id: 0,
-            module: Rc::new(module),
+            module,
+            renderer: RefCell::new(None),
+            sprites: Rc::default(),
+        }
+    }
+
+    /// Load sprites that rendering can draw from assets of the package.
+    pub fn load_sprites(&mut self, package: &Package) {
+        self.sprites = Rc::new(Sprites::from_assets(&package.assets));
+    }
+
+    /// Create input from state of input devices, the same way that the
+    /// client does.
+    pub fn create_input(&mut self, state: &InputState) -> Input {
+        let buffer_handle = self.copy_to_buffer(&state.to_bytes());
+        let input = self.module.create_input(&buffer_handle);
+        self.module.free_handle(buffer_handle);
+        Input {
+            handle: AutoHandle {
+                raw: Some(input),
+                module: self.module.clone(),
+            },
+        }
+    }
+
+    pub fn deserialize_world(&mut self, from: &[u8]) -> World {
+        let buffer_handle = self.copy_to_buffer(from);
+        let world = self.module.deserialize_world(&buffer_handle);
+        self.module.free_handle(buffer_handle);
+        World {
+            handle: AutoHandle {
+                raw: Some(world),
+                module: self.module.clone(),
+            },
+        }
+    }
+
+    fn copy_to_buffer(&self, from: &[u8]) -> Handle {
+        if from.len() > i32::MAX as usize {
+            panic!("buffer too large to deserialize");
         }
+        let buffer_handle = self.module.allocate_buffer(from.len() as u32);
+        let ptr = self.module.buffer_ptr(&buffer_handle);
+        self.module.write_memory(ptr, from);
+        buffer_handle
     }
 }
 
@@ -138,12 +206,7 @@ impl Game for WasmiGame {
     }
 
     fn deserialize_input(&mut self, from: &[u8]) -> Result<Input, DeserializeError> {
-        if from.len() > i32::max_value() as usize {
-            panic!("buffer too large to deserialize");
-        }
-        let buffer_handle = self.module.allocate_buffer(from.len() as u32);
-        let ptr = self.module.buffer_ptr(&buffer_handle);
-        self.module.write_memory(ptr, from);
+        let buffer_handle = self.copy_to_buffer(from);
         // FIXME: somehow communicate deserialization failure
         let input = self.module.deserialize_input(&buffer_handle);
         self.module.free_handle(buffer_handle);
@@ -160,4 +223,83 @@ impl Game for WasmiGame {
         self.next_player_id += 1;
         id
     }
+
+    fn set_random_seed(&mut self, seed: u32) {
+        self.module.set_random_seed(seed);
+    }
+
+    fn set_log_frame(&mut self, room_id: u64, frame: u64) {
+        self.module.set_log_frame(room_id, frame);
+    }
+
+    fn diff_world(&self, base: &World, world: &World) -> Option<Vec<u8>> {
+        if !self.module.has_world_diff() {
+            return None;
+        }
+        Some(take_buffer(&self.module, self.module.diff_world(&base.handle, &world.handle)))
+    }
+}
+
+impl Reload for WasmiGame {
+    type Error = ModuleError;
+
+    fn reload(&self, package: &Package, world: &World) -> Result<Reloaded<Self>, ModuleError> {
+        let blob = world.to_blob();
+        let mut game = WasmiGame {
+            next_player_id: self.next_player_id,
+            module: Rc::new(Module::from_buffer(&package.wasm_module, self.module.runtime())?),
+            renderer: RefCell::new(None),
+            sprites: Rc::default(),
+        };
+        game.load_sprites(package);
+        // New code traps if it can't read the world. Module is unusable after
+        // a trap, so in that case we start over with a fresh instance.
+        let migrated = panic::catch_unwind(AssertUnwindSafe(|| game.deserialize_world(&blob)));
+        match migrated {
+            Ok(world) => {
+                if world.to_blob() == blob {
+                    return Ok(Reloaded { game, world: Some(world) });
+                }
+                warn!("world changes after serialization roundtrip with new code");
+            }
+            Err(_) => {
+                warn!("new code failed to deserialize the world");
+            }
+        }
+        game.module = Rc::new(Module::from_buffer(&package.wasm_module, self.module.runtime())?);
+        Ok(Reloaded { game, world: None })
+    }
+}
+
+impl Render for WasmiGame {
+    // Game code that traps leaves its instance unusable, so rendering runs on
+    // a copy of the world in a separate instance, which can't break the game.
+    fn render_to_image(&self, world: &World, width: u32, height: u32) -> Result<Image, String> {
+        if width > image::MAX_SIZE || height > image::MAX_SIZE {
+            return Err(format!("image is too large: {}x{}", width, height));
+        }
+        let blob = world.to_blob();
+        let module = match self.renderer.borrow_mut().take() {
+            Some(module) => module,
+            None => Rc::new(self.module.duplicate().map_err(|e| e.to_string())?),
+        };
+        module.set_sprites(self.sprites.clone());
+        let rendered = panic::catch_unwind(AssertUnwindSafe(|| {
+            let world = WasmiGame::with_shared_module(module.clone()).deserialize_world(&blob);
+            let rendered = module.render_to_image(&world.handle, SPECTATOR, width, height);
+            if rendered.is_err() {
+                // instance is thrown away, and freeing would trap again
+                mem::forget(world);
+            }
+            rendered
+        }));
+        match rendered {
+            Ok(Ok(image)) => {
+                *self.renderer.borrow_mut() = Some(module);
+                Ok(image)
+            }
+            Ok(Err(trap)) => Err(format!("game code failed while rendering: {}", trap)),
+            Err(_) => Err("game code failed to deserialize the world for rendering".to_string()),
+        }
+    }
 }
@@ -0,0 +1,271 @@
+//! Tests that every runtime behaves the same. Tests with the primitive game
+//! need a built game package, and are ignored by default. Run them with
+//! `GAME_PACKAGE=path/to/game.zip cargo test --features jit -- --ignored`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::rc::Rc;
+use crate::game::{Game, Render, ToBlob};
+use crate::image::{Image, Sprites};
+use crate::game::wasmi::WasmiGame;
+use crate::game::wasmi::sys::Module;
+use crate::package;
+use crate::simulate::{self, Options};
+use super::{ExportKind, Instance, Runtime, Signature, ValueType};
+
+fn runtimes() -> Vec<Runtime> {
+    let mut runtimes = vec![Runtime::Interpreter];
+    if cfg!(feature = "jit") {
+        runtimes.push(Runtime::Jit);
+    }
+    runtimes
+}
+
+const TEST_MODULE: &str = r#"
+    (module
+        (import "env" "log_str" (func $log_str (param i32 i32)))
+        (import "env" "abort" (func $abort))
+        (import "env" "random_seed" (func $random_seed (result i32)))
+        (import "env" "log_message" (func $log (param i32 i32 i32)))
+        (import "env" "draw_rectangle" (func $draw_rectangle (param i32 i32 i32 i32 i32)))
+        (import "env" "fill_circle" (func $fill_circle (param i32 i32 i32 i32)))
+        (import "env" "draw_line" (func $draw_line (param i32 i32 i32 i32 i32)))
+        (import "env" "fill_polygon" (func $fill_polygon (param i32 i32 i32)))
+        (import "env" "load_sprite" (func $load_sprite (param i32 i32) (result i32)))
+        (import "env" "draw_sprite" (func $draw_sprite (param i32 i32 i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 16) "hello")
+        (data (i32.const 32) "\00\00\00\00\00\00\00\00\04\00\00\00\00\00\00\00\00\00\00\00\04\00\00\00")
+        (data (i32.const 64) "dot.png")
+        (func (export "add") (param i32 i32) (result i32)
+            (i32.add (local.get 0) (local.get 1)))
+        (func (export "load") (param i32) (result i32)
+            (i32.load (local.get 0)))
+        (func (export "log") (param i32 i32)
+            (call $log_str (local.get 0) (local.get 1)))
+        (func (export "log_level") (param i32 i32 i32)
+            (call $log (local.get 0) (local.get 1) (local.get 2)))
+        (func (export "draw") (param i32 i32 i32 i32 i32)
+            (call $draw_rectangle (local.get 0) (local.get 1) (local.get 2) (local.get 3) (local.get 4)))
+        (func (export "circle") (param i32 i32 i32 i32)
+            (call $fill_circle (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+        (func (export "line") (param i32 i32 i32 i32 i32)
+            (call $draw_line (local.get 0) (local.get 1) (local.get 2) (local.get 3) (local.get 4)))
+        (func (export "polygon") (param i32 i32 i32)
+            (call $fill_polygon (local.get 0) (local.get 1) (local.get 2)))
+        (func (export "load_sprite") (param i32 i32) (result i32)
+            (call $load_sprite (local.get 0) (local.get 1)))
+        (func (export "sprite") (param i32 i32 i32)
+            (call $draw_sprite (local.get 0) (local.get 1) (local.get 2)))
+        (func (export "abort")
+            (call $abort))
+        (func (export "seed") (result i32)
+            (call $random_seed))
+        (func (export "unreachable")
+            (unreachable))
+        (global (export "global") i32 (i32.const 0)))
+"#;
+
+fn instantiate(runtime: Runtime, source: &str) -> Result<Box<dyn Instance>, String> {
+    let buffer = wat::parse_str(source).expect("invalid test module");
+    runtime.instantiate(&buffer)
+}
+
+#[test]
+fn exports() {
+    for runtime in runtimes() {
+        let instance = instantiate(runtime, TEST_MODULE).unwrap();
+        match instance.export("add") {
+            Some(ExportKind::Function(signature)) => assert_eq!(
+                signature,
+                Signature { params: vec![ValueType::I32; 2], result: Some(ValueType::I32) },
+            ),
+            _ => panic!("{}: `add` is not a function", runtime),
+        }
+        assert!(matches!(instance.export("memory"), Some(ExportKind::Memory)));
+        assert!(matches!(instance.export("global"), Some(ExportKind::Other)));
+        assert!(instance.export("missing").is_none());
+    }
+}
+
+#[test]
+fn invoke() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        assert_eq!(instance.invoke("add", &[2, 3]).unwrap(), Some(5));
+        assert_eq!(instance.invoke("add", &[u32::MAX, 2]).unwrap(), Some(1));
+        assert_eq!(instance.invoke("log", &[64, 7]).unwrap(), None);
+    }
+}
+
+#[test]
+fn random_seed() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        assert_eq!(instance.invoke("seed", &[]).unwrap(), Some(0));
+        instance.host().set_random_seed(0xdead_beef);
+        assert_eq!(instance.invoke("seed", &[]).unwrap(), Some(0xdead_beef));
+    }
+}
+
+#[test]
+fn log_levels() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        for level in 1..=5 {
+            assert_eq!(instance.invoke("log_level", &[level, 16, 5]).unwrap(), None);
+        }
+        let err = instance.invoke("log_level", &[6, 16, 5]).unwrap_err();
+        assert!(err.0.contains("invalid level"), "{}: {}", runtime, err);
+        assert!(instance.invoke("log_level", &[1, 65530, 10]).is_err());
+    }
+}
+
+#[test]
+fn rendering() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        let err = instance.invoke("draw", &[0, 0, 1, 1, 0xffffff]).unwrap_err();
+        assert!(err.0.contains("wasm tried to render"), "{}: {}", runtime, err);
+        instance.host().start_rendering(2, 2);
+        instance.invoke("draw", &[-1i32 as u32, 1, 2, 5, 0x00ff00]).unwrap();
+        let image = instance.host().finish_rendering().unwrap();
+        let mut expected = Image::new(2, 2);
+        // colors of `draw_rectangle` are always opaque
+        expected.fill_rect(0, 1, 1, 1, 0xff00ff00);
+        assert_eq!(image, expected, "{}", runtime);
+        assert!(instance.invoke("draw", &[0, 0, 1, 1, 0xffffff]).is_err());
+    }
+}
+
+#[test]
+fn drawing() {
+    let mut sprite = Image::new(1, 2);
+    sprite.fill_rect(0, 0, 1, 1, 0xffff0000);
+    let mut assets = BTreeMap::new();
+    assets.insert("dot.png".to_string(), sprite.to_png().unwrap());
+    let sprites = Rc::new(Sprites::from_assets(&assets));
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        assert!(instance.invoke("circle", &[0, 0, 1, 0xffffffff]).is_err(), "{}", runtime);
+        instance.host().set_sprites(sprites.clone());
+        instance.host().start_rendering(8, 8);
+        instance.invoke("circle", &[2, 2, 2, 0x80ffffff]).unwrap();
+        instance.invoke("line", &[0, 7, 7, 7, 0xff0000ff]).unwrap();
+        // triangle (0, 0), (4, 0), (0, 4) from the data segment
+        instance.invoke("polygon", &[32, 3, 0xff00ff00]).unwrap();
+        let id = instance.invoke("load_sprite", &[64, 7]).unwrap().unwrap();
+        assert_eq!(instance.invoke("load_sprite", &[64, 7]).unwrap(), Some(id), "{}", runtime);
+        let missing = instance.invoke("load_sprite", &[64, 3]).unwrap().unwrap();
+        assert_ne!(missing, id, "{}", runtime);
+        instance.invoke("sprite", &[id, 6, 5]).unwrap();
+        instance.invoke("sprite", &[missing, 0, 0]).unwrap();
+        let image = instance.host().finish_rendering().unwrap();
+        let mut expected = Image::new(8, 8);
+        expected.fill_circle(2, 2, 2, 0x80ffffff);
+        expected.draw_line((0, 7), (7, 7), 0xff0000ff);
+        expected.fill_polygon(&[(0, 0), (4, 0), (0, 4)], 0xff00ff00);
+        expected.draw_image(&sprite, 6, 5);
+        assert_eq!(image, expected, "{}", runtime);
+    }
+}
+
+#[test]
+fn drawing_traps() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        instance.host().start_rendering(8, 8);
+        assert!(instance.invoke("polygon", &[65534, 3, 0]).is_err(), "{}", runtime);
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        instance.host().start_rendering(8, 8);
+        assert!(instance.invoke("polygon", &[0, 1 << 30, 0]).is_err(), "{}", runtime);
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        instance.host().start_rendering(8, 8);
+        assert!(instance.invoke("sprite", &[0, 0, 0]).is_err(), "{}", runtime);
+    }
+}
+
+#[test]
+fn memory() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        assert_eq!(instance.memory_size(), 65536);
+        let mut data = Vec::new();
+        instance.with_memory(&mut |memory| data.extend_from_slice(&memory[16..21]));
+        assert_eq!(data, b"hello");
+        instance.with_memory(&mut |memory| memory[0..4].copy_from_slice(&[1, 2, 3, 4]));
+        assert_eq!(instance.invoke("load", &[0]).unwrap(), Some(0x0403_0201));
+    }
+}
+
+#[test]
+fn traps() {
+    for runtime in runtimes() {
+        let mut instance = instantiate(runtime, TEST_MODULE).unwrap();
+        let err = instance.invoke("abort", &[]).unwrap_err();
+        assert!(err.0.contains("wasm aborted"), "{}: {}", runtime, err);
+        assert!(instance.invoke("unreachable", &[]).is_err());
+        assert!(instance.invoke("load", &[65536]).is_err());
+        assert!(instance.invoke("log", &[65530, 10]).is_err());
+    }
+}
+
+#[test]
+fn unresolved_imports() {
+    let unknown = r#"(module (import "env" "missing" (func)))"#;
+    let wrong_signature = r#"(module (import "env" "abort" (func (param i32))))"#;
+    for runtime in runtimes() {
+        assert!(instantiate(runtime, unknown).is_err());
+        assert!(instantiate(runtime, wrong_signature).is_err());
+    }
+}
+
+fn load_game(runtime: Runtime) -> Module {
+    let path = env::var("GAME_PACKAGE").expect("GAME_PACKAGE is not set");
+    let package = package::load_from_file(&path).expect("failed to load package");
+    Module::from_buffer(&package.wasm_module, runtime).expect("failed to load module")
+}
+
+#[test]
+#[ignore]
+fn primitive_game_initial_world() {
+    let worlds = runtimes()
+        .into_iter()
+        .map(|runtime| {
+            let module = Rc::new(load_game(runtime));
+            let mut game = WasmiGame::with_shared_module(module);
+            game.initial_world().to_blob()
+        })
+        .collect::<Vec<_>>();
+    assert!(worlds.windows(2).all(|pair| pair[0] == pair[1]));
+}
+
+#[test]
+#[ignore]
+fn primitive_game_simulation() {
+    let worlds = runtimes()
+        .into_iter()
+        .map(|runtime| {
+            let options = Options { players: 4, frames: 1000, seed: 7, script: None, recorder: None };
+            simulate::simulate(load_game(runtime), options).final_world
+        })
+        .collect::<Vec<_>>();
+    assert!(worlds.windows(2).all(|pair| pair[0] == pair[1]));
+}
+
+#[test]
+#[ignore]
+fn primitive_game_snapshot() {
+    for runtime in runtimes() {
+        let mut game = WasmiGame::new(load_game(runtime));
+        let world = game.initial_world();
+        let player = game.generate_player_id();
+        let world = game.add_player(&world, player);
+        let image = game.render_to_image(&world, 320, 240).unwrap();
+        // background is white, and the player is drawn over it
+        assert_eq!(image.pixel(0, 0), 0xffffffff, "{}", runtime);
+        let drawn = (0..240).any(|y| (0..320).any(|x| image.pixel(x, y) != 0xffffffff));
+        assert!(drawn, "{}: player was not drawn", runtime);
+        assert!(game.render_to_image(&world, 100_000, 1).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! Functions that game modules can import from the host. Runtimes resolve
+//! imports with `resolve` and forward calls to `Host::call`.
+
+use std::fmt;
+use std::rc::Rc;
+use log::{log, Level};
+use crate::image::{Image, Sprites};
+use super::{Signature, Trap, ValueType};
+
+pub struct Import {
+    pub module: &'static str,
+    pub name: &'static str,
+    pub params: &'static [ValueType],
+    pub result: Option<ValueType>,
+}
+
+const LOG_STR: usize = 0;
+const ABORT: usize = 1;
+const DRAW_RECTANGLE: usize = 2;
+const RANDOM_SEED: usize = 3;
+const LOG: usize = 4;
+const FILL_RECT: usize = 5;
+const FILL_CIRCLE: usize = 6;
+const DRAW_LINE: usize = 7;
+const FILL_POLYGON: usize = 8;
+const LOAD_SPRITE: usize = 9;
+const DRAW_SPRITE: usize = 10;
+
+/// Every function that host provides, indexed by the constants above.
+pub const IMPORTS: &[Import] = &[
+    Import { module: "env", name: "log_str", params: &[ValueType::I32; 2], result: None },
+    Import { module: "env", name: "abort", params: &[], result: None },
+    Import { module: "env", name: "draw_rectangle", params: &[ValueType::I32; 5], result: None },
+    Import { module: "env", name: "random_seed", params: &[], result: Some(ValueType::I32) },
+    Import { module: "env", name: "log_message", params: &[ValueType::I32; 3], result: None },
+    Import { module: "env", name: "fill_rect", params: &[ValueType::I32; 5], result: None },
+    Import { module: "env", name: "fill_circle", params: &[ValueType::I32; 4], result: None },
+    Import { module: "env", name: "draw_line", params: &[ValueType::I32; 5], result: None },
+    Import { module: "env", name: "fill_polygon", params: &[ValueType::I32; 3], result: None },
+    Import { module: "env", name: "load_sprite", params: &[ValueType::I32; 2], result: Some(ValueType::I32) },
+    Import { module: "env", name: "draw_sprite", params: &[ValueType::I32; 3], result: None },
+];
+
+/// Most points that a polygon can have, so that game code can't make the
+/// host read huge amounts of memory.
+const MAX_POLYGON_POINTS: u32 = 4096;
+
+/// Target of log records from game modules.
+const LOG_TARGET: &str = "wasm";
+
+/// Index of the host function that should be linked to given import.
+pub fn resolve(module: &str, name: &str, signature: &Signature) -> Result<usize, String> {
+    let index = IMPORTS
+        .iter()
+        .position(|import| import.module == module && import.name == name)
+        .ok_or_else(|| format!("cannot resolve function `{}::{}`", module, name))?;
+    let import = &IMPORTS[index];
+    if import.params != &signature.params[..] || import.result != signature.result {
+        return Err(format!(
+            "function `{}::{}` is imported as `{}`, but host provides `{}`",
+            module,
+            name,
+            signature,
+            Signature { params: import.params.to_vec(), result: import.result },
+        ));
+    }
+    Ok(index)
+}
+
+/// Part of module's memory that a host function was given.
+fn slice<'m>(memory: &'m [u8], ptr: u32, len: u32, what: &str) -> Result<&'m [u8], Trap> {
+    let ptr = ptr as usize;
+    ptr.checked_add(len as usize)
+        .and_then(|end| memory.get(ptr..end))
+        .ok_or_else(|| Trap(format!("wasm passed {} out of bounds", what)))
+}
+
+/// Level that `log_message` is called with, numbered like `log::Level`.
+fn level_from_wasm(level: u32) -> Option<Level> {
+    match level {
+        1 => Some(Level::Error),
+        2 => Some(Level::Warn),
+        3 => Some(Level::Info),
+        4 => Some(Level::Debug),
+        5 => Some(Level::Trace),
+        _ => None,
+    }
+}
+
+/// What the module was doing when it logged a message.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct LogContext {
+    pub room_id: u64,
+    pub frame: u64,
+    /// Export that is being executed, if any.
+    pub export: Option<&'static str>,
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "room={} frame={}", self.room_id, self.frame)?;
+        if let Some(export) = self.export {
+            write!(f, " export={}", export)?;
+        }
+        Ok(())
+    }
+}
+
+/// State of host functions for a single module instance.
+#[derive(Default)]
+pub struct Host {
+    random_seed: u32,
+    log_context: LogContext,
+    /// Image that drawing functions draw to, only set while rendering.
+    canvas: Option<Image>,
+    sprites: Rc<Sprites>,
+    /// Names of sprites that the module loaded, indexed by sprite id.
+    sprite_names: Vec<String>,
+}
+
+impl Host {
+    /// Value that `random_seed` returns to the module.
+    pub fn set_random_seed(&mut self, seed: u32) {
+        self.random_seed = seed;
+    }
+
+    /// Room and frame that messages logged by the module are tagged with.
+    pub fn set_frame(&mut self, room_id: u64, frame: u64) {
+        self.log_context.room_id = room_id;
+        self.log_context.frame = frame;
+    }
+
+    /// Export that messages logged by the module are tagged with.
+    pub fn set_export(&mut self, export: Option<&'static str>) {
+        self.log_context.export = export;
+    }
+
+    /// Drawing functions draw to a new image until `finish_rendering`.
+    /// Otherwise they trap, because game logic must not render.
+    pub fn start_rendering(&mut self, width: u32, height: u32) {
+        self.canvas = Some(Image::new(width, height));
+    }
+
+    pub fn finish_rendering(&mut self) -> Option<Image> {
+        self.canvas.take()
+    }
+
+    /// Sprites that the module can draw.
+    pub fn set_sprites(&mut self, sprites: Rc<Sprites>) {
+        self.sprites = sprites;
+    }
+
+    fn log(&self, level: Level, ptr: u32, len: u32, memory: &[u8]) -> Result<Option<u32>, Trap> {
+        let message = String::from_utf8_lossy(slice(memory, ptr, len, "log message")?);
+        log!(target: LOG_TARGET, level, "{}: {}", self.log_context, message);
+        Ok(None)
+    }
+
+    fn canvas(&mut self) -> Result<&mut Image, Trap> {
+        self.canvas.as_mut().ok_or_else(|| Trap("wasm tried to render".to_string()))
+    }
+
+    /// Id of the sprite with given name. Sprites don't have to exist, ids
+    /// of missing ones are valid, they just draw nothing.
+    fn load_sprite(&mut self, ptr: u32, len: u32, memory: &[u8]) -> Result<Option<u32>, Trap> {
+        self.canvas()?;
+        let name = String::from_utf8_lossy(slice(memory, ptr, len, "sprite name")?);
+        let id = match self.sprite_names.iter().position(|loaded| *loaded == name) {
+            Some(id) => id,
+            None => {
+                self.sprite_names.push(name.into_owned());
+                self.sprite_names.len() - 1
+            }
+        };
+        Ok(Some(id as u32))
+    }
+
+    fn draw_sprite(&mut self, id: u32, x: i32, y: i32) -> Result<Option<u32>, Trap> {
+        let name = self.sprite_names
+            .get(id as usize)
+            .ok_or_else(|| Trap(format!("wasm tried to draw invalid sprite {}", id)))?;
+        let canvas = self.canvas.as_mut().ok_or_else(|| Trap("wasm tried to render".to_string()))?;
+        if let Some(sprite) = self.sprites.get(name) {
+            canvas.draw_image(sprite, x, y);
+        }
+        Ok(None)
+    }
+
+    fn fill_polygon(&mut self, ptr: u32, points: u32, color: u32, memory: &[u8]) -> Result<Option<u32>, Trap> {
+        if points > MAX_POLYGON_POINTS {
+            return Err(Trap(format!("wasm tried to draw polygon with {} points", points)));
+        }
+        let bytes = slice(memory, ptr, points * 8, "polygon")?;
+        let coordinate = |bytes: &[u8]| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let points = bytes
+            .chunks(8)
+            .map(|point| (coordinate(&point[..4]), coordinate(&point[4..])))
+            .collect::<Vec<_>>();
+        self.canvas()?.fill_polygon(&points, color);
+        Ok(None)
+    }
+
+    /// Calls host function with given index. Arguments must match the
+    /// signature of the function, which is guaranteed by `resolve`.
+    pub fn call(&mut self, index: usize, args: &[u32], memory: &mut [u8]) -> Result<Option<u32>, Trap> {
+        match index {
+            // Modules built before `log_message` was added don't say what level
+            // their messages are.
+            LOG_STR => self.log(Level::Info, args[0], args[1], memory),
+            ABORT => Err(Trap("wasm aborted".to_string())),
+            // Colors of `draw_rectangle` don't have alpha.
+            DRAW_RECTANGLE => {
+                let color = args[4] | 0xff00_0000;
+                self.canvas()?.fill_rect(args[0] as i32, args[1] as i32, args[2], args[3], color);
+                Ok(None)
+            }
+            RANDOM_SEED => Ok(Some(self.random_seed)),
+            LOG => {
+                let level = level_from_wasm(args[0])
+                    .ok_or_else(|| Trap(format!("wasm logged with invalid level {}", args[0])))?;
+                self.log(level, args[1], args[2], memory)
+            }
+            FILL_RECT => {
+                self.canvas()?.fill_rect(args[0] as i32, args[1] as i32, args[2], args[3], args[4]);
+                Ok(None)
+            }
+            FILL_CIRCLE => {
+                self.canvas()?.fill_circle(args[0] as i32, args[1] as i32, args[2], args[3]);
+                Ok(None)
+            }
+            DRAW_LINE => {
+                let from = (args[0] as i32, args[1] as i32);
+                let to = (args[2] as i32, args[3] as i32);
+                self.canvas()?.draw_line(from, to, args[4]);
+                Ok(None)
+            }
+            FILL_POLYGON => self.fill_polygon(args[0], args[1], args[2], memory),
+            LOAD_SPRITE => self.load_sprite(args[0], args[1], memory),
+            DRAW_SPRITE => self.draw_sprite(args[0], args[1] as i32, args[2] as i32),
+            _ => Err(Trap("wasm called invalid function".to_string())),
+        }
+    }
+}
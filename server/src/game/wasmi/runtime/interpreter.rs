@@ -0,0 +1,157 @@
+//! Runtime backed by wasmi interpreter.
+
+use wasmi::{Error, MemoryRef, ModuleRef, RuntimeValue};
+use super::host::{self, Host};
+use super::{ExportKind, Instance, Signature, Trap, ValueType};
+
+struct ImportResolver;
+
+fn convert_type(ty: wasmi::ValueType) -> ValueType {
+    match ty {
+        wasmi::ValueType::I32 => ValueType::I32,
+        wasmi::ValueType::I64 => ValueType::I64,
+        wasmi::ValueType::F32 => ValueType::F32,
+        wasmi::ValueType::F64 => ValueType::F64,
+    }
+}
+
+fn convert_signature(signature: &wasmi::Signature) -> Signature {
+    Signature {
+        params: signature.params().iter().cloned().map(convert_type).collect(),
+        result: signature.return_type().map(convert_type),
+    }
+}
+
+impl wasmi::ImportResolver for ImportResolver {
+    fn resolve_func(
+        &self,
+        module_name: &str,
+        field_name: &str,
+        signature: &wasmi::Signature,
+    ) -> Result<wasmi::FuncRef, Error> {
+        let index = host::resolve(module_name, field_name, &convert_signature(signature))
+            .map_err(Error::Instantiation)?;
+        Ok(wasmi::FuncInstance::alloc_host(signature.clone(), index))
+    }
+
+    fn resolve_global(
+        &self,
+        _module_name: &str,
+        _field_name: &str,
+        _descriptor: &wasmi::GlobalDescriptor,
+    ) -> Result<wasmi::GlobalRef, Error> {
+        Err(Error::Instantiation("cannot resolve global".into()))
+    }
+
+    fn resolve_memory(
+        &self,
+        _module_name: &str,
+        _field_name: &str,
+        _descriptor: &wasmi::MemoryDescriptor,
+    ) -> Result<wasmi::MemoryRef, Error> {
+        Err(Error::Instantiation("cannot resolve memory".into()))
+    }
+
+    fn resolve_table(
+        &self,
+        _module_name: &str,
+        _field_name: &str,
+        _descriptor: &wasmi::TableDescriptor,
+    ) -> Result<wasmi::TableRef, Error> {
+        Err(Error::Instantiation("cannot resolve table".into()))
+    }
+}
+
+impl wasmi::HostError for Trap {}
+
+struct Externals<'a> {
+    memory: Option<&'a MemoryRef>,
+    host: &'a mut Host,
+}
+
+impl<'a> wasmi::Externals for Externals<'a> {
+    fn invoke_index(
+        &mut self,
+        index: usize,
+        args: wasmi::RuntimeArgs<'_>,
+    ) -> Result<Option<RuntimeValue>, wasmi::Trap> {
+        let args = args
+            .as_ref()
+            .iter()
+            .map(|arg| match arg {
+                RuntimeValue::I32(value) => *value as u32,
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        let host = &mut *self.host;
+        let result = match self.memory {
+            Some(memory) => memory.with_direct_access_mut(|memory| host.call(index, &args, memory)),
+            None => host.call(index, &args, &mut []),
+        };
+        match result {
+            Ok(value) => Ok(value.map(|value| RuntimeValue::I32(value as i32))),
+            Err(trap) => Err(wasmi::TrapKind::Host(Box::new(trap)).into()),
+        }
+    }
+}
+
+struct InterpreterInstance {
+    instance: ModuleRef,
+    memory: Option<MemoryRef>,
+    host: Host,
+}
+
+pub fn instantiate(buffer: &[u8]) -> Result<Box<dyn Instance>, String> {
+    let module = wasmi::Module::from_buffer(buffer).map_err(|e| e.to_string())?;
+    let instance = wasmi::ModuleInstance::new(&module, &ImportResolver).map_err(|e| e.to_string())?;
+    let memory = match instance.not_started_instance().export_by_name("memory") {
+        Some(wasmi::ExternVal::Memory(memory)) => Some(memory),
+        _ => None,
+    };
+    let mut host = Host::default();
+    let mut externals = Externals { memory: memory.as_ref(), host: &mut host };
+    let instance = instance.run_start(&mut externals).map_err(|e| e.to_string())?;
+    Ok(Box::new(InterpreterInstance { instance, memory, host }))
+}
+
+impl Instance for InterpreterInstance {
+    fn export(&self, name: &str) -> Option<ExportKind> {
+        match self.instance.export_by_name(name)? {
+            wasmi::ExternVal::Func(func) => Some(ExportKind::Function(convert_signature(func.signature()))),
+            wasmi::ExternVal::Memory(_) => Some(ExportKind::Memory),
+            _ => Some(ExportKind::Other),
+        }
+    }
+
+    fn invoke(&mut self, name: &str, args: &[u32]) -> Result<Option<u32>, Trap> {
+        let args = args.iter().map(|&arg| RuntimeValue::I32(arg as i32)).collect::<Vec<_>>();
+        let mut externals = Externals { memory: self.memory.as_ref(), host: &mut self.host };
+        match self.instance.invoke_export(name, &args, &mut externals) {
+            Ok(None) => Ok(None),
+            Ok(Some(RuntimeValue::I32(value))) => Ok(Some(value as u32)),
+            Ok(Some(value)) => Err(Trap(format!("`{}` returned non-i32 value {:?}", name, value))),
+            Err(e) => Err(Trap(e.to_string())),
+        }
+    }
+
+    fn with_memory(&mut self, f: &mut dyn FnMut(&mut [u8])) {
+        match &self.memory {
+            Some(memory) => memory.with_direct_access_mut(|memory| f(memory)),
+            None => f(&mut []),
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        match &self.memory {
+            Some(memory) => {
+                let wasmi::memory_units::Bytes(size) = memory.current_size().into();
+                size
+            }
+            None => 0,
+        }
+    }
+
+    fn host(&mut self) -> &mut Host {
+        &mut self.host
+    }
+}
@@ -0,0 +1,118 @@
+//! Runtime backed by wasmtime, which compiles modules to native code.
+
+use wasmtime::{Caller, Config, Engine, Extern, ExternType, Linker, Memory, Store, Val, ValType};
+use super::host::{self, Host};
+use super::{ExportKind, Instance, Signature, Trap, ValueType};
+
+fn convert_type(ty: &ValType) -> Result<ValueType, String> {
+    match ty {
+        ValType::I32 => Ok(ValueType::I32),
+        ValType::I64 => Ok(ValueType::I64),
+        ValType::F32 => Ok(ValueType::F32),
+        ValType::F64 => Ok(ValueType::F64),
+        other => Err(format!("unsupported value type {}", other)),
+    }
+}
+
+fn convert_signature(ty: &wasmtime::FuncType) -> Result<Signature, String> {
+    let params = ty.params().map(|ty| convert_type(&ty)).collect::<Result<_, _>>()?;
+    let mut results = ty.results();
+    let result = match (results.next(), results.next()) {
+        (None, _) => None,
+        (Some(ty), None) => Some(convert_type(&ty)?),
+        (Some(_), Some(_)) => return Err("multiple return values are not supported".to_string()),
+    };
+    Ok(Signature { params, result })
+}
+
+fn call_host(mut caller: Caller<'_, Host>, index: usize, params: &[Val], results: &mut [Val]) -> wasmtime::Result<()> {
+    let args = params.iter().map(|param| param.unwrap_i32() as u32).collect::<Vec<_>>();
+    let result = match caller.get_export("memory").and_then(Extern::into_memory) {
+        Some(memory) => {
+            let (memory, host) = memory.data_and_store_mut(&mut caller);
+            host.call(index, &args, memory)
+        }
+        None => caller.data_mut().call(index, &args, &mut []),
+    };
+    match result {
+        Ok(Some(value)) => results[0] = Val::I32(value as i32),
+        Ok(None) => {}
+        Err(trap) => return Err(wasmtime::Error::msg(trap.0)),
+    }
+    Ok(())
+}
+
+struct JitInstance {
+    module: wasmtime::Module,
+    store: Store<Host>,
+    instance: wasmtime::Instance,
+    memory: Option<Memory>,
+}
+
+pub fn instantiate(buffer: &[u8]) -> Result<Box<dyn Instance>, String> {
+    let mut config = Config::new();
+    // Game state must not depend on the machine that runs the game.
+    config.cranelift_nan_canonicalization(true);
+    let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+    let module = wasmtime::Module::new(&engine, buffer).map_err(|e| e.to_string())?;
+    let mut linker = Linker::new(&engine);
+    for import in module.imports() {
+        let ty = match import.ty() {
+            ExternType::Func(ty) => ty,
+            _ => return Err(format!("cannot resolve `{}::{}`", import.module(), import.name())),
+        };
+        let index = host::resolve(import.module(), import.name(), &convert_signature(&ty)?)?;
+        linker
+            .func_new(import.module(), import.name(), ty, move |caller, params, results| {
+                call_host(caller, index, params, results)
+            })
+            .map_err(|e| e.to_string())?;
+    }
+    let mut store = Store::new(&engine, Host::default());
+    let instance = linker.instantiate(&mut store, &module).map_err(|e| e.to_string())?;
+    let memory = instance.get_memory(&mut store, "memory");
+    Ok(Box::new(JitInstance { module, store, instance, memory }))
+}
+
+impl Instance for JitInstance {
+    fn export(&self, name: &str) -> Option<ExportKind> {
+        match self.module.get_export(name)? {
+            ExternType::Func(ty) => match convert_signature(&ty) {
+                Ok(signature) => Some(ExportKind::Function(signature)),
+                Err(_) => Some(ExportKind::Other),
+            },
+            ExternType::Memory(_) => Some(ExportKind::Memory),
+            _ => Some(ExportKind::Other),
+        }
+    }
+
+    fn invoke(&mut self, name: &str, args: &[u32]) -> Result<Option<u32>, Trap> {
+        let func = self
+            .instance
+            .get_func(&mut self.store, name)
+            .ok_or_else(|| Trap(format!("module does not export function `{}`", name)))?;
+        let params = args.iter().map(|&arg| Val::I32(arg as i32)).collect::<Vec<_>>();
+        let mut results = vec![Val::I32(0); func.ty(&self.store).results().len()];
+        func.call(&mut self.store, &params, &mut results).map_err(|e| Trap(format!("{:#}", e)))?;
+        match results.first() {
+            None => Ok(None),
+            Some(Val::I32(value)) => Ok(Some(*value as u32)),
+            Some(value) => Err(Trap(format!("`{}` returned non-i32 value {:?}", name, value))),
+        }
+    }
+
+    fn with_memory(&mut self, f: &mut dyn FnMut(&mut [u8])) {
+        match self.memory {
+            Some(memory) => f(memory.data_mut(&mut self.store)),
+            None => f(&mut []),
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        self.memory.map_or(0, |memory| memory.data_size(&self.store))
+    }
+
+    fn host(&mut self) -> &mut Host {
+        self.store.data_mut()
+    }
+}
@@ -0,0 +1,125 @@
+//! Wasm engines that can run game modules. Every engine links modules against
+//! functions from `host`, and exposes exports and linear memory of the
+//! instance through the `Instance` trait, so that `sys::Module` does not
+//! depend on a particular engine.
+
+#[cfg(test)]
+mod conformance;
+pub mod host;
+mod interpreter;
+#[cfg(feature = "jit")]
+mod jit;
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ValueType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Signature {
+    pub params: Vec<ValueType>,
+    pub result: Option<ValueType>,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show_type(ty: ValueType) -> &'static str {
+            match ty {
+                ValueType::I32 => "i32",
+                ValueType::I64 => "i64",
+                ValueType::F32 => "f32",
+                ValueType::F64 => "f64",
+            }
+        }
+        let params = self.params.iter().map(|&ty| show_type(ty)).collect::<Vec<_>>();
+        match self.result {
+            Some(result) => write!(f, "fn({}) -> {}", params.join(", "), show_type(result)),
+            None => write!(f, "fn({})", params.join(", ")),
+        }
+    }
+}
+
+pub enum ExportKind {
+    Function(Signature),
+    Memory,
+    Other,
+}
+
+/// Error raised while executing wasm code: either a trap in the module
+/// itself, or a failure in one of the host functions it called.
+#[derive(Debug, Clone)]
+pub struct Trap(pub String);
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// Instantiated module. All values are passed as `u32`, because game modules
+/// only use `i32` in their interface.
+pub trait Instance {
+    fn export(&self, name: &str) -> Option<ExportKind>;
+
+    fn invoke(&mut self, name: &str, args: &[u32]) -> Result<Option<u32>, Trap>;
+
+    /// Gives access to linear memory exported as `memory`.
+    fn with_memory(&mut self, f: &mut dyn FnMut(&mut [u8]));
+
+    /// Current size of memory exported as `memory` in bytes.
+    fn memory_size(&self) -> usize;
+
+    /// State of host functions that this instance calls.
+    fn host(&mut self) -> &mut host::Host;
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Copy, Clone)]
+pub enum Runtime {
+    /// wasmi interpreter.
+    #[default]
+    Interpreter,
+    /// wasmtime compiler, only available with `jit` feature.
+    Jit,
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Runtime, String> {
+        match s {
+            "interpreter" => Ok(Runtime::Interpreter),
+            "jit" => Ok(Runtime::Jit),
+            _ => Err(format!("unknown runtime `{}`, expected `interpreter` or `jit`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Runtime::Interpreter => write!(f, "interpreter"),
+            Runtime::Jit => write!(f, "jit"),
+        }
+    }
+}
+
+impl Runtime {
+    /// Compiles, links and starts the module. Exports are not checked.
+    pub fn instantiate(self, buffer: &[u8]) -> Result<Box<dyn Instance>, String> {
+        match self {
+            Runtime::Interpreter => interpreter::instantiate(buffer),
+            #[cfg(feature = "jit")]
+            Runtime::Jit => jit::instantiate(buffer),
+            #[cfg(not(feature = "jit"))]
+            Runtime::Jit => Err("server was built without `jit` feature".to_string()),
+        }
+    }
+}
@@ -2,8 +2,15 @@
 //! module, except for ownership - handles are taken by reference where
 //! corresponding wasm functions don't take ownership of the passed handle.
 
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use log::trace;
-use wasmi::{Error, ValueType};
+use wasmi::Error;
+use crate::image::{Image, Sprites};
+use super::runtime::{ExportKind, Instance, Runtime, Signature, Trap, ValueType};
 
 #[derive(Debug)]
 pub struct Handle {
@@ -11,43 +18,39 @@ pub struct Handle {
 }
 
 trait AsWasmValue {
-    fn as_wasm_value(&self) -> wasmi::RuntimeValue;
+    fn as_wasm_value(&self) -> u32;
 }
 
 impl AsWasmValue for Handle {
-    fn as_wasm_value(&self) -> wasmi::RuntimeValue {
-        wasmi::RuntimeValue::from(self.ptr)
+    fn as_wasm_value(&self) -> u32 {
+        self.ptr
     }
 }
 
 impl AsWasmValue for u32 {
-    fn as_wasm_value(&self) -> wasmi::RuntimeValue {
-        wasmi::RuntimeValue::from(*self)
+    fn as_wasm_value(&self) -> u32 {
+        *self
     }
 }
 
 trait FromWasmValue: Sized {
-    fn from_wasm_value(value: Option<wasmi::RuntimeValue>) -> Option<Self>;
+    fn from_wasm_value(value: Option<u32>) -> Option<Self>;
 }
 
 impl FromWasmValue for Handle {
-    fn from_wasm_value(value: Option<wasmi::RuntimeValue>) -> Option<Self> {
-        u32::from_wasm_value(value).map(|ptr| Handle { ptr })
+    fn from_wasm_value(value: Option<u32>) -> Option<Self> {
+        value.map(|ptr| Handle { ptr })
     }
 }
 
 impl FromWasmValue for u32 {
-    fn from_wasm_value(value: Option<wasmi::RuntimeValue>) -> Option<Self> {
-        if let Some(wasmi::RuntimeValue::I32(value)) = value {
-            Some(value as u32)
-        } else {
-            None
-        }
+    fn from_wasm_value(value: Option<u32>) -> Option<Self> {
+        value
     }
 }
 
 impl FromWasmValue for () {
-    fn from_wasm_value(value: Option<wasmi::RuntimeValue>) -> Option<Self> {
+    fn from_wasm_value(value: Option<u32>) -> Option<Self> {
         if value.is_none() {
             Some(())
         } else {
@@ -56,18 +59,196 @@ impl FromWasmValue for () {
     }
 }
 
+/// Signature of a function that game modules must export.
+pub struct Export {
+    pub name: &'static str,
+    pub params: &'static [ValueType],
+    pub result: Option<ValueType>,
+}
+
+/// Functions that a game module must export. Server only calls some of them,
+/// but the same module is also served to clients, so it is rejected if any of
+/// these are missing.
+pub const REQUIRED_EXPORTS: &[Export] = &[
+    Export { name: "initialize", params: &[], result: None },
+    Export { name: "initial_world", params: &[], result: Some(ValueType::I32) },
+    Export { name: "update_world", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "update_player", params: &[ValueType::I32; 3], result: Some(ValueType::I32) },
+    Export { name: "add_player", params: &[ValueType::I32; 2], result: Some(ValueType::I32) },
+    Export { name: "remove_player", params: &[ValueType::I32; 2], result: Some(ValueType::I32) },
+    Export { name: "allocate_buffer", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "free_handle", params: &[ValueType::I32], result: None },
+    Export { name: "buffer_ptr", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "buffer_size", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "deserialize_world", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "serialize_world", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "deserialize_input", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "serialize_input", params: &[ValueType::I32], result: Some(ValueType::I32) },
+    Export { name: "create_input", params: &[ValueType::I32; 1], result: Some(ValueType::I32) },
+    Export { name: "render", params: &[ValueType::I32; 4], result: None },
+];
+
+/// Functions that a game module may export, both or neither of them. Game
+/// has its own format for differences between worlds if it exports them:
+/// `diff_world(base, world)` returns a buffer with the difference, and
+/// `apply_world_diff(base, buffer)` returns the world.
+pub const WORLD_DIFF_EXPORTS: &[Export] = &[
+    Export { name: "diff_world", params: &[ValueType::I32; 2], result: Some(ValueType::I32) },
+    Export { name: "apply_world_diff", params: &[ValueType::I32; 2], result: Some(ValueType::I32) },
+];
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const WASM_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum ModuleError {
+    /// Buffer does not start with wasm magic number.
+    BadMagic,
+    /// Module is encoded with a binary format version that we can't load.
+    UnsupportedVersion(u32),
+    /// Module failed to parse or validate.
+    Wasm(Error),
+    /// Runtime failed to compile, link or start the module.
+    Instantiation(String),
+    /// Module does not export linear memory as `memory`.
+    MissingMemory,
+    MissingExport(&'static str),
+    NotAFunction(&'static str),
+    SignatureMismatch {
+        name: &'static str,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::BadMagic => {
+                write!(f, "not a wasm module (bad magic number)")
+            }
+            ModuleError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wasm binary version {}, expected {}", version, WASM_VERSION)
+            }
+            ModuleError::Wasm(err) => write!(f, "{}", err),
+            ModuleError::Instantiation(err) => write!(f, "{}", err),
+            ModuleError::MissingMemory => {
+                write!(f, "module does not export its memory as `memory`")
+            }
+            ModuleError::MissingExport(name) => {
+                write!(f, "module does not export function `{}`", name)
+            }
+            ModuleError::NotAFunction(name) => {
+                write!(f, "export `{}` is not a function", name)
+            }
+            ModuleError::SignatureMismatch { name, expected, found } => {
+                write!(f, "export `{}` has signature `{}`, expected `{}`", name, found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+impl From<Error> for ModuleError {
+    fn from(err: Error) -> ModuleError {
+        ModuleError::Wasm(err)
+    }
+}
+
+/// Checks the signature of the export, returns `false` if it is missing.
+fn check_export(instance: &dyn Instance, export: &Export) -> Result<bool, ModuleError> {
+    let signature = match instance.export(export.name) {
+        Some(ExportKind::Function(signature)) => signature,
+        Some(_) => return Err(ModuleError::NotAFunction(export.name)),
+        None => return Ok(false),
+    };
+    let expected = Signature {
+        params: export.params.to_vec(),
+        result: export.result,
+    };
+    if signature != expected {
+        return Err(ModuleError::SignatureMismatch {
+            name: export.name,
+            expected: expected.to_string(),
+            found: signature.to_string(),
+        });
+    }
+    Ok(true)
+}
+
+fn check_exports(instance: &dyn Instance) -> Result<(), ModuleError> {
+    for export in REQUIRED_EXPORTS {
+        if !check_export(instance, export)? {
+            return Err(ModuleError::MissingExport(export.name));
+        }
+    }
+    let mut world_diff = Vec::new();
+    for export in WORLD_DIFF_EXPORTS {
+        world_diff.push((export.name, check_export(instance, export)?));
+    }
+    if let Some(&(missing, _)) = world_diff.iter().find(|(_, found)| !found) {
+        if world_diff.iter().any(|(_, found)| *found) {
+            return Err(ModuleError::MissingExport(missing));
+        }
+    }
+    match instance.export("memory") {
+        Some(ExportKind::Memory) => Ok(()),
+        _ => Err(ModuleError::MissingMemory),
+    }
+}
+
+/// Parses and instantiates the module with given runtime, and checks that it
+/// provides every export listed in `REQUIRED_EXPORTS`. Module is not
+/// initialized.
+fn instantiate(buffer: &[u8], runtime: Runtime) -> Result<Box<dyn Instance>, ModuleError> {
+    if buffer.len() < 8 || &buffer[..4] != WASM_MAGIC {
+        return Err(ModuleError::BadMagic);
+    }
+    let version = u32::from(buffer[4])
+        | (u32::from(buffer[5]) << 8)
+        | (u32::from(buffer[6]) << 16)
+        | (u32::from(buffer[7]) << 24);
+    if version != WASM_VERSION {
+        return Err(ModuleError::UnsupportedVersion(version));
+    }
+    // Validation rules are the same for every runtime, so that a package that
+    // is accepted by one of them is accepted by all.
+    let module = wasmi::Module::from_buffer(buffer)?;
+    module.deny_floating_point()?;
+    let instance = runtime.instantiate(buffer).map_err(ModuleError::Instantiation)?;
+    check_exports(&*instance)?;
+    Ok(instance)
+}
+
+/// Checks that the buffer contains a wasm module that could be loaded as a
+/// game, without running any of its code.
+pub fn validate(buffer: &[u8]) -> Result<(), ModuleError> {
+    instantiate(buffer, Runtime::default()).map(|_| ())
+}
+
+/// Time spent executing a single export.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CallStats {
+    pub calls: u64,
+    pub total_time: Duration,
+}
+
 pub struct Module {
-    instance: wasmi::ModuleRef,
-    memory: wasmi::MemoryRef,
+    instance: RefCell<Box<dyn Instance>>,
+    world_diff: bool,
+    code: Vec<u8>,
+    runtime: Runtime,
+    profiling: Cell<bool>,
+    call_stats: RefCell<BTreeMap<&'static str, CallStats>>,
 }
 
 macro_rules! call {
-    ($memory:expr, $instance:expr, $name:ident ($($arg:expr),*) as $return_ty:ty) => {{
+    ($module:expr, $name:ident ($($arg:expr),*) as $return_ty:ty) => {{
         trace!(concat!("calling wasm: ", stringify!($name)));
-        let wasm_value = $instance.invoke_export(
+        let wasm_value = $module.invoke_export(
                 stringify!($name),
                 &[$($arg.as_wasm_value(),)*],
-                &mut Externals($memory.clone()),
             )
             .expect(concat!("failed to execute `", stringify!($name), "`"));
         <$return_ty as FromWasmValue>::from_wasm_value(wasm_value)
@@ -82,176 +263,153 @@ macro_rules! call {
     }}
 }
 
-struct ImportResolver;
-
-fn is_log_signature(signature: &wasmi::Signature) -> bool {
-    signature.return_type().is_none() && signature.params() == &[ValueType::I32; 2]
-}
+impl Module {
+    pub fn from_buffer(buffer: &[u8], runtime: Runtime) -> Result<Module, ModuleError> {
+        let instance = instantiate(buffer, runtime)?;
+        let module = Module {
+            world_diff: instance.export(WORLD_DIFF_EXPORTS[0].name).is_some(),
+            instance: RefCell::new(instance),
+            code: buffer.to_vec(),
+            runtime,
+            profiling: Cell::new(false),
+            call_stats: RefCell::new(BTreeMap::new()),
+        };
+        call!(module, initialize() as ());
+        Ok(module)
+    }
 
-fn is_abort_signature(signature: &wasmi::Signature) -> bool {
-    signature.return_type().is_none() && signature.params() == &[]
-}
+    /// New instance of the same code, with its own memory.
+    pub fn duplicate(&self) -> Result<Module, ModuleError> {
+        Module::from_buffer(&self.code, self.runtime)
+    }
 
-fn is_draw_signature(signature: &wasmi::Signature) -> bool {
-    signature.return_type().is_none() && signature.params() == &[ValueType::I32; 5]
-}
+    /// Runtime that executes the module.
+    pub fn runtime(&self) -> Runtime {
+        self.runtime
+    }
 
-impl wasmi::ImportResolver for ImportResolver {
-    fn resolve_func(
-        &self,
-        module_name: &str,
-        field_name: &str,
-        signature: &wasmi::Signature,
-    ) -> Result<wasmi::FuncRef, Error> {
-        match (module_name, field_name) {
-            ("env", "log_str") if is_log_signature(signature) => {
-                Ok(wasmi::FuncInstance::alloc_host(signature.clone(), 0))
-            }
-            ("env", "abort") if is_abort_signature(signature) => {
-                Ok(wasmi::FuncInstance::alloc_host(signature.clone(), 1))
-            }
-            ("env", "draw_rectangle") if is_draw_signature(signature) => {
-                Ok(wasmi::FuncInstance::alloc_host(signature.clone(), 2))
-            }
-            _ => Err(Error::Instantiation("cannot resolve function".into())),
+    fn invoke_export(&self, name: &'static str, args: &[u32]) -> Result<Option<u32>, Trap> {
+        let mut instance = self.instance.borrow_mut();
+        instance.host().set_export(Some(name));
+        if !self.profiling.get() {
+            let result = instance.invoke(name, args);
+            instance.host().set_export(None);
+            return result;
         }
+        let start = Instant::now();
+        let result = instance.invoke(name, args);
+        let elapsed = start.elapsed();
+        instance.host().set_export(None);
+        let mut call_stats = self.call_stats.borrow_mut();
+        let stats = call_stats.entry(name).or_default();
+        stats.calls += 1;
+        stats.total_time += elapsed;
+        result
     }
 
-    fn resolve_global(
-        &self,
-        _module_name: &str,
-        _field_name: &str,
-        _descriptor: &wasmi::GlobalDescriptor,
-    ) -> Result<wasmi::GlobalRef, Error> {
-        Err(Error::Instantiation("cannot resolve global".into()))
+    /// Seed that the module gets from `random_seed` import.
+    pub fn set_random_seed(&self, seed: u32) {
+        self.instance.borrow_mut().host().set_random_seed(seed);
     }
 
-    fn resolve_memory(
-        &self,
-        _module_name: &str,
-        _field_name: &str,
-        _descriptor: &wasmi::MemoryDescriptor,
-    ) -> Result<wasmi::MemoryRef, Error> {
-        Err(Error::Instantiation("cannot resolve memory".into()))
+    /// Sprites that the module draws when rendering to an image.
+    pub fn set_sprites(&self, sprites: Rc<Sprites>) {
+        self.instance.borrow_mut().host().set_sprites(sprites);
     }
 
-    fn resolve_table(
-        &self,
-        _module_name: &str,
-        _field_name: &str,
-        _descriptor: &wasmi::TableDescriptor,
-    ) -> Result<wasmi::TableRef, Error> {
-        Err(Error::Instantiation("cannot resolve table".into()))
+    /// Room and frame that messages logged by the module are tagged with.
+    pub fn set_log_frame(&self, room_id: u64, frame: u64) {
+        self.instance.borrow_mut().host().set_frame(room_id, frame);
     }
-}
-
-struct Externals(wasmi::MemoryRef);
-
-#[derive(Debug, Copy, Clone)]
-struct RuntimeError(&'static str);
 
-impl std::fmt::Display for RuntimeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RuntimeError({})", self.0)
+    /// Start or stop measuring time spent in each export.
+    pub fn set_profiling(&self, enabled: bool) {
+        self.profiling.set(enabled);
     }
-}
 
-impl wasmi::HostError for RuntimeError {}
-
-impl wasmi::Externals for Externals {
-    fn invoke_index(
-        &mut self,
-        index: usize,
-        args: wasmi::RuntimeArgs<'_>,
-    ) -> Result<Option<wasmi::RuntimeValue>, wasmi::Trap> {
-        match index {
-            0 => {
-                let ptr: u32 = args.nth(0);
-                let len: u32 = args.nth(1);
-                self.0.with_direct_access(|slice| {
-                    let subslice = &slice[(ptr as usize)..((ptr + len) as usize)];
-                    let message = String::from_utf8_lossy(subslice);
-                    trace!("message from wasm: {}", message);
-                });
-                Ok(None)
-            }
-            1 => {
-                Err(wasmi::TrapKind::Host(Box::new(RuntimeError("wasm aborted"))).into())
-            }
-            2 => {
-                Err(wasmi::TrapKind::Host(Box::new(RuntimeError("wasm tried to render"))).into())
-            }
-            _ => {
-                Err(wasmi::TrapKind::Host(Box::new(RuntimeError("wasm called invalid function"))).into())
-            }
-        }
+    /// Time spent in each export while profiling was enabled.
+    pub fn call_stats(&self) -> BTreeMap<&'static str, CallStats> {
+        self.call_stats.borrow().clone()
     }
-}
 
-impl Module {
-    pub fn from_buffer(buffer: &[u8]) -> Result<Module, Error> {
-        let module = wasmi::Module::from_buffer(buffer)?;
-        module.deny_floating_point()?;
-        let instance =
-            wasmi::ModuleInstance::new(&module, &ImportResolver)?
-            .assert_no_start();
-        let memory_export = instance.export_by_name("memory")
-            .expect("module does not export memory");
-        let memory = if let wasmi::ExternVal::Memory(memory) = memory_export {
-            memory
-        } else {
-            panic!("`memory` export is not memory");
-        };
-        call!(memory, instance, initialize() as ());
-        Ok(Module { instance, memory })
+    /// Current size of module's linear memory in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.instance.borrow().memory_size()
     }
 
     pub fn initial_world(&self) -> Handle {
-        call!(self.memory, self.instance, initial_world() as Handle)
+        call!(self, initial_world() as Handle)
     }
 
     pub fn update_world(&self, world: &Handle) -> Handle {
-        call!(self.memory, self.instance, update_world(world) as Handle)
+        call!(self, update_world(world) as Handle)
     }
 
     pub fn update_player(&self, world: &Handle, player_id: u32, input: &Handle) -> Handle {
-        call!(self.memory, self.instance, update_player(world, player_id, input) as Handle)
+        call!(self, update_player(world, player_id, input) as Handle)
     }
 
     pub fn add_player(&self, world: &Handle, player_id: u32) -> Handle {
-        call!(self.memory, self.instance, add_player(world, player_id) as Handle)
+        call!(self, add_player(world, player_id) as Handle)
     }
 
     pub fn remove_player(&self, world: &Handle, player_id: u32) -> Handle {
-        call!(self.memory, self.instance, remove_player(world, player_id) as Handle)
+        call!(self, remove_player(world, player_id) as Handle)
     }
 
     pub fn allocate_buffer(&self, size: u32) -> Handle {
-        call!(self.memory, self.instance, allocate_buffer(size) as Handle)
+        call!(self, allocate_buffer(size) as Handle)
     }
 
     pub fn free_handle(&self, handle: Handle) {
-        call!(self.memory, self.instance, free_handle(handle) as ())
+        call!(self, free_handle(handle) as ())
     }
 
     pub fn buffer_ptr(&self, buffer: &Handle) -> u32 {
-        call!(self.memory, self.instance, buffer_ptr(buffer) as u32)
+        call!(self, buffer_ptr(buffer) as u32)
     }
 
     pub fn buffer_size(&self, buffer: &Handle) -> u32 {
-        call!(self.memory, self.instance, buffer_size(buffer) as u32)
+        call!(self, buffer_size(buffer) as u32)
     }
 
     pub fn serialize_world(&self, world: &Handle) -> Handle {
-        call!(self.memory, self.instance, serialize_world(world) as Handle)
+        call!(self, serialize_world(world) as Handle)
+    }
+
+    pub fn deserialize_world(&self, buffer: &Handle) -> Handle {
+        call!(self, deserialize_world(buffer) as Handle)
     }
 
     pub fn deserialize_input(&self, buffer: &Handle) -> Handle {
-        call!(self.memory, self.instance, deserialize_input(buffer) as Handle)
+        call!(self, deserialize_input(buffer) as Handle)
     }
 
     pub fn serialize_input(&self, input: &Handle) -> Handle {
-        call!(self.memory, self.instance, serialize_input(input) as Handle)
+        call!(self, serialize_input(input) as Handle)
+    }
+
+    pub fn create_input(&self, input_state: &Handle) -> Handle {
+        call!(self, create_input(input_state) as Handle)
+    }
+
+    /// Whether the module exports `WORLD_DIFF_EXPORTS`.
+    pub fn has_world_diff(&self) -> bool {
+        self.world_diff
+    }
+
+    pub fn diff_world(&self, base: &Handle, world: &Handle) -> Handle {
+        call!(self, diff_world(base, world) as Handle)
+    }
+
+    /// Draws the world like `player` sees it. Drawing functions are only
+    /// available to the module during this call.
+    pub fn render_to_image(&self, world: &Handle, player: u32, width: u32, height: u32) -> Result<Image, Trap> {
+        self.instance.borrow_mut().host().start_rendering(width, height);
+        let result = self.invoke_export("render", &[world.as_wasm_value(), player, width, height]);
+        let image = self.instance.borrow_mut().host().finish_rendering();
+        result?;
+        Ok(image.expect("canvas disappeared while rendering"))
     }
 
     pub fn write_memory(&self, ptr: u32, data: &[u8]) {
@@ -270,6 +428,11 @@ impl Module {
     }
 
     fn with_memory<R, F: FnOnce(&mut [u8]) -> R>(&self, f: F) -> R {
-        self.memory.with_direct_access_mut(|memory| f(memory))
+        let mut f = Some(f);
+        let mut result = None;
+        self.instance.borrow_mut().with_memory(&mut |memory| {
+            result = f.take().map(|f| f(memory));
+        });
+        result.expect("runtime did not provide memory access")
     }
 }
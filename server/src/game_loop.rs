@@ -1,44 +1,196 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use std::thread;
-use log::trace;
+use log::{info, trace, warn};
 use crate::server::{Server, ClientId};
-use crate::game::{Game, ToBlob};
-use crate::network::{ConnectionId, Event, Message, WebsocketServer};
+use crate::delta;
+use crate::game::{Reload, Render, ToBlob};
+use crate::latency::Latency;
+use crate::rate_limit::RateLimit;
+use crate::network::{ConnectionId, Event, Message, SnapshotError, SnapshotRequest, WebsocketServer};
 use crate::protocol;
+use crate::recording::Recorder;
+use crate::send_queue::{SendQueue, SlowClientPolicy};
+use crate::watch::PackageWatcher;
 
-pub struct GameLoop<G: Game> {
+const FRAMES_PER_SECOND: u64 = 60;
+const FRAME_TIME: Duration = Duration::from_micros(1_000_000 / FRAMES_PER_SECOND);
+
+/// How often network statistics are logged.
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often round trip time to each connection is measured.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients send an input each frame and answer pings, and might send a few
+/// dozen inputs at once when they are advised to send them earlier.
+const MESSAGES_PER_SECOND: u32 = 2 * FRAMES_PER_SECOND as u32;
+const MESSAGE_BURST: u32 = 3 * FRAMES_PER_SECOND as u32;
+
+/// Clients can fall this many frame updates behind by default, which is a
+/// few seconds.
+const DEFAULT_SEND_QUEUE_LIMIT: u64 = 5 * FRAMES_PER_SECOND;
+
+pub struct GameLoop<G: Reload + Render> {
     network_server: WebsocketServer,
     game_server: Server<G>,
     clients: HashMap<ConnectionId, ClientId>,
+    connections: HashMap<ConnectionId, Connection>,
+    /// When the last frame was supposed to be simulated, it might have been
+    /// simulated later if the server is running behind.
+    last_frame_time: Instant,
+    watcher: Option<PackageWatcher>,
+    recorder: Option<Recorder>,
+    /// Message with the server's current keyframe, which is the same for
+    /// every client, so it is only serialized once.
+    keyframe: Option<KeyframeMessage>,
+    send_queue_limit: u64,
+    slow_client_policy: SlowClientPolicy,
 }
 
-impl<G: Game> GameLoop<G> {
+struct Connection {
+    latency: Latency,
+    rate_limit: RateLimit,
+    send_queue: SendQueue,
+    /// Bytes that were sent to the connection before the latest ping.
+    pinged_bytes: u64,
+    /// Frame of the keyframe that the client holds, it is sent only with
+    /// the first delta from it.
+    keyframe: Option<u64>,
+}
+
+struct KeyframeMessage {
+    frame: u64,
+    blob: Vec<u8>,
+    message: Message,
+}
+
+impl<G: Reload + Render> GameLoop<G> {
     pub fn new(network_server: WebsocketServer, game_server: Server<G>) -> Self {
         GameLoop {
             network_server,
             game_server,
             clients: HashMap::new(),
+            connections: HashMap::new(),
+            last_frame_time: Instant::now(),
+            watcher: None,
+            recorder: None,
+            keyframe: None,
+            send_queue_limit: DEFAULT_SEND_QUEUE_LIMIT,
+            slow_client_policy: SlowClientPolicy::Disconnect,
+        }
+    }
+
+    /// Apply `policy` to clients that have more than `limit` frame updates
+    /// sent to them that they haven't applied yet.
+    pub fn limit_send_queue(&mut self, limit: u64, policy: SlowClientPolicy) {
+        self.send_queue_limit = limit;
+        self.slow_client_policy = policy;
+    }
+
+    /// Reload the game whenever the package changes.
+    pub fn watch(&mut self, watcher: PackageWatcher) {
+        self.watcher = Some(watcher);
+    }
+
+    /// Record every frame from now on.
+    pub fn record(&mut self, mut recorder: Recorder) {
+        let world = self.game_server.world().to_blob();
+        match recorder.record_start(self.game_server.frame(), &world) {
+            Ok(()) => self.recorder = Some(recorder),
+            Err(e) => warn!("failed to write recording: {}", e),
         }
     }
 
     pub fn run(&mut self) {
-        let mut last_frame_time = Instant::now();
-        let frames_per_second = 60;
-        let frame_time = Duration::from_micros(1_000_000 / frames_per_second);
+        self.last_frame_time = Instant::now();
+        let mut last_stats_time = Instant::now();
+        let mut last_ping_time = Instant::now();
 
         loop {
             self.process_network_events();
+            self.reload_if_changed();
+            if last_ping_time.elapsed() >= PING_INTERVAL {
+                self.send_pings();
+                last_ping_time = Instant::now();
+            }
+            if last_stats_time.elapsed() >= STATS_INTERVAL {
+                self.log_stats();
+                last_stats_time = Instant::now();
+            }
             let current_time = Instant::now();
-            let next_frame_time = last_frame_time + frame_time;
+            let next_frame_time = self.last_frame_time + FRAME_TIME;
             if next_frame_time > current_time {
                 thread::sleep(next_frame_time - current_time);
             } else {
                 self.game_tick();
-                last_frame_time += frame_time;
+                self.last_frame_time += FRAME_TIME;
+            }
+        }
+    }
+
+    fn log_stats(&mut self) {
+        info!("network: {}", self.network_server.stats());
+        let (clients, game_server) = (&self.clients, &self.game_server);
+        let frame = game_server.frame();
+        for (id, connection) in &mut self.connections {
+            let latency = &connection.latency;
+            let queue = &mut connection.send_queue;
+            let queue = format!(
+                "{} updates queued{}, peak {}",
+                queue.depth(frame),
+                if queue.is_paused() { " (paused)" } else { "" },
+                queue.take_peak(),
+            );
+            let client = clients.get(id).and_then(|&client| game_server.client_stats(client));
+            match client {
+                Some(stats) => info!("connection {:?}: {}, {}, inputs: {}", id, latency, queue, stats),
+                None => info!("connection {:?}: {}, {}", id, latency, queue),
             }
         }
     }
+
+    fn send_pings(&mut self) {
+        let now = Instant::now();
+        for (&id, connection) in &mut self.connections {
+            // answering the ping proves that the client read everything
+            // before it
+            connection.pinged_bytes = self.network_server.sent_bytes(id);
+            let ping = protocol::PingMessage {
+                ping: protocol::Ping { id: connection.latency.ping(now) },
+            };
+            let message = Message::new(protocol::ping_to_json(&ping).into_bytes());
+            self.network_server.send(id, message);
+        }
+    }
+
+    /// Records the round trip time and tells the client where the server is
+    /// in time.
+    fn received_pong(&mut self, connection: ConnectionId, id: u64) {
+        let now = Instant::now();
+        let (latency, pinged_bytes) = match self.connections.get_mut(&connection) {
+            Some(connection) => (&mut connection.latency, connection.pinged_bytes),
+            None => return,
+        };
+        if latency.pong(id, now).is_none() {
+            trace!("connection {:?} answered an old ping {}", connection, id);
+            return;
+        }
+        self.network_server.confirm_received(connection, pinged_bytes);
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let since_frame = now.saturating_duration_since(self.last_frame_time);
+        let time_sync = protocol::TimeSyncMessage {
+            time_sync: protocol::TimeSync {
+                frame: self.game_server.frame(),
+                phase: (since_frame.as_secs_f64() / FRAME_TIME.as_secs_f64()).min(1.0),
+                frame_time: millis(FRAME_TIME),
+                rtt: millis(latency.rtt().unwrap_or_default()),
+                jitter: millis(latency.jitter().unwrap_or_default()),
+            },
+        };
+        let message = Message::new(protocol::time_sync_to_json(&time_sync).into_bytes());
+        self.network_server.send(connection, message);
+    }
     
     fn process_network_events(&mut self) {
         while let Some(event) = self.network_server.poll_event() {
@@ -50,81 +202,297 @@ impl<G: Game> GameLoop<G> {
                     self.client_connected(id);
                 }
                 Event::Disconnected { id } => {
-                    self.disconnect_client(id);
+                    self.disconnect_client(id, "connection closed");
+                }
+                Event::Snapshot(request) => {
+                    self.snapshot(request);
                 }
             }
         }
     }
+
+    fn snapshot(&mut self, request: SnapshotRequest) {
+        if request.room_id != self.game_server.room_id() {
+            request.reply(Err(SnapshotError::UnknownRoom));
+            return;
+        }
+        let image = self.game_server
+            .game()
+            .render_to_image(self.game_server.world(), request.width, request.height);
+        let png = image.and_then(|image| image.to_png());
+        if let Err(e) = &png {
+            warn!("failed to render snapshot of room {}: {}", request.room_id, e);
+        }
+        request.reply(png.map_err(SnapshotError::Failed));
+    }
     
     fn client_connected(&mut self, connection: ConnectionId) {
         let (client, world) = self.game_server.client_connected();
+        let (frame, local_player_id) = (world.frame, world.local_player_id);
         self.clients.insert(connection, client);
+        self.connections.insert(connection, Connection {
+            latency: Latency::new(),
+            rate_limit: RateLimit::new(MESSAGES_PER_SECOND, MESSAGE_BURST, Instant::now()),
+            send_queue: SendQueue::new(frame),
+            pinged_bytes: 0,
+            keyframe: None,
+        });
         let world = protocol::World {
-            frame: world.frame,
-            local_player_id: world.local_player_id,
-            world: world.world.to_blob(),
+            frame,
+            local_player_id,
+            snapshot: self.world_snapshot(connection),
         };
         let message = Message::new(protocol::world_to_json(&world).into_bytes());
         self.network_server.send(connection, message);
     }
 
-    fn disconnect_client(&mut self, connection: ConnectionId) {
+    /// Current world as a difference from the keyframe, or the whole world if
+    /// that is smaller. Keyframe is sent to the connection first if it
+    /// doesn't have it yet.
+    fn world_snapshot(&mut self, connection: ConnectionId) -> protocol::Snapshot {
+        let world = self.game_server.world();
+        let blob = world.to_blob();
+        let keyframe = match self.game_server.keyframe() {
+            Some(keyframe) => keyframe,
+            None => return protocol::Snapshot::World(blob),
+        };
+        if self.keyframe.as_ref().is_none_or(|sent| sent.frame != keyframe.frame) {
+            let message = protocol::NewKeyframe {
+                keyframe: protocol::Keyframe {
+                    frame: keyframe.frame,
+                    world: keyframe.world.to_blob(),
+                },
+            };
+            self.keyframe = Some(KeyframeMessage {
+                frame: keyframe.frame,
+                message: Message::new(protocol::keyframe_to_json(&message).into_bytes()),
+                blob: message.keyframe.world,
+            });
+        }
+        let sent = self.keyframe.as_ref().unwrap();
+        let (delta, from_game) = match self.game_server.game().diff_world(&keyframe.world, world) {
+            Some(delta) => (delta, true),
+            None => (delta::diff(&sent.blob, &blob), false),
+        };
+        let state = self.connections.get_mut(&connection);
+        let has_keyframe = state.as_ref().is_some_and(|state| state.keyframe == Some(keyframe.frame));
+        let keyframe_size = if has_keyframe { 0 } else { sent.blob.len() };
+        if delta.len() + keyframe_size >= blob.len() {
+            return protocol::Snapshot::World(blob);
+        }
+        trace!(
+            "sending world of {} bytes as {} byte delta from frame {}",
+            blob.len(),
+            delta.len(),
+            keyframe.frame,
+        );
+        if !has_keyframe {
+            self.network_server.send(connection, sent.message.clone());
+            if let Some(state) = state {
+                state.keyframe = Some(keyframe.frame);
+            }
+        }
+        protocol::Snapshot::Delta(protocol::Delta {
+            keyframe: keyframe.frame,
+            from_game,
+            delta,
+        })
+    }
+
+    fn reload_if_changed(&mut self) {
+        let package = match self.watcher.as_mut().and_then(|w| w.poll()) {
+            Some(package) => package,
+            None => return,
+        };
+        let reloaded = match self.game_server.game().reload(&package, self.game_server.world()) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!("failed to reload game: {}", e);
+                return;
+            }
+        };
+        if reloaded.world.is_some() {
+            info!("reloaded game, keeping current world");
+        } else {
+            info!("reloaded game, world is not compatible so game is restarted");
+        }
+        self.game_server.replace_game(reloaded.game, reloaded.world);
+        self.keyframe = None;
+        if self.recorder.take().is_some() {
+            // recorded frames can't be replayed with different code
+            info!("game was reloaded, stopping recording");
+        }
+        if let Some(watcher) = &self.watcher {
+            watcher.publish(package);
+        }
+
+        for (&connection, &client) in &self.clients {
+            let world = match self.game_server.client_world(client) {
+                Some(world) => world,
+                None => continue,
+            };
+            let reload = protocol::Reload {
+                reload: protocol::World {
+                    frame: world.frame,
+                    local_player_id: world.local_player_id,
+                    snapshot: protocol::Snapshot::World(world.world.to_blob()),
+                },
+            };
+            let message = Message::new(protocol::reload_to_json(&reload).into_bytes());
+            self.network_server.send(connection, message);
+            // client continues from the new world, so it doesn't need a
+            // resync anymore
+            if let Some(connection) = self.connections.get_mut(&connection) {
+                // and it forgets keyframes of the old game
+                connection.keyframe = None;
+                if connection.send_queue.is_paused() {
+                    connection.send_queue.resume(world.frame);
+                }
+            }
+        }
+    }
+
+    /// Client applied updates up to `frame`. If updates were paused because
+    /// it fell behind and it has now caught up, it is sent the current world.
+    fn received_ack(&mut self, connection: ConnectionId, client: ClientId, frame: u64) {
+        let send_queue = match self.connections.get_mut(&connection) {
+            Some(connection) => &mut connection.send_queue,
+            None => return,
+        };
+        send_queue.acknowledge(frame);
+        if !send_queue.caught_up() {
+            return;
+        }
+        let (frame, local_player_id) = match self.game_server.client_world(client) {
+            Some(world) => (world.frame, world.local_player_id),
+            None => return,
+        };
+        info!("connection {:?} caught up, resyncing it on frame {}", connection, frame);
+        let resync = protocol::Resync {
+            resync: protocol::World {
+                frame,
+                local_player_id,
+                snapshot: self.world_snapshot(connection),
+            },
+        };
+        let message = Message::new(protocol::resync_to_json(&resync).into_bytes());
+        self.network_server.send(connection, message);
+        self.game_server.client_resynced(client);
+        if let Some(connection) = self.connections.get_mut(&connection) {
+            connection.send_queue.resume(frame);
+        }
+    }
+
+    /// Connections that shouldn't be sent the update for `frame`, because
+    /// they are too far behind. Disconnects them or pauses their updates,
+    /// depending on the policy.
+    fn slow_connections(&mut self, frame: u64) -> HashSet<ConnectionId> {
+        let mut paused = HashSet::new();
+        let mut behind = Vec::new();
+        for (&id, connection) in &mut self.connections {
+            let queue = &mut connection.send_queue;
+            if queue.is_paused() {
+                paused.insert(id);
+                continue;
+            }
+            let depth = queue.depth(frame + 1);
+            if depth <= self.send_queue_limit {
+                continue;
+            }
+            match self.slow_client_policy {
+                SlowClientPolicy::Disconnect => behind.push((id, depth)),
+                SlowClientPolicy::Resync => {
+                    info!("connection {:?} is {} frames behind, pausing updates", id, depth);
+                    queue.pause(frame);
+                    paused.insert(id);
+                }
+            }
+        }
+        for (id, depth) in behind {
+            self.disconnect_client(id, &format!("fell {} frames behind", depth));
+        }
+        paused
+    }
+
+    fn disconnect_client(&mut self, connection: ConnectionId, reason: &str) {
+        self.connections.remove(&connection);
         if let Some(client) = self.clients.remove(&connection) {
             self.game_server.client_disconnected(client);
-            self.network_server.disconnect(connection);
+            self.network_server.disconnect(connection, reason);
         }
     }
 
     fn received_message(&mut self, sender: ConnectionId, message: Message) {
-        // FIXME: hack, json messages are being passed through as binary blobs
-        let message = std::str::from_utf8(message.data()).expect("invalid utf-8");
-        let message = match protocol::message_from_json(message) {
+        // messages that were received before disconnecting are still
+        // delivered afterwards
+        let (client, connection) = match (self.clients.get(&sender), self.connections.get_mut(&sender)) {
+            (Some(&client), Some(connection)) => (client, connection),
+            _ => {
+                trace!("ignoring message from disconnected connection {:?}", sender);
+                return;
+            }
+        };
+        if !connection.rate_limit.allow(Instant::now()) {
+            let reason = format!("sent more than {} messages per second", MESSAGES_PER_SECOND);
+            self.disconnect_client(sender, &reason);
+            return;
+        }
+        let message = match protocol::message_from_bytes(message.data()) {
             Ok(msg) => msg,
-            Err(_) => {
-                trace!(
-                    "client {:?} sent malformed message, disconnecting",
-                    sender,
-                );
-                self.disconnect_client(sender);
+            Err(e) => {
+                self.disconnect_client(sender, &format!("sent a malformed message: {}", e));
                 return;
             }
         };
-        
-        let client = self.clients[&sender];
-        let is_ok = match message {
+
+        let result = match message {
             protocol::ClientMessage::Join { frame } => {
-                self.game_server.client_joined(client, frame).is_ok()
+                self.game_server.client_joined(client, frame).map_err(|e| e.to_string())
             }
             protocol::ClientMessage::Input { frame, input } => {
-                self.game_server.client_input(client, frame, &input).is_ok()
+                self.game_server.client_input(client, frame, &input).map_err(|e| e.to_string())
+            }
+            protocol::ClientMessage::Pong { id } => {
+                self.received_pong(sender, id);
+                Ok(())
+            }
+            protocol::ClientMessage::Ack { frame } => {
+                self.received_ack(sender, client, frame);
+                Ok(())
             }
         };
 
-        if !is_ok {
-            self.disconnect_client(sender);
+        if let Err(reason) = result {
+            self.disconnect_client(sender, &reason);
         }
     }
 
     fn game_tick(&mut self) {
-        let update = self.game_server.game_tick();
-        let update = protocol::Update {
-            new_players: update
-                .new_players
-                .into_iter()
-                .map(|p| p.into())
-                .collect(),
-            removed_players: update
-                .removed_players
-                .into_iter()
-                .map(|p| p.into())
-                .collect(),
-            inputs: update
-                .player_inputs
-                .into_iter()
-                .map(|(p, i)| (p.into(), i.to_blob()))
-                .collect(),
-        };
+        let frame = self.game_server.frame();
+        let skip = self.slow_connections(frame);
+        let update = protocol::Update::from(self.game_server.game_tick());
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record_update(&update) {
+                warn!("failed to write recording, stopping: {}", e);
+                self.recorder = None;
+            }
+        }
         let message = Message::new(protocol::update_to_json(&update).into_bytes());
-        self.network_server.broadcast(message);
+        self.network_server.broadcast(message, &skip);
+        for (id, connection) in &mut self.connections {
+            if !skip.contains(id) {
+                connection.send_queue.sent(frame);
+            }
+        }
+
+        let advice = self.game_server.input_advice();
+        for (&connection, client) in &self.clients {
+            if let Some(&advice) = advice.get(client) {
+                trace!("advising connection {:?}: {:?}", connection, advice);
+                let message = protocol::InputAdviceMessage { input_advice: advice.into() };
+                let message = Message::new(protocol::input_advice_to_json(&message).into_bytes());
+                self.network_server.send(connection, message);
+            }
+        }
     }
 }
@@ -0,0 +1,379 @@
+//! Software rasterizer for draw calls that game code makes while rendering,
+//! so that worlds can be drawn on the server, without a browser.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use log::warn;
+
+/// Largest width or height of an image, to keep memory use of a single
+/// snapshot reasonable.
+pub const MAX_SIZE: u32 = 4096;
+
+/// Image with alpha, pixels are stored row by row as `0xAARRGGBB`, the same
+/// way that game code passes colors. Drawing blends colors over what is
+/// already drawn, assuming that the image is opaque, which canvases are.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u32>,
+}
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image({}x{})", self.width, self.height)
+    }
+}
+
+impl Image {
+    /// Opaque black image. Panics if a dimension is larger than `MAX_SIZE`.
+    pub fn new(width: u32, height: u32) -> Image {
+        assert!(width <= MAX_SIZE && height <= MAX_SIZE, "image is too large: {}x{}", width, height);
+        Image {
+            width,
+            height,
+            pixels: vec![0xff00_0000; width as usize * height as usize],
+        }
+    }
+
+    pub fn from_png(data: &[u8]) -> Result<Image, String> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let (width, height) = (reader.info().width, reader.info().height);
+        if width > MAX_SIZE || height > MAX_SIZE {
+            return Err(format!("image is too large: {}x{}", width, height));
+        }
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).map_err(|e| e.to_string())?;
+        let argb = |r: u8, g: u8, b: u8, a: u8| {
+            u32::from(a) << 24 | u32::from(r) << 16 | u32::from(g) << 8 | u32::from(b)
+        };
+        let data = &data[..info.buffer_size()];
+        let pixels = match info.color_type {
+            png::ColorType::Grayscale => data.iter().map(|&v| argb(v, v, v, 255)).collect(),
+            png::ColorType::GrayscaleAlpha => data.chunks(2).map(|p| argb(p[0], p[0], p[0], p[1])).collect(),
+            png::ColorType::Rgb => data.chunks(3).map(|p| argb(p[0], p[1], p[2], 255)).collect(),
+            png::ColorType::Rgba => data.chunks(4).map(|p| argb(p[0], p[1], p[2], p[3])).collect(),
+            png::ColorType::Indexed => return Err("palette was not expanded".to_string()),
+        };
+        Ok(Image { width, height, pixels })
+    }
+
+    // This is only used in tests, so cfg(test) effectively silences dead code warning
+    #[cfg(test)]
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        assert!(x < self.width && y < self.height, "pixel ({}, {}) is out of bounds", x, y);
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Draws a horizontal run of pixels, parts outside of the image are
+    /// skipped.
+    fn fill_span(&mut self, y: i64, left: i64, right: i64, color: u32) {
+        if y < 0 || y >= i64::from(self.height) {
+            return;
+        }
+        let left = left.max(0);
+        let right = right.min(i64::from(self.width));
+        if left >= right {
+            return;
+        }
+        let offset = y as usize * self.width as usize;
+        let span = &mut self.pixels[offset + left as usize..offset + right as usize];
+        span.iter_mut().for_each(|pixel| *pixel = blend(*pixel, color));
+    }
+
+    fn put_pixel(&mut self, x: i64, y: i64, color: u32) {
+        self.fill_span(y, x, x + 1, color);
+    }
+
+    /// Fills a rectangle, parts outside of the image are skipped.
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: u32) {
+        let (left, right) = (i64::from(x), i64::from(x) + i64::from(width));
+        let top = i64::from(y).max(0);
+        let bottom = (i64::from(y) + i64::from(height)).min(i64::from(self.height));
+        for row in top..bottom {
+            self.fill_span(row, left, right, color);
+        }
+    }
+
+    /// Fills pixels that are at most `radius` away from the center.
+    pub fn fill_circle(&mut self, x: i32, y: i32, radius: u32, color: u32) {
+        let (x, y, radius) = (i64::from(x), i64::from(y), i64::from(radius));
+        let top = (y - radius).max(0);
+        let bottom = (y + radius).min(i64::from(self.height) - 1);
+        for row in top..=bottom {
+            let (dy, radius) = ((row - y) as f64, radius as f64);
+            let half = (radius * radius - dy * dy).sqrt() as i64;
+            self.fill_span(row, x - half, x + half + 1, color);
+        }
+    }
+
+    /// One pixel wide line, including both ends.
+    pub fn draw_line(&mut self, from: (i32, i32), to: (i32, i32), color: u32) {
+        let (from, to) = match self.clip_line(from, to) {
+            Some(line) => line,
+            None => return,
+        };
+        // Bresenham's algorithm
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (step_x, step_y) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut error = dx + dy;
+        loop {
+            self.put_pixel(x, y, color);
+            if (x, y) == to {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Part of the line that is inside the image, or just around it, so that
+    /// lines far outside of the image don't take long to draw.
+    fn clip_line(&self, from: (i32, i32), to: (i32, i32)) -> Option<((i64, i64), (i64, i64))> {
+        // Liang-Barsky algorithm
+        let (x0, y0) = (f64::from(from.0), f64::from(from.1));
+        let (dx, dy) = (f64::from(to.0) - x0, f64::from(to.1) - y0);
+        let (max_x, max_y) = (f64::from(self.width), f64::from(self.height));
+        let (mut start, mut end) = (0.0f64, 1.0f64);
+        for &(p, q) in &[(-dx, x0 + 1.0), (dx, max_x - x0), (-dy, y0 + 1.0), (dy, max_y - y0)] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return None;
+                }
+            } else if p < 0.0 {
+                start = start.max(q / p);
+            } else {
+                end = end.min(q / p);
+            }
+        }
+        if start > end {
+            return None;
+        }
+        let point = |t: f64| ((x0 + t * dx).round() as i64, (y0 + t * dy).round() as i64);
+        Some((point(start), point(end)))
+    }
+
+    /// Fills pixels whose centers are inside the polygon, by even-odd rule.
+    pub fn fill_polygon(&mut self, points: &[(i32, i32)], color: u32) {
+        if points.len() < 3 {
+            return;
+        }
+        let top = points.iter().map(|p| i64::from(p.1)).min().unwrap().max(0);
+        let bottom = points.iter().map(|p| i64::from(p.1)).max().unwrap().min(i64::from(self.height));
+        let mut crossings = Vec::new();
+        for row in top..bottom {
+            let center = row as f64 + 0.5;
+            crossings.clear();
+            for (i, &(x0, y0)) in points.iter().enumerate() {
+                let (x1, y1) = points[(i + 1) % points.len()];
+                let (x0, y0, x1, y1) = (f64::from(x0), f64::from(y0), f64::from(x1), f64::from(y1));
+                if (y0 > center) != (y1 > center) {
+                    crossings.push(x0 + (center - y0) * (x1 - x0) / (y1 - y0));
+                }
+            }
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for pair in crossings.chunks(2) {
+                if let [left, right] = *pair {
+                    let first = (left - 0.5).ceil() as i64;
+                    let last = (right - 0.5).ceil() as i64;
+                    self.fill_span(row, first, last, color);
+                }
+            }
+        }
+    }
+
+    /// Draws another image with its top left corner at `x`, `y`.
+    pub fn draw_image(&mut self, image: &Image, x: i32, y: i32) {
+        for row in 0..image.height {
+            let target_y = i64::from(y) + i64::from(row);
+            if target_y < 0 || target_y >= i64::from(self.height) {
+                continue;
+            }
+            for column in 0..image.width {
+                let color = image.pixels[(row * image.width + column) as usize];
+                self.put_pixel(i64::from(x) + i64::from(column), target_y, color);
+            }
+        }
+    }
+
+    /// Encodes the image as PNG, fails if it is empty.
+    pub fn to_png(&self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 4);
+        for &pixel in &self.pixels {
+            data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, (pixel >> 24) as u8]);
+        }
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer.write_image_data(&data).map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+        Ok(png)
+    }
+}
+
+/// Draws `color` over `pixel`, using alpha of `color`.
+fn blend(pixel: u32, color: u32) -> u32 {
+    let alpha = color >> 24;
+    match alpha {
+        0 => pixel,
+        255 => color,
+        _ => {
+            let mix = |shift: u32| {
+                let over = (color >> shift) & 0xff;
+                let under = (pixel >> shift) & 0xff;
+                ((over * alpha + under * (255 - alpha) + 127) / 255) << shift
+            };
+            let under_alpha = pixel >> 24;
+            let alpha = alpha + (under_alpha * (255 - alpha) + 127) / 255;
+            alpha << 24 | mix(16) | mix(8) | mix(0)
+        }
+    }
+}
+
+/// Images from `assets/` in the game package, which game code can draw as
+/// sprites.
+#[derive(Default)]
+pub struct Sprites {
+    images: HashMap<String, Image>,
+}
+
+impl Sprites {
+    /// Decodes every PNG image. Other assets are not sprites, and images
+    /// that can't be decoded are skipped.
+    pub fn from_assets(assets: &BTreeMap<String, Vec<u8>>) -> Sprites {
+        let mut images = HashMap::new();
+        for (name, data) in assets.iter().filter(|(name, _)| name.ends_with(".png")) {
+            match Image::from_png(data) {
+                Ok(image) => {
+                    images.insert(name.clone(), image);
+                }
+                Err(e) => warn!("failed to decode sprite `{}`: {}", name, e),
+            }
+        }
+        Sprites { images }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Image> {
+        self.images.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: u32 = 0xff00_0000;
+    const RED: u32 = 0xffff_0000;
+
+    fn rows(image: &Image) -> Vec<String> {
+        (0..image.height)
+            .map(|y| {
+                (0..image.width)
+                    .map(|x| match image.pixel(x, y) {
+                        BLACK => '.',
+                        RED => '#',
+                        _ => '?',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rectangles_are_clipped() {
+        let mut image = Image::new(4, 3);
+        image.fill_rect(-2, 1, 4, 10, RED);
+        image.fill_rect(3, -5, 100, 6, RED);
+        assert_eq!(rows(&image), vec!["...#", "##..", "##.."]);
+        // completely outside
+        image.fill_rect(i32::MAX, i32::MAX, u32::MAX, u32::MAX, 0xffff_ffff);
+        image.fill_rect(i32::MIN, 0, 10, 10, 0xffff_ffff);
+        assert_eq!(image.pixel(0, 0), BLACK);
+    }
+
+    #[test]
+    fn circles() {
+        let mut image = Image::new(7, 7);
+        image.fill_circle(3, 3, 2, RED);
+        image.fill_circle(i32::MIN, 0, 1000, RED);
+        assert_eq!(rows(&image), vec![
+            ".......",
+            "...#...",
+            "..###..",
+            ".#####.",
+            "..###..",
+            "...#...",
+            ".......",
+        ]);
+        let mut image = Image::new(2, 2);
+        image.fill_circle(i32::MIN, i32::MIN, u32::MAX, RED);
+        assert_eq!(rows(&image), vec!["##", "##"]);
+    }
+
+    #[test]
+    fn lines() {
+        let mut image = Image::new(5, 4);
+        image.draw_line((0, 0), (4, 2), RED);
+        image.draw_line((-1000, 3), (i32::MAX, 3), RED);
+        assert_eq!(rows(&image), vec!["#....", ".##..", "...##", "#####"]);
+    }
+
+    #[test]
+    fn polygons() {
+        let mut image = Image::new(6, 4);
+        image.fill_polygon(&[(0, 0), (4, 0), (4, 4), (0, 4)], RED);
+        assert_eq!(rows(&image), vec!["####..", "####..", "####..", "####.."]);
+        let mut image = Image::new(5, 4);
+        image.fill_polygon(&[(0, 0), (5, 4), (0, 4)], RED);
+        assert_eq!(rows(&image), vec!["#....", "##...", "###..", "####."]);
+    }
+
+    #[test]
+    fn alpha_blending() {
+        let mut image = Image::new(1, 1);
+        image.fill_rect(0, 0, 1, 1, 0x80ff_ffff);
+        assert_eq!(image.pixel(0, 0), 0xff80_8080);
+        image.fill_rect(0, 0, 1, 1, 0x0012_3456);
+        assert_eq!(image.pixel(0, 0), 0xff80_8080);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut image = Image::new(3, 2);
+        image.fill_rect(1, 0, 1, 2, 0xff10_2030);
+        image.fill_rect(2, 1, 1, 1, 0x0000_0000);
+        let decoded = Image::from_png(&image.to_png().unwrap()).unwrap();
+        assert_eq!(decoded, image);
+        assert!(Image::from_png(b"not a png").is_err());
+        assert!(Image::new(0, 2).to_png().is_err());
+    }
+
+    #[test]
+    fn sprites_are_drawn_with_alpha() {
+        let mut sprite = Image::new(2, 1);
+        sprite.pixels = vec![RED, 0];
+        let mut assets = BTreeMap::new();
+        assets.insert("player.png".to_string(), sprite.to_png().unwrap());
+        assets.insert("broken.png".to_string(), b"broken".to_vec());
+        assets.insert("level.txt".to_string(), b"text".to_vec());
+        let sprites = Sprites::from_assets(&assets);
+        assert!(sprites.get("broken.png").is_none());
+        assert!(sprites.get("level.txt").is_none());
+        let mut image = Image::new(3, 2);
+        image.draw_image(sprites.get("player.png").unwrap(), 1, 1);
+        image.draw_image(sprites.get("player.png").unwrap(), -1, 0);
+        assert_eq!(rows(&image), vec!["...", ".#."]);
+    }
+}
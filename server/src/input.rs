@@ -0,0 +1,123 @@
+//! Input state that game modules turn into game input, in the format that
+//! their `create_input` export reads. The format is described in
+//! `primitive_game::input`.
+
+const INPUT_STATE_VERSION: u8 = 1;
+
+/// Numbers of keys other than letters and digits, named like variants of
+/// `primitive_game::input::Key` in snake case.
+const NAMED_KEYS: &[(&str, u8)] = &[
+    ("up", 32), ("down", 33), ("left", 34), ("right", 35), ("space", 46), ("enter", 47),
+    ("escape", 48), ("tab", 49), ("backspace", 50), ("delete", 51), ("insert", 52),
+    ("home", 53), ("end", 54), ("page_up", 55), ("page_down", 56), ("left_shift", 57),
+    ("right_shift", 58), ("left_ctrl", 59), ("right_ctrl", 60), ("left_alt", 61),
+    ("right_alt", 62), ("left_meta", 63), ("right_meta", 64), ("caps_lock", 65), ("f1", 66),
+    ("f2", 67), ("f3", 68), ("f4", 69), ("f5", 70), ("f6", 71), ("f7", 72), ("f8", 73),
+    ("f9", 74), ("f10", 75), ("f11", 76), ("f12", 77), ("minus", 78), ("equal", 79),
+    ("left_bracket", 80), ("right_bracket", 81), ("backslash", 82), ("semicolon", 83),
+    ("quote", 84), ("backquote", 85), ("comma", 86), ("period", 87), ("slash", 88),
+    ("numpad0", 89), ("numpad1", 90), ("numpad2", 91), ("numpad3", 92), ("numpad4", 93),
+    ("numpad5", 94), ("numpad6", 95), ("numpad7", 96), ("numpad8", 97), ("numpad9", 98),
+    ("numpad_add", 99), ("numpad_subtract", 100), ("numpad_multiply", 101),
+    ("numpad_divide", 102), ("numpad_decimal", 103), ("numpad_enter", 104), ("num_lock", 105),
+    ("scroll_lock", 106), ("print_screen", 107), ("pause", 108), ("context_menu", 109),
+];
+
+/// Keys that are held, bit `n` is key number `n` of `primitive_game::input::Key`.
+#[derive(PartialEq, Eq, Debug, Default, Copy, Clone)]
+pub struct KeySet(u128);
+
+impl KeySet {
+    pub fn from_bits(bits: u128) -> KeySet {
+        KeySet(bits)
+    }
+
+    /// Key number from its name: letters `a`-`z`, digits `0`-`9`, or one of
+    /// `NAMED_KEYS`, like `up`, `space`, `left_shift` or `f1`.
+    pub fn key_number(name: &str) -> Option<u8> {
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c @ 'a'..='z'), None) => Some(c as u8 - b'a'),
+            (Some(c @ '0'..='9'), None) => Some(36 + c as u8 - b'0'),
+            _ => NAMED_KEYS.iter().find(|&&(key, _)| key == name).map(|&(_, number)| number),
+        }
+    }
+
+    pub fn with(self, key: u8) -> KeySet {
+        KeySet(self.0 | (1 << key))
+    }
+
+    fn write(self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Clone)]
+pub struct InputState {
+    pub keys: KeySet,
+    pub old_keys: KeySet,
+    /// Pointer position in canvas pixels.
+    pub mouse: (i32, i32),
+    /// Bit `n` is set if mouse button `n` is held, numbered like in browsers.
+    pub buttons: u8,
+    pub old_buttons: u8,
+    /// Distance scrolled since the previous input.
+    pub wheel: (i32, i32),
+    /// Text typed since the previous input.
+    pub text: String,
+}
+
+impl InputState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![INPUT_STATE_VERSION, std::mem::size_of::<KeySet>() as u8];
+        self.keys.write(&mut buffer);
+        self.old_keys.write(&mut buffer);
+        for value in &[self.mouse.0, self.mouse.1] {
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+        buffer.extend_from_slice(&[self.buttons, self.old_buttons]);
+        for value in &[self.wheel.0, self.wheel.1] {
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+        buffer.extend_from_slice(&(self.text.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(self.text.as_bytes());
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_numbers() {
+        assert_eq!(KeySet::key_number("a"), Some(0));
+        assert_eq!(KeySet::key_number("z"), Some(25));
+        assert_eq!(KeySet::key_number("right"), Some(35));
+        assert_eq!(KeySet::key_number("9"), Some(45));
+        assert_eq!(KeySet::key_number("space"), Some(46));
+        assert_eq!(KeySet::key_number("left_shift"), Some(57));
+        assert_eq!(KeySet::key_number("f12"), Some(77));
+        assert_eq!(KeySet::key_number("context_menu"), Some(109));
+        assert_eq!(KeySet::key_number("A"), None);
+        assert_eq!(KeySet::key_number("f13"), None);
+    }
+
+    #[test]
+    fn input_state_bytes() {
+        let state = InputState {
+            keys: KeySet::default().with(0).with(9),
+            old_keys: KeySet::default(),
+            mouse: (-2, 7),
+            buttons: 0b101,
+            old_buttons: 0b001,
+            wheel: (0, -10),
+            text: "hi".to_string(),
+        };
+        let mut expected = vec![1, 16, 1, 2];
+        expected.extend_from_slice(&[0; 30]);
+        expected.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff, 7, 0, 0, 0, 0b101, 0b001]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0xf6, 0xff, 0xff, 0xff, 2, 0, 0, 0, b'h', b'i']);
+        assert_eq!(state.to_bytes(), expected);
+    }
+}
@@ -0,0 +1,120 @@
+//! Round trip time of a connection, measured with pings that the client
+//! answers as soon as it receives them.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// Ping that was sent but not answered yet.
+struct PendingPing {
+    id: u64,
+    sent_at: Instant,
+}
+
+#[derive(Default)]
+pub struct Latency {
+    next_ping_id: u64,
+    /// Ping ids are hashed with random keys so that clients can't answer
+    /// pings before receiving them.
+    id_keys: RandomState,
+    pending: Option<PendingPing>,
+    /// Smoothed round trip time and its mean deviation, computed like TCP's
+    /// retransmission timer does (RFC 6298). `None` until the first pong.
+    estimate: Option<(Duration, Duration)>,
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        Latency::default()
+    }
+
+    /// Id for a new ping that is sent at `now`. If the previous one wasn't
+    /// answered yet, its answer will be ignored.
+    pub fn ping(&mut self, now: Instant) -> u64 {
+        // ids must fit in JavaScript's numbers
+        let id = self.id_keys.hash_one(self.next_ping_id) & ((1 << 53) - 1);
+        self.next_ping_id += 1;
+        self.pending = Some(PendingPing { id, sent_at: now });
+        id
+    }
+
+    /// Client answered ping `id` at `now`. Returns measured round trip time,
+    /// or `None` if this is not the latest ping.
+    pub fn pong(&mut self, id: u64, now: Instant) -> Option<Duration> {
+        let sent_at = match &self.pending {
+            Some(pending) if pending.id == id => pending.sent_at,
+            _ => return None,
+        };
+        self.pending = None;
+        let sample = now.saturating_duration_since(sent_at);
+        self.estimate = Some(match self.estimate {
+            None => (sample, sample / 2),
+            Some((rtt, jitter)) => (rtt * 7 / 8 + sample / 8, jitter * 3 / 4 + rtt.abs_diff(sample) / 4),
+        });
+        Some(sample)
+    }
+
+    /// Smoothed round trip time.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.estimate.map(|(rtt, _)| rtt)
+    }
+
+    /// Mean deviation of round trip times.
+    pub fn jitter(&self) -> Option<Duration> {
+        self.estimate.map(|(_, jitter)| jitter)
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.estimate {
+            Some((rtt, jitter)) => write!(
+                f,
+                "rtt {:.1} ms, jitter {:.1} ms",
+                rtt.as_secs_f64() * 1000.0,
+                jitter.as_secs_f64() * 1000.0,
+            ),
+            None => write!(f, "rtt not measured yet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn smoothing() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        assert_eq!(latency.rtt(), None);
+        assert_eq!(latency.to_string(), "rtt not measured yet");
+
+        let id = latency.ping(start);
+        assert_eq!(latency.pong(id, start + ms(80)), Some(ms(80)));
+        assert_eq!((latency.rtt(), latency.jitter()), (Some(ms(80)), Some(ms(40))));
+        // answered twice
+        assert_eq!(latency.pong(id, start + ms(90)), None);
+
+        let id = latency.ping(start + ms(1000));
+        assert_eq!(latency.pong(id, start + ms(1160)), Some(ms(160)));
+        assert_eq!((latency.rtt(), latency.jitter()), (Some(ms(90)), Some(ms(50))));
+        assert_eq!(latency.to_string(), "rtt 90.0 ms, jitter 50.0 ms");
+    }
+
+    #[test]
+    fn only_latest_ping_counts() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        let old = latency.ping(start);
+        let new = latency.ping(start + ms(1000));
+        assert_eq!(latency.pong(old, start + ms(1010)), None);
+        assert_eq!(latency.pong(new, start + ms(1020)), Some(ms(20)));
+        assert_eq!(latency.pong(new + 1, start + ms(1030)), None);
+    }
+}
@@ -1,36 +1,343 @@
 #![warn(rust_2018_idioms)]
 
+mod bots;
+mod delta;
+mod determinism;
 mod game;
+mod image;
+mod input;
+mod latency;
 mod network;
 mod package;
 mod resources;
 mod result_ext;
 mod server;
+mod simulate;
 mod protocol;
+mod rate_limit;
+mod send_queue;
 mod game_loop;
+mod recording;
+mod validate;
+mod watch;
 
+use std::env;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::info;
 use structopt::StructOpt;
+use crate::game::wasmi::runtime::Runtime;
 use crate::package::Package;
+use crate::send_queue::SlowClientPolicy;
 
 #[derive(StructOpt, Debug)]
-struct Opt {
-    /// Path to game package
-    #[structopt(parse(from_os_str))]
-    package: PathBuf,
+enum Opt {
+    /// Run game server, also done when no subcommand is given
+    #[structopt(name = "run")]
+    Run {
+        /// Path to game package
+        #[structopt(parse(from_os_str))]
+        package: PathBuf,
+        /// Reload the game when package file changes
+        #[structopt(long = "watch")]
+        watch: bool,
+        /// File to record the game to
+        #[structopt(long = "record", parse(from_os_str))]
+        record: Option<PathBuf>,
+        /// Wasm runtime to run the game with: `interpreter` or `jit`
+        #[structopt(long = "runtime", default_value = "interpreter")]
+        runtime: Runtime,
+        /// Room id that game randomness is derived from, picked from current
+        /// time if not given
+        #[structopt(long = "room-id")]
+        room_id: Option<u64>,
+        /// Compress messages of at least this many bytes for clients that
+        /// support it
+        #[structopt(long = "compress-above")]
+        compress_above: Option<usize>,
+        /// Serve admin pages, like room snapshots, to requests with
+        /// `Authorization: Bearer <token>` header
+        #[structopt(long = "admin-token")]
+        admin_token: Option<String>,
+        /// Clients are advised to send inputs so that they arrive at least
+        /// this many frames before being simulated
+        #[structopt(long = "min-input-margin", default_value = "1")]
+        min_input_margin: i64,
+        /// Clients are advised to send inputs so that they arrive at most
+        /// this many frames before being simulated
+        #[structopt(long = "max-input-margin", default_value = "4")]
+        max_input_margin: i64,
+        /// Let clients skip frames when sending inputs, instead of
+        /// disconnecting them
+        #[structopt(long = "allow-input-gaps")]
+        allow_input_gaps: bool,
+        /// Connections that send larger messages are closed. Clients only
+        /// send small JSON messages, inputs with typed text being the largest
+        #[structopt(long = "max-message-size", default_value = "16384")]
+        max_message_size: usize,
+        /// Clients that are this many frame updates behind are handled
+        /// according to `--slow-clients`
+        #[structopt(long = "send-queue-limit", default_value = "300")]
+        send_queue_limit: u64,
+        /// What to do with clients that fall behind: `disconnect` or
+        /// `resync` them by sending a fresh world once they catch up
+        #[structopt(long = "slow-clients", default_value = "disconnect")]
+        slow_clients: SlowClientPolicy,
+    },
+    /// Check that game package can be loaded and played
+    #[structopt(name = "validate")]
+    Validate {
+        /// Path to game package
+        #[structopt(parse(from_os_str))]
+        package: PathBuf,
+        /// Number of players in smoke simulation
+        #[structopt(long = "players", default_value = "4")]
+        players: u32,
+        /// Number of frames in smoke simulation
+        #[structopt(long = "frames", default_value = "600")]
+        frames: u64,
+        /// Wasm runtime to run the game with: `interpreter` or `jit`
+        #[structopt(long = "runtime", default_value = "interpreter")]
+        runtime: Runtime,
+    },
+    /// Run game without networking, with bots as players
+    #[structopt(name = "simulate")]
+    Simulate {
+        /// Path to game package
+        #[structopt(parse(from_os_str))]
+        package: PathBuf,
+        /// Number of bots
+        #[structopt(long = "players", default_value = "4")]
+        players: u32,
+        /// Number of frames to simulate
+        #[structopt(long = "frames", default_value = "3600")]
+        frames: u64,
+        /// Seed for random bot inputs, also used as room id that game
+        /// randomness is derived from
+        #[structopt(long = "seed", default_value = "1")]
+        seed: u64,
+        /// File with keys that bots should press on each frame, instead of
+        /// random ones
+        #[structopt(long = "script", parse(from_os_str))]
+        script: Option<PathBuf>,
+        /// File to write the final world to
+        #[structopt(long = "world-output", parse(from_os_str))]
+        world_output: Option<PathBuf>,
+        /// File to record the simulated game to
+        #[structopt(long = "record", parse(from_os_str))]
+        record: Option<PathBuf>,
+        /// Wasm runtime to run the game with: `interpreter` or `jit`
+        #[structopt(long = "runtime", default_value = "interpreter")]
+        runtime: Runtime,
+    },
+    /// Replay a recorded game on independent game instances and check that
+    /// they compute identical worlds
+    #[structopt(name = "check-determinism")]
+    CheckDeterminism {
+        /// Path to game package
+        #[structopt(parse(from_os_str))]
+        package: PathBuf,
+        /// Recording made with `--record`
+        #[structopt(parse(from_os_str))]
+        recording: PathBuf,
+        /// Wasm runtime for the first game instance
+        #[structopt(long = "runtime", default_value = "interpreter")]
+        runtime: Runtime,
+        /// Wasm runtime for the second game instance
+        #[structopt(long = "against", default_value = "interpreter")]
+        against: Runtime,
+        /// Also replay with `primitive-game` compiled into the server
+        #[structopt(long = "native")]
+        native: bool,
+    },
+}
+
+/// Names of subcommands, anything else as the first argument is an argument
+/// of `run`.
+const SUBCOMMANDS: &[&str] = &["run", "validate", "simulate", "check-determinism", "help"];
+
+/// Command line arguments, with `run` inserted if subcommand is not given, so
+/// that `server <package>` still runs the server.
+fn args() -> Vec<OsString> {
+    let mut args = env::args_os().collect::<Vec<_>>();
+    let implicit_run = match args.get(1).and_then(|arg| arg.to_str()) {
+        Some("-h") | Some("--help") | Some("-V") | Some("--version") => false,
+        Some(arg) => !SUBCOMMANDS.contains(&arg),
+        // not UTF-8, so it must be a package path
+        None => args.len() > 1,
+    };
+    if implicit_run {
+        args.insert(1, OsString::from("run"));
+    }
+    args
 }
 
 fn main() {
-    let options = Opt::from_args();
     setup_logger();
-    let package = load_package(&options.package);
-    let game = create_game(&package);
+    validate::silence_caught_panics();
+    match Opt::from_iter(args()) {
+        Opt::Run {
+            package,
+            watch,
+            runtime,
+            record,
+            room_id,
+            compress_above,
+            admin_token,
+            min_input_margin,
+            max_input_margin,
+            allow_input_gaps,
+            max_message_size,
+            send_queue_limit,
+            slow_clients,
+        } => {
+            if min_input_margin > max_input_margin {
+                eprintln!("Minimum input margin is larger than the maximum");
+                std::process::exit(1);
+            }
+            if send_queue_limit <= send_queue::ACK_INTERVAL {
+                // clients acknowledge updates only every few frames, so they
+                // would always seem to be behind
+                eprintln!("Send queue limit must be larger than {}", send_queue::ACK_INTERVAL);
+                std::process::exit(1);
+            }
+            let options = RunOptions {
+                watch,
+                runtime,
+                record,
+                room_id,
+                compress_above,
+                admin_token,
+                input_margin: server::InputMargin { min: min_input_margin, max: max_input_margin },
+                allow_input_gaps,
+                max_message_size,
+                send_queue_limit,
+                slow_clients,
+            };
+            run(package, options);
+        }
+        Opt::Validate { package, players, frames, runtime } => {
+            let options = validate::Options { players, frames, runtime };
+            let report = validate::validate_package(&package, &options);
+            println!("{}", report);
+            if !report.is_ok() {
+                std::process::exit(1);
+            }
+        }
+        Opt::Simulate { package, players, frames, seed, script, world_output, runtime, record } => {
+            let script = script.map(|path| load_script(&path));
+            let package = load_package(&package);
+            let recorder = record.map(|path| create_recorder(&path));
+            let options = simulate::Options { players, frames, seed, script, recorder };
+            let report = simulate::simulate(create_module(&package, runtime), options);
+            println!("{}", report);
+            if let Some(path) = world_output {
+                if let Err(e) = std::fs::write(&path, &report.final_world) {
+                    eprintln!("Failed to write world to {}", path.display());
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Opt::CheckDeterminism { package, recording, runtime, against, native } => {
+            check_determinism(&package, &recording, runtime, against, native);
+        }
+    }
+}
+
+fn check_determinism(package: &Path, recording: &Path, runtime: Runtime, against: Runtime, native: bool) {
+    let package = load_package(package);
+    let recording = match recording::load(recording) {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("Failed to load recording");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut backends = vec![
+        determinism::Backend::new(runtime.to_string(), create_game(&package, runtime)),
+        determinism::Backend::new(against.to_string(), create_game(&package, against)),
+    ];
+    if native {
+        backends.push(native_backend());
+    }
+    let report = determinism::check(&recording, backends);
+    println!("{}", report);
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "native")]
+fn native_backend() -> determinism::Backend {
+    let game = game::native::NativeGame::<primitive_game::squares::Squares>::new();
+    determinism::Backend::new("native", game)
+}
+
+#[cfg(not(feature = "native"))]
+fn native_backend() -> determinism::Backend {
+    eprintln!("Server was built without `native` feature");
+    std::process::exit(1);
+}
+
+struct RunOptions {
+    watch: bool,
+    runtime: Runtime,
+    record: Option<PathBuf>,
+    room_id: Option<u64>,
+    compress_above: Option<usize>,
+    admin_token: Option<String>,
+    input_margin: server::InputMargin,
+    allow_input_gaps: bool,
+    max_message_size: usize,
+    send_queue_limit: u64,
+    slow_clients: SlowClientPolicy,
+}
+
+fn run(package_path: PathBuf, options: RunOptions) {
+    let RunOptions {
+        watch,
+        runtime,
+        record,
+        room_id,
+        compress_above,
+        admin_token,
+        input_margin,
+        allow_input_gaps,
+        max_message_size,
+        send_queue_limit,
+        slow_clients,
+    } = options;
+    let package = load_package(&package_path);
+    let game = create_game(&package, runtime);
     let resources = Arc::new(resources::ServerResources::load(package));
 
-    let websocket_server = network::WebsocketServer::listen(resources, "127.0.0.1:8000");
-    let server = server::Server::new(game);
+    let mut websocket_server = network::WebsocketServer::listen(resources.clone(), "127.0.0.1:8000", max_message_size);
+    if let Some(threshold) = compress_above {
+        websocket_server.compress_above(threshold);
+    }
+    if let Some(token) = admin_token {
+        websocket_server.enable_admin(token);
+    }
+    let room_id = room_id.unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system time is before epoch");
+        now.as_secs() ^ u64::from(now.subsec_nanos())
+    });
+    info!("Room id is {}", room_id);
+    let mut server = server::Server::with_room_id(game, room_id);
+    server.set_input_margin(input_margin);
+    server.set_allow_input_gaps(allow_input_gaps);
     let mut game_loop = game_loop::GameLoop::new(websocket_server, server);
+    game_loop.limit_send_queue(send_queue_limit, slow_clients);
+    if watch {
+        game_loop.watch(watch::PackageWatcher::new(package_path, resources));
+    }
+    if let Some(path) = record {
+        game_loop.record(create_recorder(&path));
+    }
 
     game_loop.run();
 }
@@ -47,6 +354,8 @@ fn setup_logger() {
         })
         .level(log::LevelFilter::Info)
         .level_for("server", log::LevelFilter::Debug)
+        // messages logged by game code
+        .level_for("wasm", log::LevelFilter::Debug)
         .chain(std::io::stdout())
         .apply();
     if let Err(e) = result {
@@ -59,23 +368,56 @@ fn setup_logger() {
 fn load_package<P: AsRef<Path>>(path: P) -> Package {
     match package::load_from_file(path) {
         Ok(package) => package,
-        Err(package::LoadError::MalformedPackage) => {
-            eprintln!("Incorrect package file format");
+        Err(e) => {
+            eprintln!("Failed to load game package");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_script(path: &Path) -> bots::Script {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to read bot script");
+            eprintln!("{}", e);
             std::process::exit(1);
         }
-        Err(package::LoadError::Io(err)) => {
-            eprintln!("{}", err);
+    };
+    match bots::Script::parse(&source) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Invalid bot script");
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     }
 }
 
-fn create_game(package: &Package) -> game::wasmi::WasmiGame {
-    match game::wasmi::sys::Module::from_buffer(&package.wasm_module) {
-        Ok(module) => game::wasmi::WasmiGame::new(module),
+fn create_recorder(path: &Path) -> recording::Recorder {
+    match recording::Recorder::create(path) {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            eprintln!("Failed to create recording file");
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn create_game(package: &Package, runtime: Runtime) -> game::wasmi::WasmiGame {
+    let mut game = game::wasmi::WasmiGame::new(create_module(package, runtime));
+    game.load_sprites(package);
+    game
+}
+
+fn create_module(package: &Package, runtime: Runtime) -> game::wasmi::sys::Module {
+    match game::wasmi::sys::Module::from_buffer(&package.wasm_module, runtime) {
+        Ok(module) => module,
         Err(e) => {
             eprintln!("Failed to load game code");
-            eprintln!("{:?}", e);
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     }
@@ -1,17 +1,20 @@
-use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::fmt::Debug;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::io::Write;
 use std::net::ToSocketAddrs;
 use std::str;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use crate::resources::ServerResources;
 use crate::result_ext::ResultExt;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Copy, Clone)]
 pub struct ConnectionId(u64);
 
+#[derive(Clone)]
 pub struct Message {
     data: Vec<u8>,
 }
@@ -37,18 +40,241 @@ pub enum Event {
         sender: ConnectionId,
         message: Message,
     },
+    Snapshot(SnapshotRequest),
+}
+
+pub enum SnapshotError {
+    UnknownRoom,
+    Failed(String),
+}
+
+/// Request for a PNG image of a room's current world. The answer is stored in
+/// the snapshot cache, HTTP requests are served from there.
+pub struct SnapshotRequest {
+    pub room_id: u64,
+    pub width: u32,
+    pub height: u32,
+    cache: Arc<Mutex<SnapshotCache>>,
+}
+
+impl SnapshotRequest {
+    pub fn reply(self, result: Result<Vec<u8>, SnapshotError>) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.requested = None;
+        cache.latest = Some(Snapshot {
+            room_id: self.room_id,
+            width: self.width,
+            height: self.height,
+            rendered: Instant::now(),
+            result,
+        });
+    }
+}
+
+struct Snapshot {
+    room_id: u64,
+    width: u32,
+    height: u32,
+    rendered: Instant,
+    result: Result<Vec<u8>, SnapshotError>,
+}
+
+/// Latest snapshot that the game loop rendered. Waiting for the game loop
+/// would block all other connections, so HTTP requests get the cached one
+/// and ask for a new one if it is stale.
+#[derive(Default)]
+struct SnapshotCache {
+    latest: Option<Snapshot>,
+    /// When the snapshot that is being rendered was requested.
+    requested: Option<Instant>,
+}
+
+/// Size of snapshots when request doesn't specify it, same as the canvas in
+/// the client.
+const DEFAULT_SNAPSHOT_SIZE: (u32, u32) = (640, 480);
+
+/// Largest width or height of a snapshot. Rendering runs on the game loop
+/// thread, so it must stay cheap.
+const MAX_SNAPSHOT_SIZE: u32 = 1024;
+
+/// Cached snapshots older than this are rendered again.
+const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(1);
+
+/// Snapshot is requested again if game loop doesn't answer in this time.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Room id, width and height from
+/// `/admin/rooms/<id>/snapshot.png?width=<width>&height=<height>`.
+fn parse_snapshot_resource(resource: &str) -> Option<(u64, u32, u32)> {
+    let (path, query) = match resource.find('?') {
+        Some(index) => (&resource[..index], &resource[index + 1..]),
+        None => (resource, ""),
+    };
+    let room_id = path
+        .strip_prefix("/admin/rooms/")?
+        .strip_suffix("/snapshot.png")?
+        .parse()
+        .ok()?;
+    let (mut width, mut height) = DEFAULT_SNAPSHOT_SIZE;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(index) => (&pair[..index], &pair[index + 1..]),
+            None => (pair, ""),
+        };
+        match key {
+            "width" => width = value.parse().ok()?,
+            "height" => height = value.parse().ok()?,
+            _ => return None,
+        }
+    }
+    if width == 0 || height == 0 || width > MAX_SNAPSHOT_SIZE || height > MAX_SNAPSHOT_SIZE {
+        return None;
+    }
+    Some((room_id, width, height))
+}
+
+/// Content type of a file from `assets/` directory of the game package.
+fn asset_content_type(name: &str) -> &'static [u8] {
+    match name.rsplit('.').next() {
+        Some("png") => b"image/png",
+        Some("json") => b"application/json",
+        Some("txt") => b"text/plain",
+        _ => b"application/octet-stream",
+    }
+}
+
+/// Largest websocket frame header: 2 bytes, 8 bytes of length and 4 bytes of
+/// mask.
+const MAX_FRAME_HEADER: usize = 14;
+
+/// Messages can be split into this many frames.
+const MAX_FRAGMENTS: usize = 16;
+
+/// Connections that have this many bytes sent to them without the client
+/// confirming that it received them are closed. Game loop disconnects slow
+/// clients much sooner, this is for clients that lie about how far behind
+/// they are.
+const MAX_UNCONFIRMED_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Close frames can hold at most this many bytes of reason.
+const MAX_CLOSE_REASON: usize = 123;
+
+/// `reason` shortened to fit in a close frame.
+fn close_reason(reason: &str) -> String {
+    let mut end = reason.len().min(MAX_CLOSE_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    reason[..end].to_string()
+}
+
+/// Query of the websocket resource with which clients say that they can
+/// inflate binary frames.
+const COMPRESSION_QUERY: &str = "compression=deflate";
+
+/// Messages are JSON and go out as text frames. Clients that connected with
+/// [`COMPRESSION_QUERY`] also accept binary frames, which hold the same JSON
+/// compressed with raw deflate, so the frame opcode says which one it is.
+struct OutgoingMessage {
+    text: String,
+    compressed: Option<Vec<u8>>,
+}
+
+impl OutgoingMessage {
+    /// Compresses the message if it is at least `threshold` bytes long and
+    /// compressing makes it smaller.
+    fn new(message: Message, threshold: Option<usize>) -> OutgoingMessage {
+        let compressed = threshold
+            .filter(|&threshold| message.data.len() >= threshold)
+            .map(|_| deflate(&message.data))
+            .filter(|compressed| compressed.len() < message.data.len());
+        OutgoingMessage {
+            text: String::from_utf8(message.data).unwrap(),
+            compressed,
+        }
+    }
+
+    /// Frame for a client, `compression` says whether it can inflate it.
+    fn frame(&self, compression: bool, stats: &mut CompressionStats) -> ws::Message {
+        stats.messages += 1;
+        stats.raw_bytes += self.text.len() as u64;
+        match &self.compressed {
+            Some(compressed) if compression => {
+                stats.compressed_messages += 1;
+                stats.sent_bytes += compressed.len() as u64;
+                ws::Message::Binary(compressed.clone())
+            }
+            _ => {
+                stats.sent_bytes += self.text.len() as u64;
+                ws::Message::Text(self.text.clone())
+            }
+        }
+    }
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    // messages go out every frame, so speed matters more than size
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data).expect("writing to a vec can't fail");
+    encoder.finish().expect("writing to a vec can't fail")
+}
+
+/// Sizes of messages sent since the server started, counting each recipient
+/// of a broadcast separately.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub messages: u64,
+    pub compressed_messages: u64,
+    /// Size of messages before compression.
+    pub raw_bytes: u64,
+    /// Size of message payloads that were actually sent.
+    pub sent_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Sent bytes per message byte, 1 if nothing was sent.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            1.0
+        } else {
+            self.sent_bytes as f64 / self.raw_bytes as f64
+        }
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} messages ({} compressed), {} bytes as {} bytes, ratio {:.2}",
+            self.messages,
+            self.compressed_messages,
+            self.raw_bytes,
+            self.sent_bytes,
+            self.ratio(),
+        )
+    }
 }
 
 pub struct WebsocketServer {
     inner: Arc<Mutex<InnerServer>>,
     events: Receiver<Event>,
+    /// Connections that were closed for not reading what was sent to them,
+    /// reported as disconnected before other events.
+    dropped: Vec<ConnectionId>,
+    /// Messages of at least this many bytes are compressed for clients that
+    /// support it, `None` if compression is off.
+    compression_threshold: Option<usize>,
+    stats: CompressionStats,
     // We don't have shutdown for now, so join handle is unused.
     #[allow(dead_code)]
     listener_thread: thread::JoinHandle<()>,
 }
 
 impl WebsocketServer {
-    pub fn listen<A>(resources: Arc<ServerResources>, addr: A) -> WebsocketServer
+    /// Starts listening on `addr`. Connections that send messages or frames
+    /// larger than `max_message_size` are closed.
+    pub fn listen<A>(resources: Arc<ServerResources>, addr: A, max_message_size: usize) -> WebsocketServer
     where
         A: ToSocketAddrs + Debug + Send + 'static,
     {
@@ -56,12 +282,23 @@ impl WebsocketServer {
         let inner = Arc::new(Mutex::new(InnerServer {
             next_connection_id: ConnectionId(0),
             connections: HashMap::new(),
+            admin_token: None,
         }));
+        let snapshots = Arc::new(Mutex::new(SnapshotCache::default()));
 
+        let settings = ws::Settings {
+            // ws-rs grows its input buffer until a whole frame fits, so a
+            // fixed one limits frame size
+            in_buffer_capacity: max_message_size + MAX_FRAME_HEADER,
+            in_buffer_grow: false,
+            fragments_capacity: MAX_FRAGMENTS,
+            fragments_grow: false,
+            ..ws::Settings::default()
+        };
         let listener_thread = {
             let inner = inner.clone();
             thread::spawn(move || {
-                ws::listen(addr, |ws_sender| {
+                let websocket = ws::Builder::new().with_settings(settings).build(|ws_sender| {
                     let mut inner_lock = inner.lock().unwrap();
                     let id = inner_lock.generate_id();
                     // This connection might be a request for static files,
@@ -72,27 +309,71 @@ impl WebsocketServer {
                         sender: Some(ws_sender),
                         events: event_sender.clone(),
                         inner: inner.clone(),
+                        snapshots: snapshots.clone(),
+                        max_message_size,
                     }
-                }).log_if_err();
+                });
+                match websocket {
+                    Ok(websocket) => websocket.listen(addr).log_if_err(),
+                    Err(e) => error!("{}", e),
+                }
             })
         };
 
         WebsocketServer {
             inner,
             events: event_receiver,
+            dropped: Vec::new(),
+            compression_threshold: None,
+            stats: CompressionStats::default(),
             listener_thread,
         }
     }
 
+    /// Compress messages of at least `threshold` bytes for clients that can
+    /// inflate them.
+    pub fn compress_above(&mut self, threshold: usize) {
+        self.compression_threshold = Some(threshold);
+    }
+
+    /// Serve admin pages, like room snapshots, to requests that have
+    /// `Authorization: Bearer <token>` header.
+    pub fn enable_admin(&mut self, token: String) {
+        self.inner.lock().unwrap().admin_token = Some(token);
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
     pub fn poll_event(&mut self) -> Option<Event> {
+        if let Some(id) = self.dropped.pop() {
+            return Some(Event::Disconnected { id });
+        }
         self.events.try_recv().ok()
     }
 
-    pub fn disconnect(&mut self, connection: ConnectionId) {
-        info!("disconnecting connection {:?}", connection);
+    /// Total size of messages sent to the connection so far.
+    pub fn sent_bytes(&self, connection: ConnectionId) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.connections.get(&connection).map_or(0, |connection| connection.sent_bytes)
+    }
+
+    /// Client proved that it has read the first `bytes` bytes that were sent
+    /// to it, for example by answering a message that came after them.
+    pub fn confirm_received(&mut self, connection: ConnectionId, bytes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(connection) = inner.connections.get_mut(&connection) {
+            connection.confirmed_bytes = connection.confirmed_bytes.max(bytes.min(connection.sent_bytes));
+        }
+    }
+
+    /// Closes the connection, `reason` is sent to the client.
+    pub fn disconnect(&mut self, connection: ConnectionId, reason: &str) {
+        info!("disconnecting connection {:?}: {}", connection, reason);
         let mut inner = self.inner.lock().unwrap();
         if let Some(connection) = inner.connections.remove(&connection) {
-            connection.close(ws::CloseCode::Protocol).log_if_err();
+            connection.sender.close_with_reason(ws::CloseCode::Policy, close_reason(reason)).log_if_err();
         } else {
             warn!(
                 "tried to disconnect a non-existent connection: {:?}",
@@ -102,10 +383,15 @@ impl WebsocketServer {
     }
 
     pub fn send(&mut self, to: ConnectionId, message: Message) {
-        let inner = self.inner.lock().unwrap();
-        if let Some(connection) = inner.connections.get(&to) {
-            let message = ws::Message::Text(String::from_utf8(message.data).unwrap());
-            connection.send(message).log_if_err();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(connection) = inner.connections.get_mut(&to) {
+            let threshold = self.compression_threshold.filter(|_| connection.compression);
+            let message = OutgoingMessage::new(message, threshold);
+            let frame = message.frame(connection.compression, &mut self.stats);
+            if !connection.send(frame) {
+                inner.drop_connection(to);
+                self.dropped.push(to);
+            }
         } else {
             warn!(
                 "tried to send a message to non-existent connection: {:?}",
@@ -114,22 +400,56 @@ impl WebsocketServer {
         }
     }
 
-    pub fn broadcast(&mut self, message: Message) {
-        let inner = self.inner.lock().unwrap();
-        // ws-rs is a bit weird at broadcasting: you broadcast by obtaining a
-        // random connection, calling `broadcast` on it, and it will send a
-        // message to all connections made by the listener that this connection
-        // belongs to.
-        if let Some(connection) = inner.connections.values().next() {
-            let message = ws::Message::Text(String::from_utf8(message.data).unwrap());
-            connection.broadcast(message).log_if_err();
+    /// Sends the message to every connection except those in `skip`.
+    pub fn broadcast(&mut self, message: Message, skip: &HashSet<ConnectionId>) {
+        let mut inner = self.inner.lock().unwrap();
+        // ws-rs can broadcast a single message to all connections, but
+        // connections might need different frames, so send them one by one
+        // nobody could inflate it, so don't waste time compressing
+        let any_compression = inner.connections
+            .iter()
+            .any(|(id, connection)| connection.compression && !skip.contains(id));
+        let threshold = self.compression_threshold.filter(|_| any_compression);
+        let message = OutgoingMessage::new(message, threshold);
+        let mut dropped = Vec::new();
+        for (&id, connection) in inner.connections.iter_mut().filter(|(id, _)| !skip.contains(id)) {
+            let frame = message.frame(connection.compression, &mut self.stats);
+            if !connection.send(frame) {
+                dropped.push(id);
+            }
         }
+        for id in dropped {
+            inner.drop_connection(id);
+            self.dropped.push(id);
+        }
+    }
+}
+
+struct Connection {
+    sender: ws::Sender,
+    /// Whether the client can receive compressed messages.
+    compression: bool,
+    sent_bytes: u64,
+    /// How many of the sent bytes the client is known to have received.
+    /// ws-rs buffers the rest without a limit.
+    confirmed_bytes: u64,
+}
+
+impl Connection {
+    /// Returns false if the client has too many unconfirmed bytes and should
+    /// be disconnected.
+    fn send(&mut self, frame: ws::Message) -> bool {
+        self.sent_bytes += frame.len() as u64;
+        self.sender.send(frame).log_if_err();
+        self.sent_bytes - self.confirmed_bytes <= MAX_UNCONFIRMED_BYTES
     }
 }
 
 struct InnerServer {
     next_connection_id: ConnectionId,
-    connections: HashMap<ConnectionId, ws::Sender>,
+    connections: HashMap<ConnectionId, Connection>,
+    /// Token that admin requests must have, `None` if admin pages are off.
+    admin_token: Option<String>,
 }
 
 impl InnerServer {
@@ -138,6 +458,16 @@ impl InnerServer {
         self.next_connection_id.0 += 1;
         id
     }
+
+    /// Closes a connection that doesn't read what is sent to it, so that
+    /// messages for it don't pile up in memory.
+    fn drop_connection(&mut self, id: ConnectionId) {
+        if let Some(connection) = self.connections.remove(&id) {
+            let reason = format!("didn't read {} bytes of messages", MAX_UNCONFIRMED_BYTES);
+            info!("disconnecting connection {:?}: {}", id, reason);
+            connection.sender.close_with_reason(ws::CloseCode::Policy, reason).log_if_err();
+        }
+    }
 }
 
 struct ConnectionHandler {
@@ -146,6 +476,56 @@ struct ConnectionHandler {
     sender: Option<ws::Sender>,
     events: Sender<Event>,
     inner: Arc<Mutex<InnerServer>>,
+    snapshots: Arc<Mutex<SnapshotCache>>,
+    max_message_size: usize,
+}
+
+impl ConnectionHandler {
+    /// Whether the request has the admin token. Admin pages don't exist
+    /// when there is no token.
+    fn admin_request(&self, req: &ws::Request) -> Option<bool> {
+        let inner = self.inner.lock().unwrap();
+        let token = inner.admin_token.as_ref()?;
+        let authorization = req.header("Authorization").map(Vec::as_slice);
+        Some(authorization == Some(format!("Bearer {}", token).as_bytes()))
+    }
+
+    /// Serves the cached snapshot and asks game loop for a new one if it is
+    /// stale. `None` if there is no such room.
+    fn snapshot(&self, room_id: u64, width: u32, height: u32) -> Option<ws::Response> {
+        let mut cache = self.snapshots.lock().unwrap();
+        let cached = cache.latest.as_ref().filter(|snapshot| {
+            (snapshot.room_id, snapshot.width, snapshot.height) == (room_id, width, height)
+        });
+        let stale = cached.is_none_or(|snapshot| snapshot.rendered.elapsed() >= SNAPSHOT_MAX_AGE);
+        let response = match cached.map(|snapshot| &snapshot.result) {
+            Some(Ok(png)) => {
+                let mut response = ws::Response::new(200, "OK", png.clone());
+                response.headers_mut().push(("Content-Type".to_string(), b"image/png".to_vec()));
+                response
+            }
+            Some(Err(SnapshotError::UnknownRoom)) => return None,
+            Some(Err(SnapshotError::Failed(e))) => {
+                ws::Response::new(500, "Internal Server Error", e.clone().into_bytes())
+            }
+            None => {
+                let mut response = ws::Response::new(
+                    503,
+                    "Service Unavailable",
+                    b"503 - Snapshot is being rendered".to_vec(),
+                );
+                response.headers_mut().push(("Retry-After".to_string(), b"1".to_vec()));
+                response
+            }
+        };
+        let rendering = cache.requested.is_some_and(|requested| requested.elapsed() < SNAPSHOT_TIMEOUT);
+        if stale && !rendering {
+            cache.requested = Some(Instant::now());
+            let request = SnapshotRequest { room_id, width, height, cache: self.snapshots.clone() };
+            self.events.send(Event::Snapshot(request)).unwrap();
+        }
+        Some(response)
+    }
 }
 
 impl ws::Handler for ConnectionHandler {
@@ -164,7 +544,27 @@ impl ws::Handler for ConnectionHandler {
             )
         }
 
-        Ok(match req.resource() {
+        if let Some((room_id, width, height)) = parse_snapshot_resource(req.resource()) {
+            return Ok(match self.admin_request(req) {
+                Some(true) => self.snapshot(room_id, width, height).unwrap_or_else(not_found),
+                Some(false) => ws::Response::new(401, "Unauthorized", b"401 - Unauthorized".to_vec()),
+                None => not_found(),
+            });
+        }
+
+        if let Some(name) = req.resource().strip_prefix("/game/assets/") {
+            let package = self.resources.package();
+            return Ok(match package.assets.get(name) {
+                Some(asset) => ok(asset, asset_content_type(name)),
+                None => not_found(),
+            });
+        }
+
+        let (path, query) = match req.resource().find('?') {
+            Some(index) => (&req.resource()[..index], &req.resource()[index + 1..]),
+            None => (req.resource(), ""),
+        };
+        Ok(match path {
             "/" => ok(&self.resources.index(), b"text/html"),
             "/bundle.js" => ok(&self.resources.js(), b"application/javascript"),
             "/bundle.js.map" => {
@@ -180,11 +580,12 @@ impl ws::Handler for ConnectionHandler {
                 let sender = self.sender
                     .take()
                     .expect("multiple websocket connection requests on single connection");
+                let compression = query.split('&').any(|pair| pair == COMPRESSION_QUERY);
                 self.inner
                     .lock()
                     .unwrap()
                     .connections
-                    .insert(self.id, sender);
+                    .insert(self.id, Connection { sender, compression, sent_bytes: 0, confirmed_bytes: 0 });
                 ws::Response::from_request(req)?
             }
             "/game/code.wasm" => ok(&self.resources.package().wasm_module, b"application/wasm"),
@@ -197,6 +598,16 @@ impl ws::Handler for ConnectionHandler {
             ws::Message::Text(text) => text.into_bytes(),
             ws::Message::Binary(bytes) => bytes,
         };
+        // frames are limited by buffer size, but a message can be made of
+        // several of them
+        if data.len() > self.max_message_size {
+            let reason = format!("message is larger than {} bytes", self.max_message_size);
+            debug!("closing connection {:?}: {}", self.id, reason);
+            if let Some(connection) = self.inner.lock().unwrap().connections.get(&self.id) {
+                connection.sender.close_with_reason(ws::CloseCode::Size, reason).log_if_err();
+            }
+            return Ok(());
+        }
         self.events
             .send(Event::Message {
                 sender: self.id,
@@ -218,3 +629,62 @@ impl ws::Handler for ConnectionHandler {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_resources() {
+        assert_eq!(parse_snapshot_resource("/admin/rooms/42/snapshot.png"), Some((42, 640, 480)));
+        assert_eq!(
+            parse_snapshot_resource("/admin/rooms/7/snapshot.png?height=100&width=200"),
+            Some((7, 200, 100)),
+        );
+        assert_eq!(parse_snapshot_resource("/admin/rooms/7/snapshot.png?width=x"), None);
+        assert_eq!(parse_snapshot_resource("/admin/rooms/7/snapshot.png?depth=3"), None);
+        assert_eq!(parse_snapshot_resource("/admin/rooms/7/snapshot.png?width=4096"), None);
+        assert_eq!(parse_snapshot_resource("/admin/rooms/7/snapshot.png?width=0"), None);
+        assert_eq!(parse_snapshot_resource("/admin/rooms/7/snapshot.png?height=0"), None);
+        assert_eq!(parse_snapshot_resource("/admin/rooms/abc/snapshot.png"), None);
+        assert_eq!(parse_snapshot_resource("/admin/rooms/7/world.png"), None);
+    }
+
+    #[test]
+    fn long_close_reasons() {
+        assert_eq!(close_reason("malformed"), "malformed");
+        assert_eq!(close_reason(&"x".repeat(200)).len(), MAX_CLOSE_REASON);
+        let reason = format!("{}ė", "x".repeat(MAX_CLOSE_REASON - 1));
+        assert_eq!(close_reason(&reason), "x".repeat(MAX_CLOSE_REASON - 1));
+    }
+
+    fn inflate(data: &[u8]) -> String {
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::DeflateDecoder::new(data), &mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn compression() {
+        let mut stats = CompressionStats::default();
+        let json = format!("{{\"update\":[{}]}}", vec!["{\"frame\":1}"; 100].join(","));
+
+        let message = OutgoingMessage::new(Message::new(json.clone().into_bytes()), Some(100));
+        match message.frame(true, &mut stats) {
+            ws::Message::Binary(data) => assert_eq!(inflate(&data), json),
+            ws::Message::Text(_) => panic!("large message was not compressed"),
+        }
+        assert_eq!(message.frame(false, &mut stats), ws::Message::Text(json.clone()));
+
+        let message = OutgoingMessage::new(Message::new(json.clone().into_bytes()), None);
+        assert_eq!(message.frame(true, &mut stats), ws::Message::Text(json.clone()));
+        let message = OutgoingMessage::new(Message::new(b"{}".to_vec()), Some(1));
+        assert_eq!(message.frame(true, &mut stats), ws::Message::Text("{}".to_string()));
+
+        assert_eq!(stats.messages, 4);
+        assert_eq!(stats.compressed_messages, 1);
+        assert_eq!(stats.raw_bytes, 3 * json.len() as u64 + 2);
+        assert!(stats.sent_bytes < stats.raw_bytes);
+        assert!(stats.ratio() < 1.0);
+    }
+}
@@ -1,20 +1,70 @@
+use std::collections::BTreeMap;
 use std::convert::From;
+use std::fmt;
 use std::fs;
 use std::io::{self, prelude::*};
 use std::path::Path;
 use zip::result::ZipError;
+use crate::game::wasmi::sys::{self, ModuleError};
+
+const CODE_ENTRY: &str = "code.wasm";
+const ASSETS_DIRECTORY: &str = "assets/";
 
 #[derive(Debug, Clone)]
 pub struct Package {
     pub wasm_module: Vec<u8>,
+    /// Files from `assets/` directory of the package, by their path
+    /// relative to it.
+    pub assets: BTreeMap<String, Vec<u8>>,
 }
 
 #[derive(Debug)]
 pub enum LoadError {
     Io(io::Error),
-    MalformedPackage,
+    /// Package file is not a zip archive, or the archive is corrupted.
+    InvalidArchive(&'static str),
+    /// Package uses zip features that we can't read (for example, an
+    /// unsupported compression method).
+    UnsupportedArchive(&'static str),
+    /// Package does not contain a required file.
+    MissingEntry(&'static str),
+    /// A file in the package could not be read or decompressed.
+    MalformedEntry {
+        entry: &'static str,
+        reason: String,
+    },
+    /// Game code in the package is not a valid game module.
+    InvalidModule {
+        entry: &'static str,
+        error: ModuleError,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "{}", err),
+            LoadError::InvalidArchive(reason) => {
+                write!(f, "package is not a valid zip archive: {}", reason)
+            }
+            LoadError::UnsupportedArchive(reason) => {
+                write!(f, "package uses unsupported zip features: {}", reason)
+            }
+            LoadError::MissingEntry(entry) => {
+                write!(f, "package does not contain `{}`", entry)
+            }
+            LoadError::MalformedEntry { entry, reason } => {
+                write!(f, "failed to read `{}` from package: {}", entry, reason)
+            }
+            LoadError::InvalidModule { entry, error } => {
+                write!(f, "`{}` is not a valid game module: {}", entry, error)
+            }
+        }
+    }
 }
 
+impl std::error::Error for LoadError {}
+
 impl From<io::Error> for LoadError {
     fn from(err: io::Error) -> LoadError {
         LoadError::Io(err)
@@ -25,21 +75,149 @@ impl From<ZipError> for LoadError {
     fn from(err: ZipError) -> LoadError {
         match err {
             ZipError::Io(err) => LoadError::Io(err),
-            _ => LoadError::MalformedPackage,
+            ZipError::InvalidArchive(reason) => LoadError::InvalidArchive(reason),
+            ZipError::UnsupportedArchive(reason) => LoadError::UnsupportedArchive(reason),
+            // archive-level operations don't look up files by name
+            ZipError::FileNotFound => LoadError::InvalidArchive("file not found"),
         }
     }
 }
 
+fn entry_error(entry: &'static str, err: ZipError) -> LoadError {
+    match err {
+        ZipError::FileNotFound => LoadError::MissingEntry(entry),
+        ZipError::InvalidArchive(reason) |
+        ZipError::UnsupportedArchive(reason) => LoadError::MalformedEntry {
+            entry,
+            reason: reason.to_string(),
+        },
+        ZipError::Io(err) => LoadError::Io(err),
+    }
+}
+
+fn read_entry<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    entry: &'static str,
+) -> Result<Vec<u8>, LoadError> {
+    let mut file = archive.by_name(entry).map_err(|e| entry_error(entry, e))?;
+    let mut contents = Vec::new();
+    // Reading only fails on corrupted compressed data or a checksum mismatch,
+    // because the whole archive is already open at this point.
+    file.read_to_end(&mut contents).map_err(|e| LoadError::MalformedEntry {
+        entry,
+        reason: e.to_string(),
+    })?;
+    Ok(contents)
+}
+
 pub fn load_package<R: Read + Seek>(source: R) -> Result<Package, LoadError> {
     let mut archive = zip::ZipArchive::new(source)?;
-    let mut code_file = archive.by_name("code.wasm")?;
-    let mut code = Vec::new();
-    code_file.read_to_end(&mut code)?;
+    let code = read_entry(&mut archive, CODE_ENTRY)?;
+    sys::validate(&code).map_err(|error| LoadError::InvalidModule {
+        entry: CODE_ENTRY,
+        error,
+    })?;
+    let assets = read_assets(&mut archive)?;
     Ok(Package {
         wasm_module: code,
+        assets,
     })
 }
 
+fn read_assets<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Result<BTreeMap<String, Vec<u8>>, LoadError> {
+    let mut assets = BTreeMap::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = match file.name().strip_prefix(ASSETS_DIRECTORY) {
+            Some(name) if !name.is_empty() && !name.ends_with('/') => name.to_string(),
+            _ => continue,
+        };
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).map_err(|e| LoadError::MalformedEntry {
+            entry: ASSETS_DIRECTORY,
+            reason: format!("{}: {}", name, e),
+        })?;
+        assets.insert(name, contents);
+    }
+    Ok(assets)
+}
+
 pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Package, LoadError> {
     load_package(fs::File::open(path)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn zip_with(files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for &(name, mut contents) in files {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            io::copy(&mut contents, &mut zip).unwrap();
+        }
+        let mut buffer = zip.finish().unwrap();
+        buffer.set_position(0);
+        buffer
+    }
+
+    #[test]
+    fn not_a_zip() {
+        let result = load_package(Cursor::new(b"definitely not a zip".to_vec()));
+        match result {
+            Err(LoadError::InvalidArchive(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_code() {
+        let result = load_package(zip_with(&[("assets/foo.png", b"")]));
+        match result {
+            Err(LoadError::MissingEntry("code.wasm")) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn code_is_not_wasm() {
+        let result = load_package(zip_with(&[("code.wasm", b"hello world")]));
+        match result {
+            Err(LoadError::InvalidModule { entry: "code.wasm", error: ModuleError::BadMagic }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsupported_wasm_version() {
+        let result = load_package(zip_with(&[("code.wasm", b"\0asm\x02\0\0\0")]));
+        match result {
+            Err(LoadError::InvalidModule { error: ModuleError::UnsupportedVersion(2), .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn assets_are_loaded() {
+        let zip = zip_with(&[
+            ("code.wasm", b""),
+            ("assets/player.png", b"png"),
+            ("assets/sprites/wall.png", b"wall"),
+            ("readme.txt", b"not an asset"),
+        ]);
+        let assets = read_assets(&mut zip::ZipArchive::new(zip).unwrap()).unwrap();
+        let names: Vec<_> = assets.keys().map(String::as_str).collect();
+        assert_eq!(names, ["player.png", "sprites/wall.png"]);
+        assert_eq!(assets["sprites/wall.png"], b"wall");
+    }
+
+    #[test]
+    fn empty_module_is_missing_exports() {
+        let result = load_package(zip_with(&[("code.wasm", b"\0asm\x01\0\0\0")]));
+        match result {
+            Err(LoadError::InvalidModule { error: ModuleError::MissingExport("initialize"), .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -1,27 +1,173 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str;
 use serde_derive::{Deserialize, Serialize};
+use crate::game::{FrameUpdate, Game, ToBlob};
+use crate::server;
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Update {
+    pub seed: u32,
     pub new_players: Vec<u64>,
     pub removed_players: Vec<u64>,
     pub inputs: HashMap<u64, Vec<u8>>,
 }
 
+impl<G: Game> From<FrameUpdate<G>> for Update {
+    fn from(update: FrameUpdate<G>) -> Update {
+        Update {
+            seed: update.seed,
+            new_players: update
+                .new_players
+                .into_iter()
+                .map(|p| p.into())
+                .collect(),
+            removed_players: update
+                .removed_players
+                .into_iter()
+                .map(|p| p.into())
+                .collect(),
+            inputs: update
+                .player_inputs
+                .into_iter()
+                .map(|(p, i)| (p.into(), i.to_blob()))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct World {
     pub frame: u64,
     pub local_player_id: u64,
+    #[serde(flatten)]
+    pub snapshot: Snapshot,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Snapshot {
+    /// Whole serialized world.
+    World(Vec<u8>),
+    /// Difference from a keyframe that was sent before.
+    Delta(Delta),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delta {
+    /// Frame of the keyframe.
+    pub keyframe: u64,
+    /// Made by game code's `diff_world` export, instead of `delta::diff`.
+    pub from_game: bool,
+    pub delta: Vec<u8>,
+}
+
+/// World that following snapshots can be differences from. Clients only
+/// need to keep the latest one.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Keyframe {
+    pub frame: u64,
     pub world: Vec<u8>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewKeyframe {
+    pub keyframe: Keyframe,
+}
+
+/// Game code was replaced. Client should fetch it again and continue from
+/// the given world.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reload {
+    pub reload: World,
+}
+
+/// Client fell too far behind and updates were skipped, it should continue
+/// from the given world instead.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Resync {
+    pub resync: World,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ping {
+    pub id: u64,
+}
+
+/// Sent periodically to measure round trip time, client should answer with
+/// `ClientMessage::Pong` right away.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PingMessage {
+    pub ping: Ping,
+}
+
+/// Where the server is in time, sent after each answered ping so that
+/// clients can tell how far ahead of it they should send their inputs.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSync {
+    /// Next frame that the server will simulate.
+    pub frame: u64,
+    /// Fraction of frame time that has passed since the previous frame, from
+    /// 0 to 1.
+    pub phase: f64,
+    /// Milliseconds per frame.
+    pub frame_time: f64,
+    /// Smoothed round trip time in milliseconds.
+    pub rtt: f64,
+    /// Mean deviation of round trip time in milliseconds.
+    pub jitter: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSyncMessage {
+    pub time_sync: TimeSync,
+}
+
+/// Client should send its inputs `change` frames earlier, or later if it is
+/// negative.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputAdvice {
+    pub change: i64,
+    /// Inputs that arrived too late and were dropped since the last advice.
+    pub late_inputs: u64,
+}
+
+impl From<server::InputAdvice> for InputAdvice {
+    fn from(advice: server::InputAdvice) -> InputAdvice {
+        InputAdvice {
+            change: advice.change,
+            late_inputs: advice.late_inputs,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputAdviceMessage {
+    pub input_advice: InputAdvice,
+}
+
 #[derive(Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum ClientMessage {
     Join { frame: u64 },
     Input { frame: u64, input: Vec<u8> },
+    Pong { id: u64 },
+    /// Client has applied updates up to `frame`, sent every
+    /// `send_queue::ACK_INTERVAL` frames.
+    Ack { frame: u64 },
 }
 
 pub fn world_to_json(world: &World) -> String {
@@ -32,11 +178,56 @@ pub fn update_to_json(update: &Update) -> String {
     serde_json::to_string(&update).expect("failed to serialize")
 }
 
-#[derive(Debug)]
-pub struct DeserializeError;
+pub fn reload_to_json(reload: &Reload) -> String {
+    serde_json::to_string(&reload).expect("failed to serialize")
+}
+
+pub fn resync_to_json(resync: &Resync) -> String {
+    serde_json::to_string(&resync).expect("failed to serialize")
+}
+
+pub fn keyframe_to_json(keyframe: &NewKeyframe) -> String {
+    serde_json::to_string(&keyframe).expect("failed to serialize")
+}
+
+pub fn ping_to_json(ping: &PingMessage) -> String {
+    serde_json::to_string(&ping).expect("failed to serialize")
+}
+
+pub fn time_sync_to_json(time_sync: &TimeSyncMessage) -> String {
+    serde_json::to_string(&time_sync).expect("failed to serialize")
+}
+
+pub fn input_advice_to_json(advice: &InputAdviceMessage) -> String {
+    serde_json::to_string(&advice).expect("failed to serialize")
+}
+
+/// Why a client's message couldn't be read.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeserializeError {
+    /// Bytes after `valid_up_to` are not valid UTF-8.
+    InvalidUtf8 { valid_up_to: usize },
+    /// Message is not JSON of any `ClientMessage`, with serde's description.
+    Malformed(String),
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeserializeError::InvalidUtf8 { valid_up_to } => write!(f, "invalid UTF-8 after byte {}", valid_up_to),
+            DeserializeError::Malformed(e) => write!(f, "{}", e),
+        }
+    }
+}
 
 pub fn message_from_json(json: &str) -> Result<ClientMessage, DeserializeError> {
-    serde_json::from_str(json).map_err(|_| DeserializeError)
+    serde_json::from_str(json).map_err(|e| DeserializeError::Malformed(e.to_string()))
+}
+
+/// Message as received from the network, which might not even be text.
+pub fn message_from_bytes(bytes: &[u8]) -> Result<ClientMessage, DeserializeError> {
+    let json = str::from_utf8(bytes).map_err(|e| DeserializeError::InvalidUtf8 { valid_up_to: e.valid_up_to() })?;
+    message_from_json(json)
 }
 
 #[cfg(test)]
@@ -48,7 +239,7 @@ mod tests {
         let world = World {
             frame: 123,
             local_player_id: 4,
-            world: vec![4, 5, 6],
+            snapshot: Snapshot::World(vec![4, 5, 6]),
         };
         let json = world_to_json(&world);
         assert_eq!(
@@ -57,9 +248,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn delta_serialization() {
+        let world = World {
+            frame: 123,
+            local_player_id: 4,
+            snapshot: Snapshot::Delta(Delta { keyframe: 120, from_game: false, delta: vec![1, 2] }),
+        };
+        assert_eq!(
+            world_to_json(&world),
+            r#"  {"frame":123,"localPlayerId":4,"delta":{"keyframe":120,"fromGame":false,"delta":[1,2]}}  "#.trim(),
+        );
+        let keyframe = NewKeyframe { keyframe: Keyframe { frame: 120, world: vec![3] } };
+        assert_eq!(
+            keyframe_to_json(&keyframe),
+            r#"  {"keyframe":{"frame":120,"world":[3]}}  "#.trim(),
+        );
+    }
+
     #[test]
     fn update_serialization() {
         let update = Update {
+            seed: 0xdead_beef,
             new_players: vec![1],
             removed_players: vec![2],
             inputs: {
@@ -71,7 +281,23 @@ mod tests {
         let json = update_to_json(&update);
         assert_eq!(
             json,
-            r#"  {"newPlayers":[1],"removedPlayers":[2],"inputs":{"3":[4]}}  "#.trim(),
+            r#"  {"seed":3735928559,"newPlayers":[1],"removedPlayers":[2],"inputs":{"3":[4]}}  "#.trim(),
+        );
+    }
+
+    #[test]
+    fn reload_serialization() {
+        let reload = Reload {
+            reload: World {
+                frame: 123,
+                local_player_id: 4,
+                snapshot: Snapshot::World(vec![4, 5, 6]),
+            },
+        };
+        let json = reload_to_json(&reload);
+        assert_eq!(
+            json,
+            r#"  {"reload":{"frame":123,"localPlayerId":4,"world":[4,5,6]}}  "#.trim(),
         );
     }
 
@@ -87,6 +313,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn latency_messages() {
+        let ping = PingMessage { ping: Ping { id: 3 } };
+        assert_eq!(ping_to_json(&ping), r#"{"ping":{"id":3}}"#);
+        let time_sync = TimeSyncMessage {
+            time_sync: TimeSync { frame: 120, phase: 0.25, frame_time: 16.5, rtt: 40.0, jitter: 2.5 },
+        };
+        assert_eq!(
+            time_sync_to_json(&time_sync),
+            r#"  {"timeSync":{"frame":120,"phase":0.25,"frameTime":16.5,"rtt":40.0,"jitter":2.5}}  "#.trim(),
+        );
+        let advice = InputAdviceMessage { input_advice: InputAdvice { change: -1, late_inputs: 2 } };
+        assert_eq!(input_advice_to_json(&advice), r#"{"inputAdvice":{"change":-1,"lateInputs":2}}"#);
+        let pong = message_from_json(r#"{ "pong": { "id": 3 } }"#).expect("failed to deserialize");
+        assert_eq!(pong, ClientMessage::Pong { id: 3 });
+        let ack = message_from_json(r#"{"ack":{"frame":120}}"#).expect("failed to deserialize");
+        assert_eq!(ack, ClientMessage::Ack { frame: 120 });
+    }
+
     #[test]
     fn client_input_deserialization() {
         let json = r#"
@@ -98,4 +343,79 @@ mod tests {
             ClientMessage::Input { frame: 123, input: vec![4, 5, 6] },
         );
     }
+
+    /// Deterministic pseudo-random numbers, so that failures can be
+    /// reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, below: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            ((self.0 >> 33) % below as u64) as usize
+        }
+    }
+
+    const VALID_MESSAGES: &[&str] = &[
+        r#"{"join":{"frame":123}}"#,
+        r#"{"input":{"frame":5,"input":[0,255,7]}}"#,
+        r#"{"pong":{"id":18446744073709551615}}"#,
+    ];
+
+    #[test]
+    fn fuzzed_messages() {
+        let mut rng = Rng(1);
+        // pieces of valid messages are more likely to get past the first few
+        // bytes than completely random text
+        let pieces = [
+            "{", "}", "[", "]", ":", ",", "\"", "join", "input", "pong", "frame", "id", "0", "-1", "256",
+            "1e400", "18446744073709551616", "null", "true", " ", "\\u0000", "\\ud800", "é",
+        ];
+        for _ in 0..20_000 {
+            let text: String = (0..rng.next(20)).map(|_| pieces[rng.next(pieces.len())]).collect();
+            let _ = message_from_json(&text);
+        }
+
+        for _ in 0..20_000 {
+            let mut bytes = VALID_MESSAGES[rng.next(VALID_MESSAGES.len())].as_bytes().to_vec();
+            for _ in 0..1 + rng.next(4) {
+                let position = rng.next(bytes.len() + 1);
+                match rng.next(3) {
+                    0 => bytes.insert(position, rng.next(256) as u8),
+                    1 if position < bytes.len() => { bytes.remove(position); }
+                    _ if position < bytes.len() => bytes[position] = rng.next(256) as u8,
+                    _ => {}
+                }
+            }
+            if let Ok(ClientMessage::Input { input, .. }) = message_from_bytes(&bytes) {
+                assert!(input.len() < bytes.len());
+            }
+        }
+
+        for _ in 0..2_000 {
+            let bytes: Vec<u8> = (0..rng.next(64)).map(|_| rng.next(256) as u8).collect();
+            let _ = message_from_bytes(&bytes);
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        for json in VALID_MESSAGES {
+            assert!(message_from_json(json).is_ok(), "{}", json);
+        }
+        assert_eq!(message_from_bytes(b"{\"join\":\xff}"), Err(DeserializeError::InvalidUtf8 { valid_up_to: 8 }));
+        let deeply_nested = "[".repeat(100_000);
+        assert!(message_from_json(&deeply_nested).is_err());
+        for json in &[
+            "",
+            "{}",
+            r#"{"join":{"frame":-1}}"#,
+            r#"{"join":{"frame":1.5}}"#,
+            r#"{"input":{"frame":1,"input":[256]}}"#,
+            r#"{"pong":{}}"#,
+            r#"{"join":{"frame":1},"pong":{"id":1}}"#,
+            r#"{"leave":{}}"#,
+        ] {
+            assert!(message_from_json(json).is_err(), "{}", json);
+        }
+    }
 }
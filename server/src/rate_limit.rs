@@ -0,0 +1,63 @@
+//! Limit on how often a client can send messages, so that a misbehaving one
+//! can't make the server spend all its time on it.
+
+use std::time::Instant;
+
+/// Token bucket: each message takes a token, and tokens are refilled at a
+/// fixed rate up to `burst`, so short bursts are fine as long as the average
+/// rate stays below `per_second`.
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimit {
+    /// Limit that starts with a full bucket at `now`.
+    pub fn new(per_second: u32, burst: u32, now: Instant) -> RateLimit {
+        RateLimit {
+            per_second: f64::from(per_second),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token for a message received at `now`, returns false if there
+    /// are none left.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn flooding() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(10, 20, start);
+        assert_eq!((0..100).filter(|_| limit.allow(start)).count(), 20);
+        // half a second refills 5 tokens
+        let later = start + Duration::from_millis(500);
+        assert_eq!((0..100).filter(|_| limit.allow(later)).count(), 5);
+        // steady rate below the limit is never refused
+        for i in 1..=1000 {
+            assert!(limit.allow(later + Duration::from_millis(100 * i)));
+        }
+        // tokens don't accumulate above burst
+        let much_later = later + Duration::from_secs(1000);
+        assert_eq!((0..100).filter(|_| limit.allow(much_later)).count(), 20);
+    }
+}
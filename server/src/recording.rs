@@ -0,0 +1,96 @@
+//! Recorded games, for replaying them later. A recording is a JSON lines
+//! file: the first line holds the world that recording started from, and
+//! each following line holds the update for the next frame, in the same form
+//! as it was broadcast to clients.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::Path;
+use serde_derive::{Deserialize, Serialize};
+use crate::protocol::Update;
+
+#[derive(Serialize, Deserialize)]
+pub struct Start {
+    /// Frame that the first recorded update is for.
+    pub frame: u64,
+    pub world: Vec<u8>,
+}
+
+pub struct Recording {
+    pub start: Start,
+    pub updates: Vec<Update>,
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    Empty,
+    Malformed {
+        line: usize,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Io(err) => write!(f, "{}", err),
+            RecordingError::Empty => write!(f, "recording is empty"),
+            RecordingError::Malformed { line, error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(err: io::Error) -> RecordingError {
+        RecordingError::Io(err)
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Recording, RecordingError> {
+    let mut lines = BufReader::new(File::open(path)?).lines().enumerate();
+    let start = match lines.next() {
+        Some((_, line)) => serde_json::from_str(&line?)
+            .map_err(|error| RecordingError::Malformed { line: 1, error })?,
+        None => return Err(RecordingError::Empty),
+    };
+    let mut updates = Vec::new();
+    for (index, line) in lines {
+        let update = serde_json::from_str(&line?)
+            .map_err(|error| RecordingError::Malformed { line: index + 1, error })?;
+        updates.push(update);
+    }
+    Ok(Recording { start, updates })
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Ok(Recorder {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    /// Must be called once, before recording any updates.
+    pub fn record_start(&mut self, frame: u64, world: &[u8]) -> io::Result<()> {
+        let start = Start { frame, world: world.to_vec() };
+        self.write_line(&serde_json::to_string(&start).expect("failed to serialize"))
+    }
+
+    pub fn record_update(&mut self, update: &Update) -> io::Result<()> {
+        self.write_line(&serde_json::to_string(update).expect("failed to serialize"))
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", line)?;
+        // Server might be killed at any moment, and recording should contain
+        // everything up to that point.
+        self.writer.flush()
+    }
+}
@@ -1,14 +1,17 @@
 use std::borrow::Cow;
 use std::fs;
+use std::sync::{Arc, RwLock};
 use crate::package::Package;
 
 pub struct ServerResources {
-    pub package: Package,
+    package: RwLock<Arc<Package>>,
 }
 
 impl ServerResources {
     pub fn load(package: Package) -> ServerResources {
-        ServerResources { package }
+        ServerResources {
+            package: RwLock::new(Arc::new(package)),
+        }
     }
 
     pub fn index(&self) -> Cow<'_, [u8]> {
@@ -27,8 +30,13 @@ impl ServerResources {
         fs::read(CSS_PATH).unwrap_or_else(|_| panic!("failed to read {}", CSS_PATH)).into()
     }
 
-    pub fn package(&self) -> &Package {
-        &self.package
+    pub fn package(&self) -> Arc<Package> {
+        self.package.read().unwrap().clone()
+    }
+
+    /// Replace the package that is served to clients.
+    pub fn set_package(&self, package: Package) {
+        *self.package.write().unwrap() = Arc::new(package);
     }
 }
 
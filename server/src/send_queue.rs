@@ -0,0 +1,171 @@
+//! Updates that were sent to a client but that it hasn't applied yet. ws-rs
+//! buffers everything that is sent without telling how much of it went out,
+//! so clients acknowledge applied frames instead, and the difference tells
+//! how far behind they are.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Clients acknowledge every frame that is a multiple of this, must be the
+/// same as `ackInterval` in the client.
+pub const ACK_INTERVAL: u64 = 10;
+
+/// What to do with clients whose queue grows over the limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    Disconnect,
+    /// Stop sending updates until the client applies the ones that were
+    /// already sent, and then send it a fresh world to continue from.
+    Resync,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SlowClientPolicy, String> {
+        match s {
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            "resync" => Ok(SlowClientPolicy::Resync),
+            _ => Err(format!("unknown policy `{}`, expected `disconnect` or `resync`", s)),
+        }
+    }
+}
+
+impl fmt::Display for SlowClientPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlowClientPolicy::Disconnect => write!(f, "disconnect"),
+            SlowClientPolicy::Resync => write!(f, "resync"),
+        }
+    }
+}
+
+pub struct SendQueue {
+    /// Frame that the client will simulate next, as of its last
+    /// acknowledgement.
+    acknowledged: u64,
+    /// Frame after the last update that was sent. Client can't be further
+    /// than that, whatever it acknowledges.
+    sent_until: u64,
+    /// Frame of the first update that wasn't sent because the client fell
+    /// behind, `None` if updates are being sent.
+    paused_at: Option<u64>,
+    /// Largest depth since the last `take_peak`.
+    peak: u64,
+}
+
+impl SendQueue {
+    /// Queue of a client that was sent the world on `frame`.
+    pub fn new(frame: u64) -> SendQueue {
+        SendQueue {
+            acknowledged: frame,
+            sent_until: frame,
+            paused_at: None,
+            peak: 0,
+        }
+    }
+
+    pub fn acknowledge(&mut self, frame: u64) {
+        self.acknowledged = self.acknowledged.max(frame.min(self.sent_until));
+    }
+
+    /// Number of updates that were sent but not applied, if server is about
+    /// to simulate `frame`.
+    pub fn depth(&self, frame: u64) -> u64 {
+        self.paused_at.unwrap_or(frame).saturating_sub(self.acknowledged)
+    }
+
+    /// Update for `frame` was sent, returns the new depth.
+    pub fn sent(&mut self, frame: u64) -> u64 {
+        self.sent_until = self.sent_until.max(frame + 1);
+        let depth = self.depth(frame + 1);
+        self.peak = self.peak.max(depth);
+        depth
+    }
+
+    pub fn take_peak(&mut self) -> u64 {
+        std::mem::replace(&mut self.peak, 0)
+    }
+
+    /// Stop sending updates from `frame` on.
+    pub fn pause(&mut self, frame: u64) {
+        self.paused_at = Some(frame);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Whether a paused client has applied everything that was sent to it,
+    /// give or take the frames since its last acknowledgement.
+    pub fn caught_up(&self) -> bool {
+        self.paused_at.is_some_and(|paused_at| self.acknowledged + ACK_INTERVAL >= paused_at)
+    }
+
+    /// Client was sent the world on `frame`, updates can be sent again.
+    pub fn resume(&mut self, frame: u64) {
+        self.paused_at = None;
+        self.acknowledged = frame;
+        self.sent_until = frame;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falling_behind() {
+        let mut queue = SendQueue::new(100);
+        assert_eq!(queue.sent(100), 1);
+        assert_eq!(queue.sent(101), 2);
+        queue.acknowledge(102);
+        assert_eq!(queue.depth(102), 0);
+        for frame in 102..150 {
+            queue.sent(frame);
+        }
+        assert_eq!(queue.depth(150), 48);
+        // acknowledgements can arrive out of order with other messages
+        queue.acknowledge(110);
+        queue.acknowledge(90);
+        assert_eq!(queue.depth(150), 40);
+        assert_eq!(queue.take_peak(), 48);
+        assert_eq!(queue.take_peak(), 0);
+
+        queue.pause(150);
+        assert!(queue.is_paused() && !queue.caught_up());
+        // nothing more is sent while paused
+        assert_eq!(queue.depth(200), 40);
+        queue.acknowledge(140);
+        assert!(queue.caught_up());
+        queue.resume(200);
+        assert!(!queue.is_paused());
+        assert_eq!(queue.sent(200), 1);
+    }
+
+    #[test]
+    fn acknowledging_unsent_frames() {
+        let mut queue = SendQueue::new(100);
+        for frame in 100..110 {
+            queue.sent(frame);
+        }
+        // client can't be past what was sent to it
+        queue.acknowledge(u64::MAX);
+        assert_eq!(queue.depth(110), 0);
+        for frame in 110..150 {
+            queue.sent(frame);
+        }
+        assert_eq!(queue.depth(150), 40);
+        queue.resume(200);
+        queue.acknowledge(300);
+        assert_eq!(queue.sent(200), 1);
+    }
+
+    #[test]
+    fn policy_names() {
+        for policy in &[SlowClientPolicy::Disconnect, SlowClientPolicy::Resync] {
+            assert_eq!(policy.to_string().parse(), Ok(*policy));
+        }
+        assert!("drop".parse::<SlowClientPolicy>().is_err());
+    }
+}
@@ -1,9 +1,64 @@
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
 use log::trace;
 use crate::game::{FrameUpdate, Game};
 
-pub struct BadJoinError;
-pub struct BadInputError;
+/// Why a client couldn't join, it should be disconnected then.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BadJoinError {
+    AlreadyJoined,
+    InPast { frame: u64, current: u64 },
+    TooFarAhead { frame: u64, current: u64 },
+}
+
+impl fmt::Display for BadJoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BadJoinError::AlreadyJoined => write!(f, "tried to join multiple times"),
+            BadJoinError::InPast { frame, current } => {
+                write!(f, "tried to join on frame {} while server is already on frame {}", frame, current)
+            }
+            BadJoinError::TooFarAhead { frame, current } => write!(
+                f,
+                "tried to join on frame {} while server is on frame {}, at most {} frames ahead are allowed",
+                frame,
+                current,
+                MAX_LOOK_AHEAD,
+            ),
+        }
+    }
+}
+
+/// Why an input was rejected, the client should be disconnected then.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BadInputError {
+    NotJoined,
+    /// Game code couldn't deserialize the input.
+    Invalid,
+    /// Input is not for the frame that was expected next.
+    UnexpectedFrame { frame: u64, expected: u64 },
+    TooFarAhead { frame: u64, current: u64 },
+}
+
+impl fmt::Display for BadInputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BadInputError::NotJoined => write!(f, "sent inputs before joining"),
+            BadInputError::Invalid => write!(f, "sent an invalid input"),
+            BadInputError::UnexpectedFrame { frame, expected } => {
+                write!(f, "sent input for frame {} when frame {} was expected", frame, expected)
+            }
+            BadInputError::TooFarAhead { frame, current } => write!(
+                f,
+                "sent input for frame {} while server is on frame {}, at most {} frames ahead are allowed",
+                frame,
+                current,
+                MAX_LOOK_AHEAD,
+            ),
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Copy, Clone)]
 pub struct ClientId(u64);
@@ -14,29 +69,298 @@ pub struct WorldState<'a, G: Game> {
     pub world: &'a G::World,
 }
 
+/// Clients are sent worlds as differences from a keyframe, which is replaced
+/// by a newer world after this many frames.
+pub const KEYFRAME_INTERVAL: u64 = 600;
+
+/// Clients can join and send inputs at most this many frames after the one
+/// that server will simulate next, so that they can't make it queue an
+/// unbounded number of inputs.
+pub const MAX_LOOK_AHEAD: u64 = 120;
+
+/// Clients are advised how to change their input lead once per this many
+/// frames.
+pub const ADVICE_INTERVAL: u64 = 60;
+
+/// How many frames before being simulated inputs should arrive. Inputs that
+/// arrive later than that might be late when the connection gets worse, and
+/// ones that arrive earlier make the game respond slower than it could.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InputMargin {
+    pub min: i64,
+    pub max: i64,
+}
+
+impl Default for InputMargin {
+    fn default() -> Self {
+        InputMargin { min: 1, max: 4 }
+    }
+}
+
+/// Advice for a client to send its inputs `change` frames earlier (or later
+/// if negative).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InputAdvice {
+    pub change: i64,
+    /// Inputs that arrived after their frame was simulated since the last
+    /// advice, those were dropped.
+    pub late_inputs: u64,
+}
+
+/// Number of buckets in `ClientStats::early_inputs`.
+pub const EARLY_BUCKETS: usize = 16;
+
+/// Counters of a client's inputs since it joined.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Inputs that arrived after their frame was simulated, those were
+    /// dropped.
+    pub late_inputs: u64,
+    /// Frames that were simulated while the client was in game, but without
+    /// its input.
+    pub missing_inputs: u64,
+    /// Frames that the client didn't send inputs for and sent inputs for
+    /// later frames instead, only possible when gaps are allowed.
+    pub skipped_inputs: u64,
+    /// `early_inputs[n]` is the number of inputs that arrived `n` frames
+    /// before their frame was simulated. The last bucket also counts inputs
+    /// that arrived even earlier.
+    pub early_inputs: [u64; EARLY_BUCKETS],
+    /// Inputs that were received but not simulated yet.
+    pub queue_depth: usize,
+}
+
+impl fmt::Display for ClientStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} late, {} missing, {} skipped, {} queued, early by",
+            self.late_inputs,
+            self.missing_inputs,
+            self.skipped_inputs,
+            self.queue_depth,
+        )?;
+        let mut any = false;
+        for (frames, &count) in self.early_inputs.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            let plus = if frames == EARLY_BUCKETS - 1 { "+" } else { "" };
+            write!(f, " {}{}: {}", frames, plus, count)?;
+            any = true;
+        }
+        if !any {
+            write!(f, " -")?;
+        }
+        Ok(())
+    }
+}
+
+/// World from an earlier frame that is kept to make differences from.
+pub struct Keyframe<G: Game> {
+    pub frame: u64,
+    pub world: G::World,
+}
+
 pub struct Server<G: Game> {
     game: G,
+    /// Identifies the game that this server is running, so that random
+    /// numbers differ between games.
+    room_id: u64,
     frame: u64,
     world: G::World,
+    keyframe: Option<Keyframe<G>>,
+    input_margin: InputMargin,
+    /// Whether clients can skip frames when sending inputs.
+    allow_input_gaps: bool,
     clients: HashMap<ClientId, ClientState<G>>,
     /// Players that need to be removed in the next game tick.
     removed_players: Vec<G::PlayerId>,
+    /// Players that were in game when it was restarted, and need to be added
+    /// again in the next game tick.
+    restarted_players: Vec<G::PlayerId>,
     next_client_id: u64,
 }
 
+/// Seed for random numbers on the given frame. Seeds of all frames look
+/// unrelated to each other, even for similar room ids.
+fn frame_seed(room_id: u64, frame: u64) -> u32 {
+    // splitmix64 finalizer
+    fn mix(mut z: u64) -> u64 {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    (mix(mix(room_id) ^ frame) >> 32) as u32
+}
+
 impl<G: Game> Server<G> {
-    pub fn new(mut game: G) -> Self {
+    pub fn new(game: G) -> Self {
+        Self::with_room_id(game, 0)
+    }
+
+    pub fn with_room_id(mut game: G, room_id: u64) -> Self {
+        game.set_random_seed(frame_seed(room_id, 0));
+        game.set_log_frame(room_id, 0);
         let world = game.initial_world();
         Server {
             game,
+            room_id,
             frame: 0,
             world,
+            keyframe: None,
+            input_margin: InputMargin::default(),
+            allow_input_gaps: false,
             clients: HashMap::new(),
             removed_players: Vec::new(),
+            restarted_players: Vec::new(),
             next_client_id: 0,
         }
     }
 
+    /// Id of the room that this server runs, also used to seed game's random
+    /// numbers.
+    pub fn room_id(&self) -> u64 {
+        self.room_id
+    }
+
+    /// Frame that the current world will be updated on next.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn world(&self) -> &G::World {
+        &self.world
+    }
+
+    pub fn game(&self) -> &G {
+        &self.game
+    }
+
+    /// Latest keyframe, there is none until the first game tick and after
+    /// game code is replaced.
+    pub fn keyframe(&self) -> Option<&Keyframe<G>> {
+        self.keyframe.as_ref()
+    }
+
+    pub fn set_input_margin(&mut self, margin: InputMargin) {
+        self.input_margin = margin;
+    }
+
+    /// Let clients skip frames when sending inputs, instead of disconnecting
+    /// them. Skipped frames are simulated without their input, same as when
+    /// it arrives too late.
+    pub fn set_allow_input_gaps(&mut self, allow: bool) {
+        self.allow_input_gaps = allow;
+    }
+
+    /// Input counters of a client, `None` if there is no such client.
+    /// Clients that haven't joined yet have no inputs.
+    pub fn client_stats(&self, client: ClientId) -> Option<ClientStats> {
+        Some(match self.clients.get(&client)? {
+            ClientState::Connected(_) => ClientStats::default(),
+            ClientState::WaitingForJoin(WaitingClient { inputs, .. }) |
+            ClientState::InGame(InGameClient { inputs, .. }) => ClientStats {
+                queue_depth: inputs.inputs.len(),
+                ..inputs.stats.clone()
+            },
+        })
+    }
+
+    /// Advice for clients whose inputs arrive too late or too early, once per
+    /// `ADVICE_INTERVAL` frames. Only the smallest margin that inputs arrived
+    /// with is kept within `InputMargin`, so that a few delayed messages are
+    /// enough to raise the lead. Lead is raised right away because late
+    /// inputs are lost, and lowered one frame at a time.
+    pub fn input_advice(&mut self) -> HashMap<ClientId, InputAdvice> {
+        let mut advice = HashMap::new();
+        if !self.frame.is_multiple_of(ADVICE_INTERVAL) {
+            return advice;
+        }
+        let margin = self.input_margin;
+        for (&id, client) in &mut self.clients {
+            let timing = match client {
+                ClientState::Connected(_) => continue,
+                ClientState::WaitingForJoin(WaitingClient { inputs, .. }) |
+                ClientState::InGame(InGameClient { inputs, .. }) => &mut inputs.timing,
+            };
+            let (late_inputs, smallest_margin) = (timing.late_inputs, timing.smallest_margin);
+            *timing = InputTiming::default();
+            let change = match smallest_margin {
+                None => continue,
+                Some(smallest) if smallest < margin.min => margin.min - smallest,
+                Some(smallest) if smallest > margin.max => -1,
+                Some(_) => 0,
+            };
+            if change != 0 || late_inputs > 0 {
+                advice.insert(id, InputAdvice { change, late_inputs });
+            }
+        }
+        advice
+    }
+
+    /// Current world as seen by the given client. Returns `None` if there is
+    /// no such client.
+    pub fn client_world(&self, client: ClientId) -> Option<WorldState<'_, G>> {
+        let player_id = match self.clients.get(&client)? {
+            ClientState::Connected(player_id) => *player_id,
+            ClientState::WaitingForJoin(WaitingClient { player_id, .. }) |
+            ClientState::InGame(InGameClient { player_id, .. }) => *player_id,
+        };
+        Some(WorldState {
+            frame: self.frame,
+            local_player_id: player_id.into(),
+            world: &self.world,
+        })
+    }
+
+    /// Client fell behind and was sent the current world instead of the
+    /// updates it missed, so its next input can skip the frames in between,
+    /// even if gaps are not allowed otherwise. Inputs that it sent before
+    /// getting the world don't use up the gap.
+    pub fn client_resynced(&mut self, client: ClientId) {
+        let frame = self.frame;
+        match self.clients.get_mut(&client) {
+            None | Some(ClientState::Connected(_)) => {}
+            Some(ClientState::WaitingForJoin(WaitingClient { inputs, .. })) |
+            Some(ClientState::InGame(InGameClient { inputs, .. })) => {
+                inputs.resynced_on = Some(frame);
+            }
+        }
+    }
+
+    /// Replace game code while clients stay connected. If `world` is `None`
+    /// then the game is restarted from the initial world, and players that
+    /// were in game are added to it again in the next game tick. Inputs that
+    /// were received but not applied yet are dropped, because new code might
+    /// not be able to read them. Clients should be sent the new world.
+    pub fn replace_game(&mut self, mut game: G, world: Option<G::World>) {
+        for client in self.clients.values_mut() {
+            match client {
+                ClientState::Connected(_) => {}
+                ClientState::WaitingForJoin(WaitingClient { inputs, .. }) => {
+                    inputs.clear();
+                }
+                ClientState::InGame(InGameClient { inputs, player_id }) => {
+                    inputs.clear();
+                    if world.is_none() {
+                        self.restarted_players.push(*player_id);
+                    }
+                }
+            }
+        }
+        // old worlds can only be read by old code
+        self.keyframe = None;
+        game.set_log_frame(self.room_id, self.frame);
+        self.world = match world {
+            Some(world) => world,
+            None => {
+                // removed players are not in the new world anyway
+                self.removed_players.clear();
+                game.set_random_seed(frame_seed(self.room_id, self.frame));
+                game.initial_world()
+            }
+        };
+        self.game = game;
+    }
+
     /// A new client connected to the server. Returned world should be sent to
     /// that client.
     pub fn client_connected(&mut self) -> (ClientId, WorldState<'_, G>) {
@@ -60,67 +384,72 @@ impl<G: Game> Server<G> {
     /// A client that has already connected wants to join the game. The first
     /// input that the client can send after joining must be for the next frame.
     /// Server might arbitrarily decide that the client cannot join the game
-    /// (for example, if client wants to join too far in the past or in the
-    /// future). In that case the client should be disconnected.
+    /// (for example, if client wants to join in the past or more than
+    /// `MAX_LOOK_AHEAD` frames in the future). In that case the client should
+    /// be disconnected.
     pub fn client_joined(&mut self, client: ClientId, on_frame: u64) -> Result<(), BadJoinError> {
         let result = match self.clients.get(&client) {
             None => panic!("client joined without connecting"),
             Some(ClientState::WaitingForJoin { .. }) |
             Some(ClientState::InGame(_)) => {
                 // client tried to join multiple times, should be disconnected
-                trace!("client {:?} tried to join multiple times", client);
-                Err(BadJoinError)
+                Err(BadJoinError::AlreadyJoined)
             }
             Some(ClientState::Connected(player_id)) => {
-                // currently we only disallow joining in the past
                 if on_frame < self.frame {
-                    trace!("client {:?} tried to join in the past", client);
-                    Err(BadJoinError)
+                    Err(BadJoinError::InPast { frame: on_frame, current: self.frame })
+                } else if on_frame > self.frame + MAX_LOOK_AHEAD {
+                    Err(BadJoinError::TooFarAhead { frame: on_frame, current: self.frame })
                 } else {
                     trace!("client {:?} will join on frame {}", client, on_frame);
                     let new_state = ClientState::WaitingForJoin(WaitingClient {
                         join_frame: on_frame,
                         player_id: *player_id,
-                        inputs: InputQueue {
-                            next_input_frame: on_frame + 1,
-                            inputs: VecDeque::new(),
-                        }
+                        inputs: InputQueue::new(on_frame + 1),
                     });
                     self.clients.insert(client, new_state);
                     Ok(())
                 }
             }
         };
-        if result.is_err() {
+        if let Err(e) = &result {
+            trace!("client {:?} {}, disconnecting", client, e);
             self.client_disconnected(client);
         }
         result
     }
 
     /// Client sent an input. Inputs must be sent for each frame without
-    /// skipping any, and the first one should be for the next frame after the
-    /// one that the client joined on. If those conditions are not met or the
+    /// skipping any (unless gaps are allowed, then frames must only be
+    /// increasing), and the first one should be for the next frame after the
+    /// one that the client joined on. Inputs can be at most `MAX_LOOK_AHEAD`
+    /// frames ahead of the server. If those conditions are not met or the
     /// serialized input is not valid, then the client should be disconnected.
     pub fn client_input(&mut self, client: ClientId, frame: u64, serialized: &[u8]) -> Result<(), BadInputError> {
+        let current_frame = self.frame;
+        let allow_gaps = self.allow_input_gaps;
         let result = match self.clients.get_mut(&client) {
             None => panic!("client sent inputs without connecting"),
             Some(ClientState::Connected(_)) => {
                 // client tried to send inputs before joining the game,
                 // disconnect them
-                trace!("client {:?} sent inputs before joining", client);
-                Err(BadInputError)
+                Err(BadInputError::NotJoined)
+            }
+            Some(ClientState::WaitingForJoin(_)) |
+            Some(ClientState::InGame(_)) if frame > current_frame + MAX_LOOK_AHEAD => {
+                Err(BadInputError::TooFarAhead { frame, current: current_frame })
             }
             Some(ClientState::WaitingForJoin(WaitingClient { inputs, .. })) |
             Some(ClientState::InGame(InGameClient { inputs, .. })) => {
                 trace!("client {:?} sent inputs for frame {}", client, frame);
                 self.game
                     .deserialize_input(serialized)
-                    .map_err(|_| BadInputError)
-                    .and_then(|input| inputs.add_input(frame, input))
+                    .map_err(|_| BadInputError::Invalid)
+                    .and_then(|input| inputs.add_input(frame, input, current_frame, allow_gaps))
             }
         };
-        if result.is_err() {
-            trace!("client {:?} sent bad inputs, disconnecting", client);
+        if let Err(e) = &result {
+            trace!("client {:?} {}, disconnecting", client, e);
             self.client_disconnected(client);
         }
         result
@@ -130,15 +459,19 @@ impl<G: Game> Server<G> {
     /// broadcasted to all connected clients (including those that haven't
     /// joined the game yet).
     pub fn game_tick(&mut self) -> FrameUpdate<G> {
-        let mut update = FrameUpdate::default();
+        let mut update = FrameUpdate {
+            seed: frame_seed(self.room_id, self.frame),
+            ..FrameUpdate::default()
+        };
         update.removed_players.extend(self.removed_players.drain(..));
+        update.new_players.extend(self.restarted_players.drain(..));
         for client in self.clients.values_mut() {
             match client {
                 ClientState::Connected(_) => {}
                 ClientState::WaitingForJoin(waiting) => {
                     if waiting.join_frame == self.frame {
                         update.new_player(waiting.player_id);
-                        let playing = waiting.into_playing();
+                        let playing = waiting.take_playing();
                         *client = ClientState::InGame(playing);
                     }
                 }
@@ -149,11 +482,18 @@ impl<G: Game> Server<G> {
                         // player hasn't sent inputs for this frame
                         // FIXME: for now we just ignore this, but game
                         // developers might want custom behaviour in this case
+                        client.inputs.stats.missing_inputs += 1;
                     }
                 }
             }
         }
-        self.world = self.game.apply_update(&self.world, &update);
+        self.game.set_log_frame(self.room_id, self.frame);
+        let world = self.game.apply_update(&self.world, &update);
+        let previous = mem::replace(&mut self.world, world);
+        let frame = self.frame;
+        if self.keyframe.as_ref().is_none_or(|keyframe| frame >= keyframe.frame + KEYFRAME_INTERVAL) {
+            self.keyframe = Some(Keyframe { frame, world: previous });
+        }
         trace!("completed simulation frame #{}", self.frame);
         self.frame += 1;
         update
@@ -194,11 +534,8 @@ struct WaitingClient<G: Game> {
 impl<G: Game> WaitingClient<G> {
     // FIXME: ideally this should take `self` by value, but then one of the
     // places where we use this is difficult to fix :(
-    fn into_playing(&mut self) -> InGameClient<G> {
-        let temp = InputQueue {
-            next_input_frame: 0,
-            inputs: VecDeque::new(),
-        };
+    fn take_playing(&mut self) -> InGameClient<G> {
+        let temp = InputQueue::new(0);
         InGameClient {
             player_id: self.player_id,
             inputs: std::mem::replace(&mut self.inputs, temp),
@@ -214,19 +551,66 @@ struct InGameClient<G: Game> {
 struct InputQueue<G: Game> {
     next_input_frame: u64,
     inputs: VecDeque<ClientInput<G>>,
+    timing: InputTiming,
+    /// Counters since the client joined, without `queue_depth`.
+    stats: ClientStats,
+    /// Frame of the world that the client was resynced with. Until it sends
+    /// an input for that frame or a later one, an input can skip frames.
+    resynced_on: Option<u64>,
+}
+
+/// When inputs arrived since the last advice.
+#[derive(Default)]
+struct InputTiming {
+    late_inputs: u64,
+    /// Smallest number of frames between an input's arrival and simulation of
+    /// its frame, negative if it arrived late.
+    smallest_margin: Option<i64>,
 }
 
 impl<G: Game> InputQueue<G> {
-    fn add_input(&mut self, frame: u64, input: G::Input) -> Result<(), BadInputError> {
-        if frame == self.next_input_frame {
-            self.inputs.push_back(ClientInput { frame, input });
-            self.next_input_frame += 1;
+    fn new(next_input_frame: u64) -> Self {
+        InputQueue {
+            next_input_frame,
+            inputs: VecDeque::new(),
+            timing: InputTiming::default(),
+            stats: ClientStats::default(),
+            resynced_on: None,
+        }
+    }
+
+    /// Queue input for `frame`, which arrived when `current_frame` was the
+    /// next one to be simulated.
+    fn add_input(&mut self, frame: u64, input: G::Input, current_frame: u64, allow_gaps: bool) -> Result<(), BadInputError> {
+        let resynced = self.resynced_on.is_some();
+        if frame == self.next_input_frame || ((allow_gaps || resynced) && frame > self.next_input_frame) {
+            if frame > self.next_input_frame || self.resynced_on.is_some_and(|resynced_on| frame >= resynced_on) {
+                self.resynced_on = None;
+            }
+            self.stats.skipped_inputs += frame - self.next_input_frame;
+            let margin = frame as i64 - current_frame as i64;
+            if margin < 0 {
+                self.timing.late_inputs += 1;
+                self.stats.late_inputs += 1;
+            } else {
+                self.stats.early_inputs[(margin as usize).min(EARLY_BUCKETS - 1)] += 1;
+            }
+            self.timing.smallest_margin = Some(self.timing.smallest_margin.map_or(margin, |m| m.min(margin)));
+            // late inputs would be dropped when getting the next one anyway
+            if margin >= 0 {
+                self.inputs.push_back(ClientInput { frame, input });
+            }
+            self.next_input_frame = frame + 1;
             Ok(())
         } else {
-            Err(BadInputError)
+            Err(BadInputError::UnexpectedFrame { frame, expected: self.next_input_frame })
         }
     }
 
+    fn clear(&mut self) {
+        self.inputs.clear();
+    }
+
     fn get_input(&mut self, frame: u64) -> Option<G::Input> {
         while let Some(input) = self.inputs.pop_front() {
             if input.frame == frame {
@@ -303,6 +687,17 @@ mod tests {
         }
     }
 
+    /// Advances the game and checks the seed of the update. Seed is reset to
+    /// zero, so that the rest of the update can be compared with
+    /// `FrameUpdate::default()`.
+    fn advance(server: &mut Server<TestGame>) -> FrameUpdate<TestGame> {
+        let seed = frame_seed(server.room_id, server.frame);
+        let mut update = server.game_tick();
+        assert_eq!(update.seed, seed);
+        update.seed = 0;
+        update
+    }
+
     fn server() -> Server<TestGame> {
         Server::new(TestGame(0))
     }
@@ -314,7 +709,7 @@ mod tests {
         assert!(server.client_joined(client, 0).is_ok());
 
         // player is added on next tick
-        let tick = server.game_tick();
+        let tick = advance(&mut server);
         let mut expected = FrameUpdate::default();
         expected.new_player(1);
         assert_eq!(tick, expected);
@@ -326,8 +721,8 @@ mod tests {
     fn ticking() {
         let mut server = server();
         // default FrameUpdate because there are no players - so no inputs
-        assert_eq!(server.game_tick(), FrameUpdate::default());
-        assert_eq!(server.game_tick(), FrameUpdate::default());
+        assert_eq!(advance(&mut server), FrameUpdate::default());
+        assert_eq!(advance(&mut server), FrameUpdate::default());
         assert_eq!(server.world, vec!["update", "update"]);
         assert_eq!(server.frame, 2);
     }
@@ -335,7 +730,7 @@ mod tests {
     #[test]
     fn connect() {
         let mut server = server();
-        server.game_tick();
+        advance(&mut server);
         let (_client, world) = server.client_connected();
         assert_eq!(world.world, &vec!["update"]);
         assert_eq!(world.frame, 1);
@@ -348,12 +743,12 @@ mod tests {
         let local_player = world.local_player_id;
         assert!(server.client_joined(client, 1).is_ok());
         // default Frame update because it's tick 0
-        assert_eq!(server.game_tick(), FrameUpdate::default());
+        assert_eq!(advance(&mut server), FrameUpdate::default());
         // frame 1 - a new player should appear
         // test game generates player ids sequentially starting from 1
         let mut expected = FrameUpdate::default();
         expected.new_player(local_player);
-        assert_eq!(server.game_tick(), expected);
+        assert_eq!(advance(&mut server), expected);
     }
 
     #[test]
@@ -372,14 +767,14 @@ mod tests {
         assert!(server.client_joined(client, 0).is_ok());
 
         // player is added on next tick
-        let tick = server.game_tick();
+        let tick = advance(&mut server);
         let mut expected = FrameUpdate::default();
         expected.new_player(local_player);
         assert_eq!(tick, expected);
 
         assert!(server.client_input(client, 1, "abc".as_bytes()).is_ok());
 
-        let tick = server.game_tick();
+        let tick = advance(&mut server);
         let mut expected = FrameUpdate::default();
         expected.input(local_player, "abc".to_string());
         assert_eq!(tick, expected);
@@ -391,19 +786,243 @@ mod tests {
 
         server.client_disconnected(client);
 
-        let tick = server.game_tick();
+        let tick = advance(&mut server);
         let mut expected = FrameUpdate::default();
         expected.remove_player(local_player);
         assert_eq!(tick, expected);
     }
 
+    #[test]
+    fn replace_game_keeping_world() {
+        let (mut server, client, local_player) = server_with_client();
+        assert!(server.client_input(client, 1, "abc".as_bytes()).is_ok());
+        assert!(server.client_input(client, 2, "def".as_bytes()).is_ok());
+
+        server.replace_game(TestGame(local_player), Some(vec!["migrated".into()]));
+        // queued inputs are dropped, but new ones are still expected to
+        // continue from the last one
+        assert_eq!(advance(&mut server), FrameUpdate::default());
+        assert!(server.client_input(client, 3, "ghi".as_bytes()).is_ok());
+        advance(&mut server);
+        let mut expected = FrameUpdate::default();
+        expected.input(local_player, "ghi".to_string());
+        assert_eq!(advance(&mut server), expected);
+        assert_eq!(server.world, vec!["migrated", "update", "update", "update", "input 1: ghi"]);
+    }
+
+    #[test]
+    fn replace_game_restarting_world() {
+        let (mut server, client, local_player) = server_with_client();
+        advance(&mut server);
+        server.replace_game(TestGame(local_player), None);
+
+        // players that were in game are added to the new world
+        let mut expected = FrameUpdate::default();
+        expected.new_player(local_player);
+        assert_eq!(advance(&mut server), expected);
+        assert_eq!(server.world, vec!["update", "add 1"]);
+
+        let world = server.client_world(client).expect("client is not connected");
+        assert_eq!(world.local_player_id, local_player);
+        assert_eq!(world.frame, 3);
+    }
+
+    #[test]
+    fn keyframes() {
+        let mut server = server();
+        assert!(server.keyframe().is_none());
+        advance(&mut server);
+        let keyframe = server.keyframe().unwrap();
+        assert_eq!((keyframe.frame, keyframe.world.len()), (0, 0));
+        for _ in 1..KEYFRAME_INTERVAL {
+            advance(&mut server);
+        }
+        assert_eq!(server.keyframe().unwrap().frame, 0);
+        advance(&mut server);
+        let keyframe = server.keyframe().unwrap();
+        assert_eq!(keyframe.frame, KEYFRAME_INTERVAL);
+        assert_eq!(keyframe.world.len() as u64, KEYFRAME_INTERVAL);
+
+        let world = server.world().clone();
+        server.replace_game(TestGame(10), Some(world));
+        assert!(server.keyframe().is_none());
+        advance(&mut server);
+        assert_eq!(server.keyframe().unwrap().frame, KEYFRAME_INTERVAL + 1);
+    }
+
+    #[test]
+    fn input_advice() {
+        let (mut server, client, _local_player) = server_with_client();
+        let advance_to = |server: &mut Server<TestGame>, frame| {
+            while server.frame < frame {
+                advance(server);
+            }
+        };
+        // inputs arrive just in time, but margin should be at least 1
+        for frame in 1..ADVICE_INTERVAL {
+            advance_to(&mut server, frame);
+            assert!(server.client_input(client, frame, b"x").is_ok());
+        }
+        advance_to(&mut server, ADVICE_INTERVAL + 1);
+        assert!(server.input_advice().is_empty(), "advice given between intervals");
+        advance_to(&mut server, 2 * ADVICE_INTERVAL);
+        assert_eq!(server.input_advice()[&client], InputAdvice { change: 1, late_inputs: 0 });
+        // timing is reset after advising, and there is nothing to advise on
+        // without inputs
+        assert!(server.input_advice().is_empty());
+
+        // 60 frames late
+        assert!(server.client_input(client, ADVICE_INTERVAL, b"x").is_ok());
+        advance_to(&mut server, 3 * ADVICE_INTERVAL);
+        assert_eq!(server.input_advice()[&client], InputAdvice { change: 61, late_inputs: 1 });
+
+        let far = 5 * ADVICE_INTERVAL - 1;
+        for frame in ADVICE_INTERVAL + 1..far {
+            assert!(server.client_input(client, frame, b"x").is_ok());
+        }
+        advance_to(&mut server, 4 * ADVICE_INTERVAL);
+        assert_eq!(server.input_advice()[&client], InputAdvice { change: 120, late_inputs: 119 });
+
+        // far ahead, lead is lowered one frame at a time
+        assert!(server.client_input(client, far, b"x").is_ok());
+        advance_to(&mut server, 5 * ADVICE_INTERVAL);
+        assert_eq!(server.input_advice()[&client], InputAdvice { change: -1, late_inputs: 0 });
+
+        server.set_input_margin(InputMargin { min: 0, max: 100 });
+        assert!(server.client_input(client, far + 1, b"x").is_ok());
+        advance_to(&mut server, 6 * ADVICE_INTERVAL);
+        assert!(server.input_advice().is_empty(), "margin of 0 frames is within limits");
+    }
+
+    #[test]
+    fn client_stats() {
+        let (mut server, client, _local_player) = server_with_client();
+        assert_eq!(server.client_stats(ClientId(100)), None);
+        assert!(server.client_input(client, 1, b"a").is_ok());
+        assert!(server.client_input(client, 2, b"b").is_ok());
+        assert!(server.client_input(client, 3, b"c").is_ok());
+        let stats = server.client_stats(client).unwrap();
+        assert_eq!((stats.queue_depth, &stats.early_inputs[..4]), (3, &[1, 1, 1, 0][..]));
+        for _ in 1..6 {
+            advance(&mut server);
+        }
+        assert!(server.client_input(client, 4, b"d").is_ok());
+        assert!(server.client_input(client, 5, b"e").is_ok());
+        let stats = server.client_stats(client).unwrap();
+        assert_eq!((stats.late_inputs, stats.missing_inputs), (2, 2));
+        // gaps are not allowed by default
+        assert!(server.client_input(client, 7, b"f").is_err());
+        assert_eq!(server.client_stats(client), None);
+
+        server.set_allow_input_gaps(true);
+        let (client, _) = server.client_connected();
+        assert_eq!(server.client_stats(client), Some(ClientStats::default()));
+        assert!(server.client_joined(client, 6).is_ok());
+        assert!(server.client_input(client, 10, b"x").is_ok());
+        for _ in 6..9 {
+            advance(&mut server);
+        }
+        let stats = server.client_stats(client).unwrap();
+        let mut early_inputs = [0; EARLY_BUCKETS];
+        early_inputs[4] = 1;
+        assert_eq!(stats, ClientStats {
+            late_inputs: 0,
+            missing_inputs: 2,
+            skipped_inputs: 3,
+            early_inputs,
+            queue_depth: 1,
+        });
+        assert_eq!(stats.to_string(), "0 late, 2 missing, 3 skipped, 1 queued, early by 4: 1");
+        assert!(server.client_input(client, 10, b"y").is_err(), "frame was repeated");
+    }
+
+    #[test]
+    fn resynced_client_skips_frames() {
+        let (mut server, client, _local_player) = server_with_client();
+        assert!(server.client_input(client, 1, b"a").is_ok());
+        for _ in 0..5 {
+            advance(&mut server);
+        }
+        server.client_resynced(client);
+        // inputs that were sent before the client got the new world
+        assert!(server.client_input(client, 2, b"b").is_ok());
+        assert!(server.client_input(client, 10, b"c").is_ok());
+        assert_eq!(server.client_stats(client).unwrap().skipped_inputs, 7);
+        // only one gap is allowed after resyncing
+        assert!(server.client_input(client, 12, b"d").is_err());
+
+        // first input after the new world doesn't skip, so no gaps later
+        let (mut server, client, _local_player) = server_with_client();
+        advance(&mut server);
+        advance(&mut server);
+        server.client_resynced(client);
+        assert!(server.client_input(client, 1, b"a").is_ok());
+        assert!(server.client_input(client, 2, b"b").is_ok());
+        assert!(server.client_input(client, 3, b"c").is_ok());
+        assert!(server.client_input(client, 10, b"d").is_err());
+    }
+
+    #[test]
+    fn flooding_with_inputs() {
+        let (mut server, client, _local_player) = server_with_client();
+        for frame in 1..=MAX_LOOK_AHEAD + 1 {
+            assert!(server.client_input(client, frame, b"x").is_ok());
+        }
+        assert_eq!(server.client_stats(client).unwrap().queue_depth as u64, MAX_LOOK_AHEAD + 1);
+        assert_eq!(
+            server.client_input(client, MAX_LOOK_AHEAD + 2, b"x"),
+            Err(BadInputError::TooFarAhead { frame: MAX_LOOK_AHEAD + 2, current: 1 }),
+        );
+        assert_eq!(server.client_stats(client), None);
+
+        // window moves with the server
+        let (client, _) = server.client_connected();
+        assert!(server.client_joined(client, 1).is_ok());
+        for frame in 2..=MAX_LOOK_AHEAD + 1 {
+            assert!(server.client_input(client, frame, b"x").is_ok());
+        }
+        for _ in 0..10 {
+            advance(&mut server);
+            assert!(server.client_input(client, server.frame + MAX_LOOK_AHEAD, b"x").is_ok());
+        }
+        assert!(server.client_input(client, server.frame + MAX_LOOK_AHEAD + 1, b"x").is_err());
+
+        let (client, _) = server.client_connected();
+        assert_eq!(
+            server.client_joined(client, server.frame + MAX_LOOK_AHEAD + 1),
+            Err(BadJoinError::TooFarAhead { frame: 132, current: 11 }),
+        );
+        let (client, _) = server.client_connected();
+        assert_eq!(server.client_joined(client, 5), Err(BadJoinError::InPast { frame: 5, current: 11 }));
+        assert_eq!(
+            BadJoinError::InPast { frame: 5, current: 11 }.to_string(),
+            "tried to join on frame 5 while server is already on frame 11",
+        );
+    }
+
     #[test]
     fn input_skip() {
         let (mut server, _client, _local_player) = server_with_client();
 
         // client does not send any inputs, so no inputs in update
-        assert_eq!(server.game_tick(), FrameUpdate::default());
-        assert_eq!(server.game_tick(), FrameUpdate::default());
-        assert_eq!(server.game_tick(), FrameUpdate::default());
+        assert_eq!(advance(&mut server), FrameUpdate::default());
+        assert_eq!(advance(&mut server), FrameUpdate::default());
+        assert_eq!(advance(&mut server), FrameUpdate::default());
+    }
+
+    #[test]
+    fn seeds_differ_between_frames_and_rooms() {
+        let seeds = [
+            frame_seed(0, 0),
+            frame_seed(0, 1),
+            frame_seed(1, 0),
+            frame_seed(1, 1),
+        ];
+        for (i, a) in seeds.iter().enumerate() {
+            for b in &seeds[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(frame_seed(7, 100), frame_seed(7, 100));
     }
 }
@@ -0,0 +1,150 @@
+//! Headless simulation of a game with synthetic players, for benchmarking and
+//! soak-testing game modules.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use log::warn;
+use crate::bots::{Bot, RandomKeys, Script};
+use crate::game::ToBlob;
+use crate::game::wasmi::WasmiGame;
+use crate::game::wasmi::sys::{CallStats, Module};
+use crate::protocol::Update;
+use crate::recording::Recorder;
+use crate::server::Server;
+
+pub struct Options {
+    pub players: u32,
+    pub frames: u64,
+    /// Seed for random bot inputs. Ignored if bots follow a script.
+    pub seed: u64,
+    pub script: Option<Script>,
+    /// Records every simulated frame.
+    pub recorder: Option<Recorder>,
+}
+
+pub struct Report {
+    pub players: u32,
+    pub frames: u64,
+    /// Time spent in `Server::game_tick`.
+    pub tick_time: Duration,
+    /// Time spent creating and submitting bot inputs.
+    pub input_time: Duration,
+    pub call_stats: BTreeMap<&'static str, CallStats>,
+    pub initial_memory: usize,
+    pub final_memory: usize,
+    pub final_world: Vec<u8>,
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tick_seconds = seconds(self.tick_time);
+        writeln!(f, "simulated {} frames with {} players", self.frames, self.players)?;
+        writeln!(
+            f,
+            "ticks: {:.3} s total, {:.1} ticks per second",
+            tick_seconds,
+            self.frames as f64 / tick_seconds,
+        )?;
+        writeln!(f, "inputs: {:.3} s total", seconds(self.input_time))?;
+        writeln!(
+            f,
+            "memory: {} KiB -> {} KiB",
+            self.initial_memory / 1024,
+            self.final_memory / 1024,
+        )?;
+        writeln!(f, "final world: {} bytes, fnv1a {:016x}", self.final_world.len(), fnv1a(&self.final_world))?;
+        writeln!(f)?;
+        write!(f, "{:<20} {:>10} {:>12} {:>12}", "export", "calls", "total ms", "avg us")?;
+        for (name, stats) in &self.call_stats {
+            let total = seconds(stats.total_time);
+            write!(
+                f,
+                "\n{:<20} {:>10} {:>12.3} {:>12.3}",
+                name,
+                stats.calls,
+                total * 1e3,
+                total * 1e6 / stats.calls as f64,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn simulate(module: Module, options: Options) -> Report {
+    // Bots create inputs with their own instance, so that stats only count
+    // what the server does.
+    let mut client_game = WasmiGame::new(module.duplicate().expect("failed to instantiate module for bots"));
+    let module = Rc::new(module);
+    let mut server = Server::with_room_id(WasmiGame::with_shared_module(module.clone()), options.seed);
+    let script = options.script.map(Rc::new);
+    let mut bots = Vec::new();
+    for index in 0..options.players {
+        let (client, _) = server.client_connected();
+        if server.client_joined(client, 0).is_err() {
+            panic!("server rejected bot join");
+        }
+        let bot = match &script {
+            Some(script) => Bot::scripted(client, script.clone(), index as usize),
+            None => Bot::random(client, RandomKeys::new(options.seed, u64::from(index))),
+        };
+        bots.push(bot);
+    }
+
+    let mut recorder = options.recorder;
+    if let Some(writer) = &mut recorder {
+        if let Err(e) = writer.record_start(server.frame(), &server.world().to_blob()) {
+            warn!("failed to write recording: {}", e);
+            recorder = None;
+        }
+    }
+
+    let initial_memory = module.memory_size();
+    module.set_profiling(true);
+    let mut tick_time = Duration::from_secs(0);
+    let mut input_time = Duration::from_secs(0);
+    for frame in 0..options.frames {
+        let start = Instant::now();
+        for bot in &mut bots {
+            let input = bot.next_input(&mut client_game).to_blob();
+            if server.client_input(bot.client(), frame + 1, &input).is_err() {
+                panic!("server rejected bot input for frame {}", frame + 1);
+            }
+        }
+        input_time += start.elapsed();
+
+        let start = Instant::now();
+        let update = server.game_tick();
+        tick_time += start.elapsed();
+
+        if let Some(writer) = &mut recorder {
+            if let Err(e) = writer.record_update(&Update::from(update)) {
+                warn!("failed to write recording, stopping: {}", e);
+                recorder = None;
+            }
+        }
+    }
+    module.set_profiling(false);
+
+    Report {
+        players: options.players,
+        frames: options.frames,
+        tick_time,
+        input_time,
+        call_stats: module.call_stats(),
+        initial_memory,
+        final_memory: module.memory_size(),
+        final_world: server.world().to_blob(),
+    }
+}
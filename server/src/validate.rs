@@ -0,0 +1,174 @@
+//! Checks that a game package can be loaded and played, so that broken
+//! packages can be rejected before they are deployed.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Once;
+use std::rc::Rc;
+use crate::game::{Game, ToBlob};
+use crate::game::wasmi::WasmiGame;
+use crate::game::wasmi::runtime::Runtime;
+use crate::game::wasmi::sys::Module;
+use crate::package;
+use crate::bots::{Bot, RandomKeys};
+use crate::server::Server;
+
+pub struct Options {
+    /// Number of synthetic players in smoke simulation.
+    pub players: u32,
+    /// Number of frames to simulate.
+    pub frames: u64,
+    pub runtime: Runtime,
+}
+
+pub struct Report {
+    checks: Vec<Check>,
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Result<(), String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.outcome.is_ok())
+    }
+
+    fn check<T, F>(&mut self, name: &'static str, f: F) -> Option<T>
+    where
+        F: FnOnce() -> Result<T, String>,
+    {
+        let (outcome, value) = match catch_panic(f) {
+            Ok(value) => (Ok(()), Some(value)),
+            Err(e) => (Err(e), None),
+        };
+        self.checks.push(Check { name, outcome });
+        value
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                Ok(()) => writeln!(f, "[ ok ] {}", check.name)?,
+                Err(e) => writeln!(f, "[FAIL] {}: {}", check.name, e)?,
+            }
+        }
+        if self.is_ok() {
+            write!(f, "package is valid")
+        } else {
+            write!(f, "package is invalid")
+        }
+    }
+}
+
+thread_local! {
+    /// Whether this thread is inside `catch_panic`.
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Installs a panic hook that doesn't print panics caught by `catch_panic`,
+/// and prints all others as usual. Panic hook is global, so it is installed
+/// once instead of being swapped around each call.
+pub fn silence_caught_panics() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING_PANICS.with(Cell::get) {
+                default_hook(info);
+            }
+        }));
+    });
+}
+
+/// Runs `f`, converting a panic into an error. Wasm bindings panic when the
+/// module traps or returns garbage, and we want to report that instead of
+/// crashing.
+pub fn catch_panic<T, F: FnOnce() -> Result<T, String>>(f: F) -> Result<T, String> {
+    silence_caught_panics();
+    let was_catching = CATCHING_PANICS.with(|catching| catching.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING_PANICS.with(|catching| catching.set(was_catching));
+    result.unwrap_or_else(|payload| Err(panic_message(&*payload)))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("panicked: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("panicked: {}", message)
+    } else {
+        "panicked".to_string()
+    }
+}
+
+pub fn validate_package<P: AsRef<Path>>(path: P, options: &Options) -> Report {
+    let mut report = Report { checks: Vec::new() };
+    let package = report.check("package format and exports", || {
+        package::load_from_file(path).map_err(|e| e.to_string())
+    });
+    let package = match package {
+        Some(package) => package,
+        None => return report,
+    };
+    let module = report.check("module initialization", || {
+        Module::from_buffer(&package.wasm_module, options.runtime).map_err(|e| e.to_string())
+    });
+    let module = match module {
+        Some(module) => Rc::new(module),
+        None => return report,
+    };
+    report.check("smoke simulation", || smoke_simulation(module.clone(), options));
+    report
+}
+
+fn smoke_simulation(module: Rc<Module>, options: &Options) -> Result<(), String> {
+    // Inputs and worlds are created with a separate game instance, as a
+    // client would do it.
+    let mut client_game = WasmiGame::with_shared_module(module.clone());
+    let mut server = Server::new(WasmiGame::with_shared_module(module));
+    let mut players = Vec::new();
+    for _ in 0..options.players {
+        let (client, _) = server.client_connected();
+        server
+            .client_joined(client, 0)
+            .map_err(|_| "server rejected player join".to_string())?;
+        let keys = RandomKeys::new(0x2545_f491_4f6c_dd1d, players.len() as u64);
+        players.push(Bot::random(client, keys));
+    }
+
+    for frame in 0..options.frames {
+        // One player leaves midway to exercise `remove_player`.
+        if frame == options.frames / 2 && players.len() > 1 {
+            let player = players.remove(0);
+            server.client_disconnected(player.client());
+        }
+        for player in &mut players {
+            let input = player.next_input(&mut client_game);
+            let blob = input.to_blob();
+            let roundtrip = client_game
+                .deserialize_input(&blob)
+                .map_err(|_| format!("failed to deserialize input on frame {}", frame))?
+                .to_blob();
+            if roundtrip != blob {
+                return Err(format!("input changed after serialization roundtrip on frame {}", frame));
+            }
+            server
+                .client_input(player.client(), frame + 1, &blob)
+                .map_err(|_| format!("server rejected input for frame {}", frame + 1))?;
+        }
+        server.game_tick();
+    }
+
+    let blob = server.world().to_blob();
+    if client_game.deserialize_world(&blob).to_blob() != blob {
+        return Err("world changed after serialization roundtrip".to_string());
+    }
+    Ok(())
+}
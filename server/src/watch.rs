@@ -0,0 +1,82 @@
+//! Watching game package for changes during development.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use log::{info, warn};
+use crate::package::{self, Package};
+use crate::resources::ServerResources;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+struct FileVersion {
+    modified: SystemTime,
+    size: u64,
+}
+
+pub struct PackageWatcher {
+    path: PathBuf,
+    resources: Arc<ServerResources>,
+    /// Version of the file seen on last poll.
+    seen: Option<FileVersion>,
+    /// Version of the file that was last loaded, or at least attempted to.
+    loaded: Option<FileVersion>,
+    last_poll: Instant,
+}
+
+impl PackageWatcher {
+    /// Watch for changes of a package that is currently served from given
+    /// resources.
+    pub fn new(path: PathBuf, resources: Arc<ServerResources>) -> PackageWatcher {
+        let version = file_version(&path);
+        PackageWatcher {
+            path,
+            resources,
+            seen: version,
+            loaded: version,
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Returns new version of the package if it has changed since the last
+    /// call. A package is only loaded once it stays unchanged for one poll
+    /// interval, so that we don't try to read it while it is being written.
+    pub fn poll(&mut self) -> Option<Package> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+        let version = file_version(&self.path);
+        if version != self.seen {
+            self.seen = version;
+            return None;
+        }
+        if version.is_none() || version == self.loaded {
+            return None;
+        }
+        self.loaded = version;
+        info!("package {} changed, reloading", self.path.display());
+        match package::load_from_file(&self.path) {
+            Ok(package) => Some(package),
+            Err(e) => {
+                warn!("failed to load changed package: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Start serving the package to clients.
+    pub fn publish(&self, package: Package) {
+        self.resources.set_package(package);
+    }
+}
+
+fn file_version(path: &Path) -> Option<FileVersion> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(FileVersion {
+        modified: metadata.modified().ok()?,
+        size: metadata.len(),
+    })
+}